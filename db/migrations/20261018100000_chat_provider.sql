@@ -0,0 +1,29 @@
+ALTER TABLE chats ADD COLUMN provider TEXT NOT NULL DEFAULT 'openai';
+
+DROP VIEW v_chat_messages;
+
+CREATE VIEW v_chat_messages AS
+SELECT
+  message_pairs.id,
+  message_block_id,
+  message_blocks.chat_id AS chat_id,
+  chats.provider AS provider,
+  chats.model AS model,
+  human_message.message AS human_message,
+  ai_message.message AS ai_message,
+  RANK() OVER (
+    PARTITION BY message_block_id
+    ORDER BY
+      message_pairs.created_at ASC
+  ) AS block_rank,
+  COUNT(*) OVER (PARTITION BY message_block_id) AS block_size
+FROM
+  message_pairs
+  JOIN messages human_message ON human_message.id = message_pairs.human_message_id
+  LEFT JOIN messages ai_message ON ai_message.id = message_pairs.ai_message_id
+  JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+  JOIN chats ON chats.id = message_blocks.chat_id
+WHERE
+  message_pairs.id = message_blocks.selected_pair_id
+ORDER BY
+  message_blocks.created_at ASC;
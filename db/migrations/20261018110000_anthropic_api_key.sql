@@ -0,0 +1 @@
+ALTER TABLE settings ADD COLUMN anthropic_api_key TEXT;
@@ -0,0 +1,5 @@
+ALTER TABLE settings ADD COLUMN openai_base_url TEXT;
+
+ALTER TABLE settings ADD COLUMN openai_api_version TEXT;
+
+ALTER TABLE settings ADD COLUMN openai_extra_headers TEXT;
@@ -0,0 +1,88 @@
+CREATE TABLE models (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  provider TEXT NOT NULL,
+  model_id TEXT NOT NULL,
+  display_name TEXT NOT NULL,
+  description TEXT NOT NULL DEFAULT '',
+  context_window INTEGER,
+  -- USD per million tokens
+  input_price REAL,
+  output_price REAL,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (provider, model_id)
+);
+
+INSERT INTO
+  models (
+    provider,
+    model_id,
+    display_name,
+    description,
+    context_window,
+    input_price,
+    output_price
+  )
+VALUES
+  (
+    'openai',
+    'gpt-4-1106-preview',
+    'GPT-4-Preview',
+    'This is the preview version of the GPT-4 model.',
+    128000,
+    10.0,
+    30.0
+  ),
+  (
+    'openai',
+    'gpt-4',
+    'GPT-4',
+    'Latest generation GPT-4 model.',
+    8192,
+    30.0,
+    60.0
+  ),
+  (
+    'openai',
+    'gpt-3.5-turbo-16k',
+    'GPT-3.5-16K',
+    'An enhanced GPT-3.5 model with 16K token limit.',
+    16385,
+    3.0,
+    4.0
+  ),
+  (
+    'openai',
+    'gpt-3.5-turbo',
+    'GPT-3.5',
+    'Standard GPT-3.5 model with turbo features.',
+    16385,
+    0.5,
+    1.5
+  ),
+  (
+    'anthropic',
+    'claude-3-opus-20240229',
+    'Claude 3 Opus',
+    'Anthropic''s most capable model for complex tasks.',
+    200000,
+    15.0,
+    75.0
+  ),
+  (
+    'anthropic',
+    'claude-3-sonnet-20240229',
+    'Claude 3 Sonnet',
+    'Balance of intelligence and speed.',
+    200000,
+    3.0,
+    15.0
+  ),
+  (
+    'anthropic',
+    'claude-3-haiku-20240307',
+    'Claude 3 Haiku',
+    'Fastest and most compact Claude model.',
+    200000,
+    0.25,
+    1.25
+  );
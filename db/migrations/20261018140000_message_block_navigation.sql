@@ -0,0 +1,52 @@
+-- Rank the pairs of each block before keeping the selected one, so that
+-- block_rank/block_size describe all the alternatives of the block.
+DROP VIEW v_chat_messages;
+
+CREATE VIEW v_chat_messages AS
+SELECT
+  id,
+  message_block_id,
+  chat_id,
+  provider,
+  model,
+  human_message,
+  ai_message,
+  block_rank,
+  block_size,
+  prev_pair_id,
+  next_pair_id
+FROM
+  (
+    SELECT
+      message_pairs.id,
+      message_pairs.message_block_id,
+      message_blocks.chat_id AS chat_id,
+      message_blocks.selected_pair_id AS selected_pair_id,
+      message_blocks.created_at AS block_created_at,
+      chats.provider AS provider,
+      chats.model AS model,
+      human_message.message AS human_message,
+      ai_message.message AS ai_message,
+      ROW_NUMBER() OVER block_pairs AS block_rank,
+      COUNT(*) OVER (PARTITION BY message_pairs.message_block_id) AS block_size,
+      LAG(message_pairs.id) OVER block_pairs AS prev_pair_id,
+      LEAD(message_pairs.id) OVER block_pairs AS next_pair_id
+    FROM
+      message_pairs
+      JOIN messages human_message ON human_message.id = message_pairs.human_message_id
+      LEFT JOIN messages ai_message ON ai_message.id = message_pairs.ai_message_id
+      JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+      JOIN chats ON chats.id = message_blocks.chat_id
+    WINDOW
+      block_pairs AS (
+        PARTITION BY message_pairs.message_block_id
+        ORDER BY
+          message_pairs.created_at ASC,
+          message_pairs.id ASC
+      )
+  )
+WHERE
+  id = selected_pair_id
+ORDER BY
+  block_created_at ASC,
+  message_block_id ASC;
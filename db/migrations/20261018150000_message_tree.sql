@@ -0,0 +1,101 @@
+-- Conversations are a tree: each block follows a pair of the previous block,
+-- the displayed conversation follows the selected pair of each block.
+ALTER TABLE message_blocks ADD COLUMN parent_pair_id INTEGER REFERENCES message_pairs(id) ON DELETE CASCADE;
+
+-- Existing conversations are linear, each block follows the selected pair of
+-- the block created before it.
+UPDATE message_blocks
+SET
+  parent_pair_id = (
+    SELECT
+      previous.selected_pair_id
+    FROM
+      message_blocks previous
+    WHERE
+      previous.chat_id = message_blocks.chat_id
+      AND (
+        previous.created_at < message_blocks.created_at
+        OR (
+          previous.created_at = message_blocks.created_at
+          AND previous.id < message_blocks.id
+        )
+      )
+    ORDER BY
+      previous.created_at DESC,
+      previous.id DESC
+    LIMIT
+      1
+  );
+
+CREATE INDEX message_blocks_parent_pair_id ON message_blocks (parent_pair_id);
+
+DROP VIEW v_chat_messages;
+
+CREATE VIEW v_chat_messages AS
+WITH RECURSIVE
+  ranked_pairs AS (
+    SELECT
+      message_pairs.id,
+      message_pairs.message_block_id,
+      message_blocks.chat_id AS chat_id,
+      message_blocks.selected_pair_id AS selected_pair_id,
+      message_blocks.parent_pair_id AS parent_pair_id,
+      chats.provider AS provider,
+      chats.model AS model,
+      human_message.message AS human_message,
+      ai_message.message AS ai_message,
+      ROW_NUMBER() OVER block_pairs AS block_rank,
+      COUNT(*) OVER (PARTITION BY message_pairs.message_block_id) AS block_size,
+      LAG(message_pairs.id) OVER block_pairs AS prev_pair_id,
+      LEAD(message_pairs.id) OVER block_pairs AS next_pair_id
+    FROM
+      message_pairs
+      JOIN messages human_message ON human_message.id = message_pairs.human_message_id
+      LEFT JOIN messages ai_message ON ai_message.id = message_pairs.ai_message_id
+      JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+      JOIN chats ON chats.id = message_blocks.chat_id
+    WINDOW
+      block_pairs AS (
+        PARTITION BY message_pairs.message_block_id
+        ORDER BY
+          message_pairs.created_at ASC,
+          message_pairs.id ASC
+      )
+  ),
+  conversation AS (
+    SELECT
+      ranked_pairs.*,
+      0 AS depth
+    FROM
+      ranked_pairs
+    WHERE
+      parent_pair_id IS NULL
+      AND id = selected_pair_id
+    UNION ALL
+    SELECT
+      ranked_pairs.*,
+      conversation.depth + 1 AS depth
+    FROM
+      ranked_pairs
+      JOIN conversation ON ranked_pairs.parent_pair_id = conversation.id
+    WHERE
+      ranked_pairs.id = ranked_pairs.selected_pair_id
+  )
+SELECT
+  id,
+  message_block_id,
+  chat_id,
+  provider,
+  model,
+  human_message,
+  ai_message,
+  block_rank,
+  block_size,
+  prev_pair_id,
+  next_pair_id,
+  depth
+FROM
+  conversation
+ORDER BY
+  chat_id ASC,
+  depth ASC;
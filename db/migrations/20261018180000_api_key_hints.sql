@@ -0,0 +1,3 @@
+-- API keys are stored encrypted, the settings show their last characters
+ALTER TABLE settings ADD COLUMN openai_api_key_hint TEXT;
+ALTER TABLE settings ADD COLUMN anthropic_api_key_hint TEXT;
@@ -0,0 +1,6 @@
+-- Failed logins by ip or email, only used with PERSIST_LOGIN_ATTEMPTS
+CREATE TABLE login_attempts (
+  key TEXT PRIMARY KEY NOT NULL,
+  failures INTEGER NOT NULL,
+  last_failure_at DATETIME NOT NULL
+);
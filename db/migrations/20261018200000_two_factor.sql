@@ -0,0 +1,21 @@
+-- TOTP two-factor authentication. The secret is encrypted with the master
+-- key, it is only used once `enabled` after checking a first code.
+CREATE TABLE user_totp (
+  user_id INTEGER PRIMARY KEY NOT NULL,
+  secret TEXT NOT NULL,
+  enabled BOOLEAN NOT NULL DEFAULT FALSE,
+  last_used_step INTEGER,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Single use codes to log in without the authenticator, stored as SHA-256
+CREATE TABLE recovery_codes (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INTEGER NOT NULL,
+  code_hash TEXT NOT NULL,
+  used_at DATETIME,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX recovery_codes_user_id ON recovery_codes (user_id);
@@ -0,0 +1,2 @@
+-- Set once the user follows the link sent to their email address
+ALTER TABLE users ADD COLUMN email_verified_at DATETIME;
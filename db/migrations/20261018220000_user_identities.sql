@@ -0,0 +1,12 @@
+-- Accounts of an OpenID Connect provider linked to users, a user logging in
+-- with single sign-on is found by the issuer and subject of the ID token.
+CREATE TABLE user_identities (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INTEGER NOT NULL,
+  issuer TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX user_identities_issuer_subject ON user_identities (issuer, subject);
@@ -0,0 +1,16 @@
+-- Personal API tokens, sent as `Authorization: Bearer <token>`. Like sessions
+-- they are looked up by the SHA-256 of the token, which is only shown once.
+-- `scopes` is a space separated list, see `security::api_token::ApiScope`.
+CREATE TABLE api_tokens (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INTEGER NOT NULL,
+  name TEXT NOT NULL,
+  token_hash TEXT NOT NULL UNIQUE,
+  scopes TEXT NOT NULL,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_used_at DATETIME,
+  expires_at DATETIME,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX api_tokens_user_id ON api_tokens (user_id);
@@ -0,0 +1,4 @@
+-- `role` is `user` or `admin`, see `data::model::Role`. Disabled users can't
+-- log in, their sessions and API tokens stop working.
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
+ALTER TABLE users ADD COLUMN disabled_at DATETIME;
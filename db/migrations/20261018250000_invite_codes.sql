@@ -0,0 +1,14 @@
+-- Single use invite codes generated by admins, needed to sign up when
+-- `REGISTRATION_POLICY` is `invite`. Stored as the SHA-256 of the code, which
+-- is only shown once.
+CREATE TABLE invite_codes (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  code_hash TEXT NOT NULL UNIQUE,
+  created_by INTEGER,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at DATETIME,
+  used_by INTEGER,
+  used_at DATETIME,
+  FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE SET NULL,
+  FOREIGN KEY (used_by) REFERENCES users(id) ON DELETE SET NULL
+);
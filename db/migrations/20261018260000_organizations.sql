@@ -0,0 +1,46 @@
+-- Organizations own provider API keys their members chat with when they have
+-- no key of their own. The keys are encrypted like the user keys, with the
+-- organization in their context, see `ai::stream::api_key`.
+CREATE TABLE organizations (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL,
+  openai_api_key TEXT,
+  openai_api_key_hint TEXT,
+  anthropic_api_key TEXT,
+  anthropic_api_key_hint TEXT,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- A user is the member of one organization at most. The monthly quotas only
+-- apply to the organization keys, NULL is unlimited.
+CREATE TABLE organization_members (
+  user_id INTEGER PRIMARY KEY,
+  organization_id INTEGER NOT NULL,
+  monthly_request_quota INTEGER,
+  monthly_token_quota INTEGER,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY (organization_id) REFERENCES organizations(id) ON DELETE CASCADE
+);
+
+CREATE INDEX organization_members_organization_id ON organization_members (organization_id);
+
+-- A generation with an organization key. Recorded when it starts, so running
+-- generations count towards the request quota, and completed with the token
+-- counts, estimated from the text, once it ends.
+CREATE TABLE usage_events (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INTEGER NOT NULL,
+  organization_id INTEGER NOT NULL,
+  chat_id INTEGER,
+  provider TEXT NOT NULL,
+  model TEXT NOT NULL,
+  input_tokens INTEGER NOT NULL DEFAULT 0,
+  output_tokens INTEGER NOT NULL DEFAULT 0,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY (organization_id) REFERENCES organizations(id) ON DELETE CASCADE,
+  FOREIGN KEY (chat_id) REFERENCES chats(id) ON DELETE SET NULL
+);
+
+CREATE INDEX usage_events_user_id_created_at ON usage_events (user_id, created_at);
@@ -0,0 +1,4 @@
+-- The extra headers of the OpenAI endpoint hold credentials, they are stored
+-- encrypted like the API keys and the settings show their names. Existing
+-- rows are encrypted at startup.
+ALTER TABLE settings ADD COLUMN openai_extra_header_names TEXT;
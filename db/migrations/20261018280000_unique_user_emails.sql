@@ -0,0 +1,6 @@
+-- An email belongs to one account. Duplicates signed up before are renamed,
+-- the oldest account keeps the email.
+UPDATE users SET email = 'duplicate-' || id || '-' || email
+WHERE id NOT IN (SELECT MIN(id) FROM users GROUP BY email);
+
+CREATE UNIQUE INDEX users_email ON users (email);
@@ -0,0 +1,220 @@
+use async_trait::async_trait;
+use reqwest::header::{HeaderValue, CONTENT_TYPE};
+use reqwest_eventsource::{Event as ReqwestEvent, EventSource as ReqwestEventSource};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::data::model::ChatMessagePair;
+
+use super::stream::{chat_messages, GenerationEvent, LlmProvider, Model, ProviderError};
+
+const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const MAX_TOKENS: u32 = 4096;
+
+// Define a struct to represent an Anthropic model.
+#[derive(Serialize, Deserialize, Debug)]
+struct AnthropicModel {
+    id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ModelList {
+    data: Vec<AnthropicModel>,
+}
+
+pub struct AnthropicProvider {
+    api_key: String,
+}
+
+impl AnthropicProvider {
+    pub fn new(api_key: &str) -> Self {
+        Self {
+            api_key: api_key.to_string(),
+        }
+    }
+}
+
+/// Build the Messages API body: the system prompt goes in the top-level
+/// `system` field, the conversation in `messages`.
+fn request_body(model: &str, pairs: &[ChatMessagePair]) -> Value {
+    let (system, messages): (Vec<_>, Vec<_>) = chat_messages(pairs)
+        .into_iter()
+        .partition(|m| m.role == "system");
+
+    let system = system
+        .into_iter()
+        .map(|m| m.content)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    json!({
+        "model": model,
+        "system": system,
+        "messages": messages,
+        "max_tokens": MAX_TOKENS,
+        "stream": true
+    })
+}
+
+/// Map one event of the Messages stream to a generation event, `None` for the
+/// events we don't care about (`message_start`, `ping`, ...).
+fn parse_event(event: &str, data: &str) -> Result<Option<GenerationEvent>, ProviderError> {
+    match event {
+        "content_block_delta" => {
+            let m: Value = serde_json::from_str(data)?;
+            Ok(m["delta"]["text"]
+                .as_str()
+                .map(|text| GenerationEvent::Text(text.to_string())))
+        }
+        "message_stop" => Ok(Some(GenerationEvent::End)),
+        "error" => {
+            let m: Value = serde_json::from_str(data)?;
+            let message = m["error"]["message"].as_str().unwrap_or("unknown error");
+            Err(message.to_string().into())
+        }
+        _ => Ok(None),
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    async fn list_models(&self) -> Result<Vec<Model>, ProviderError> {
+        let client = reqwest::Client::new();
+        let res: ModelList = client
+            .get(format!("{}/models", ANTHROPIC_BASE_URL))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(res
+            .data
+            .into_iter()
+            .map(|m| Model {
+                id: m.id,
+                owned_by: "anthropic".to_string(),
+            })
+            .collect())
+    }
+
+    async fn generate(
+        &self,
+        model: &str,
+        messages: Vec<ChatMessagePair>,
+        sender: mpsc::Sender<Result<GenerationEvent, axum::Error>>,
+        cancel: CancellationToken,
+    ) -> Result<(), ProviderError> {
+        let body = request_body(model, &messages);
+
+        let client = reqwest::Client::new();
+        let request = client
+            .post(format!("{}/messages", ANTHROPIC_BASE_URL))
+            .header("x-api-key", HeaderValue::from_str(&self.api_key)?)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .body(body.to_string());
+
+        let mut stream = ReqwestEventSource::new(request)?;
+
+        loop {
+            let event = tokio::select! {
+                _ = cancel.cancelled() => {
+                    stream.close();
+                    break;
+                }
+                event = stream.next() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+            };
+            match event {
+                Ok(ReqwestEvent::Open) => {}
+                Ok(ReqwestEvent::Message(message)) => {
+                    match parse_event(&message.event, &message.data) {
+                        Ok(Some(GenerationEvent::End)) => {
+                            stream.close();
+                            let _ = sender.send(Ok(GenerationEvent::End)).await;
+                            break;
+                        }
+                        Ok(Some(event)) => {
+                            if sender.send(Ok(event)).await.is_err() {
+                                break; // Receiver has dropped, stop sending.
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(err) => {
+                            stream.close();
+                            return Err(err);
+                        }
+                    }
+                }
+                Err(err) => {
+                    tracing::error!("anthropic stream error: {}", err);
+                    stream.close();
+                    if sender.send(Err(axum::Error::new(err))).await.is_err() {
+                        break; // Receiver has dropped, stop sending.
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_body() {
+        let pairs = vec![ChatMessagePair {
+            id: 1,
+            chat_id: 1,
+            message_block_id: 1,
+            provider: "anthropic".to_string(),
+            model: "claude-3-haiku-20240307".to_string(),
+            human_message: "Hello".to_string(),
+            ai_message: None,
+            status: "pending".to_string(),
+            block_rank: 1,
+            block_size: 1,
+            prev_pair_id: None,
+            next_pair_id: None,
+        }];
+
+        let body = request_body("claude-3-haiku-20240307", &pairs);
+        assert_eq!(body["system"], "You are a helpful assistant.");
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(body["messages"][0]["role"], "user");
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let delta = r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}"#;
+        assert!(matches!(
+            parse_event("content_block_delta", delta).unwrap(),
+            Some(GenerationEvent::Text(text)) if text == "Hello"
+        ));
+
+        let start = r#"{"type":"message_start","message":{"id":"msg_1","content":[]}}"#;
+        assert!(parse_event("message_start", start).unwrap().is_none());
+        assert!(parse_event("ping", r#"{"type":"ping"}"#).unwrap().is_none());
+
+        assert!(matches!(
+            parse_event("message_stop", r#"{"type":"message_stop"}"#).unwrap(),
+            Some(GenerationEvent::End)
+        ));
+
+        let error =
+            r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#;
+        assert!(parse_event("error", error).is_err());
+    }
+}
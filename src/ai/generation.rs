@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use super::stream::GenerationEvent;
+
+#[derive(Default)]
+struct Progress {
+    text: String,
+    done: bool,
+}
+
+/// The generation of the AI message of a pair. It runs on its own task and
+/// outlives the SSE requests following it, so a reload can attach to it.
+pub struct Generation {
+    id: u64,
+    pub pair_id: i64,
+    pub token: CancellationToken,
+    progress: Mutex<Progress>,
+    events: broadcast::Sender<GenerationEvent>,
+}
+
+impl Generation {
+    fn new(id: u64, pair_id: i64) -> Self {
+        let (events, _) = broadcast::channel(64);
+        Self {
+            id,
+            pair_id,
+            token: CancellationToken::new(),
+            progress: Mutex::new(Progress::default()),
+            events,
+        }
+    }
+
+    /// Append generated text and notify the listeners.
+    pub fn push(&self, text: &str) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.text.push_str(text);
+        let _ = self.events.send(GenerationEvent::Text(text.to_string()));
+    }
+
+    /// Mark the generation as done and notify the listeners.
+    pub fn end(&self) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.done = true;
+        let _ = self.events.send(GenerationEvent::End);
+    }
+
+    /// The text generated so far.
+    pub fn text(&self) -> String {
+        self.progress.lock().unwrap().text.clone()
+    }
+
+    /// Listen to the generation, `None` when it is already done.
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<GenerationEvent>> {
+        let progress = self.progress.lock().unwrap();
+        (!progress.done).then(|| self.events.subscribe())
+    }
+}
+
+#[derive(Default)]
+struct Running {
+    next_id: u64,
+    chats: HashMap<i64, Arc<Generation>>,
+}
+
+/// The generations running for each chat, so other requests can attach to
+/// them or cancel them (the stop button).
+#[derive(Clone, Default)]
+pub struct GenerationRegistry {
+    running: Arc<Mutex<Running>>,
+}
+
+impl GenerationRegistry {
+    /// The generation running for the chat, if any.
+    pub fn get(&self, chat_id: i64) -> Option<Arc<Generation>> {
+        self.running.lock().unwrap().chats.get(&chat_id).cloned()
+    }
+
+    /// The generation running for the pair of the chat, or a new one when
+    /// there is none, in which case `true` is returned and the caller must run
+    /// it. A generation running for another pair of the chat is cancelled.
+    pub fn start(&self, chat_id: i64, pair_id: i64) -> (Arc<Generation>, bool) {
+        let mut running = self.running.lock().unwrap();
+
+        if let Some(generation) = running.chats.get(&chat_id) {
+            if generation.pair_id == pair_id {
+                return (generation.clone(), false);
+            }
+        }
+
+        running.next_id += 1;
+        let generation = Arc::new(Generation::new(running.next_id, pair_id));
+        if let Some(previous) = running.chats.insert(chat_id, generation.clone()) {
+            previous.token.cancel();
+        }
+
+        (generation, true)
+    }
+
+    /// Cancel the generation running for the chat, returns whether there was
+    /// one. It stays registered until its task finishes it.
+    pub fn cancel(&self, chat_id: i64) -> bool {
+        match self.running.lock().unwrap().chats.get(&chat_id) {
+            Some(generation) => {
+                generation.token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget a generation once it is done, unless another one replaced it.
+    pub fn finish(&self, chat_id: i64, generation: &Generation) {
+        let mut running = self.running.lock().unwrap();
+        if running
+            .chats
+            .get(&chat_id)
+            .is_some_and(|running| running.id == generation.id)
+        {
+            running.chats.remove(&chat_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel() {
+        let registry = GenerationRegistry::default();
+        let (generation, started) = registry.start(1, 1);
+        assert!(started);
+        assert!(!generation.token.is_cancelled());
+
+        assert!(!registry.cancel(2));
+        assert!(registry.cancel(1));
+        assert!(generation.token.is_cancelled());
+
+        registry.finish(1, &generation);
+        assert!(!registry.cancel(1));
+    }
+
+    #[test]
+    fn test_start_attaches_or_replaces() {
+        let registry = GenerationRegistry::default();
+        let (first, _) = registry.start(1, 1);
+
+        // Same pair: attach to the running generation
+        let (attached, started) = registry.start(1, 1);
+        assert!(!started);
+        assert!(Arc::ptr_eq(&first, &attached));
+
+        // Another pair: the first generation is cancelled
+        let (second, started) = registry.start(1, 2);
+        assert!(started);
+        assert!(first.token.is_cancelled());
+
+        // The first generation finishing doesn't forget the second one
+        registry.finish(1, &first);
+        assert!(registry.get(1).is_some_and(|g| Arc::ptr_eq(&g, &second)));
+        registry.finish(1, &second);
+        assert!(registry.get(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let generation = Generation::new(1, 1);
+        generation.push("Hel");
+        let mut receiver = generation.subscribe().unwrap();
+
+        generation.push("lo");
+        generation.end();
+        assert_eq!(generation.text(), "Hello");
+        assert!(matches!(receiver.recv().await, Ok(GenerationEvent::Text(t)) if t == "lo"));
+        assert!(matches!(receiver.recv().await, Ok(GenerationEvent::End)));
+
+        // Nothing to listen to once done
+        assert!(generation.subscribe().is_none());
+    }
+}
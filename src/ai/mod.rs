@@ -1 +1,6 @@
+pub mod anthropic;
+pub mod generation;
+pub mod ollama;
+pub mod openai;
+pub mod registry;
 pub mod stream;
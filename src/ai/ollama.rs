@@ -0,0 +1,193 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::data::model::ChatMessagePair;
+
+use super::stream::{chat_messages, GenerationEvent, LlmProvider, Model, ProviderError};
+
+// Define a struct to represent a model returned by `/api/tags`.
+#[derive(Serialize, Deserialize, Debug)]
+struct OllamaModel {
+    name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TagList {
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkMessage {
+    content: String,
+}
+
+// One line of the `/api/chat` NDJSON stream.
+#[derive(Deserialize, Debug)]
+struct ChatChunk {
+    message: Option<ChunkMessage>,
+    #[serde(default)]
+    done: bool,
+    error: Option<String>,
+}
+
+/// Talks to an Ollama compatible server (Ollama, llama.cpp server, ...).
+pub struct OllamaProvider {
+    base_url: String,
+}
+
+impl OllamaProvider {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+/// Parse the complete lines of `buffer` into generation events, leaving any
+/// trailing partial line in the buffer for the next chunk.
+fn drain_lines(buffer: &mut Vec<u8>) -> Result<Vec<GenerationEvent>, ProviderError> {
+    let mut events = Vec::new();
+
+    while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+        let line = buffer.drain(..=pos).collect::<Vec<u8>>();
+
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        let chunk: ChatChunk = serde_json::from_slice(&line)?;
+        if let Some(error) = chunk.error {
+            return Err(error.into());
+        }
+        if let Some(message) = chunk.message {
+            if !message.content.is_empty() {
+                events.push(GenerationEvent::Text(message.content));
+            }
+        }
+        if chunk.done {
+            events.push(GenerationEvent::End);
+        }
+    }
+
+    Ok(events)
+}
+
+#[async_trait]
+impl LlmProvider for OllamaProvider {
+    async fn list_models(&self) -> Result<Vec<Model>, ProviderError> {
+        let client = reqwest::Client::new();
+        let res: TagList = client
+            .get(format!("{}/api/tags", self.base_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(res
+            .models
+            .into_iter()
+            .map(|m| Model {
+                id: m.name,
+                owned_by: "ollama".to_string(),
+            })
+            .collect())
+    }
+
+    async fn generate(
+        &self,
+        model: &str,
+        messages: Vec<ChatMessagePair>,
+        sender: mpsc::Sender<Result<GenerationEvent, axum::Error>>,
+        cancel: CancellationToken,
+    ) -> Result<(), ProviderError> {
+        let body = json!({
+            "model": model,
+            "messages": chat_messages(&messages),
+            "stream": true
+        });
+
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("{}/api/chat", self.base_url))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let mut stream = res.bytes_stream();
+        let mut buffer = Vec::new();
+
+        loop {
+            let chunk = tokio::select! {
+                _ = cancel.cancelled() => break, // Dropping the stream aborts the request.
+                chunk = stream.next() => match chunk {
+                    Some(chunk) => chunk,
+                    None => break,
+                },
+            };
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    tracing::error!("ollama stream error: {}", err);
+                    let _ = sender.send(Err(axum::Error::new(err))).await;
+                    break;
+                }
+            };
+            buffer.extend_from_slice(&chunk);
+
+            for event in drain_lines(&mut buffer)? {
+                let is_end = matches!(event, GenerationEvent::End);
+                if sender.send(Ok(event)).await.is_err() || is_end {
+                    return Ok(()); // Receiver has dropped or the stream is done.
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain_lines() {
+        let mut buffer = concat!(
+            r#"{"model":"llama2","message":{"role":"assistant","content":"Hel"},"done":false}"#,
+            "\n",
+            r#"{"model":"llama2","message":{"role":"assistant","content":"lo"},"done":false}"#,
+            "\n",
+            r#"{"model":"llama2","message":{"role":"assis"#
+        )
+        .as_bytes()
+        .to_vec();
+
+        let events = drain_lines(&mut buffer).unwrap();
+        assert!(
+            matches!(&events[..], [GenerationEvent::Text(a), GenerationEvent::Text(b)] if a == "Hel" && b == "lo")
+        );
+
+        buffer.extend_from_slice(
+            concat!(
+                r#"tant","content":""},"done":true,"total_duration":1}"#,
+                "\n"
+            )
+            .as_bytes(),
+        );
+        let events = drain_lines(&mut buffer).unwrap();
+        assert!(matches!(&events[..], [GenerationEvent::End]));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_drain_lines_error() {
+        let mut buffer = b"{\"error\":\"model 'foo' not found\"}\n".to_vec();
+        assert!(drain_lines(&mut buffer).is_err());
+    }
+}
@@ -0,0 +1,469 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use async_trait::async_trait;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    RequestBuilder,
+};
+use reqwest_eventsource::{Event as ReqwestEvent, EventSource as ReqwestEventSource};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::{data::model::ChatMessagePair, security::secret::KeyCipher, User};
+
+use super::stream::{chat_messages, GenerationEvent, LlmProvider, Model, ProviderError};
+
+const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+
+// Define a struct to represent an OpenAI model.
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAiModel {
+    id: String,
+    object: String,
+    created: i64,
+    owned_by: String,
+}
+
+// Define a struct to represent the list of models.
+#[derive(Serialize, Deserialize, Debug)]
+struct ModelList {
+    object: String,
+    data: Vec<OpenAiModel>,
+}
+
+/// Talks to OpenAI or any OpenAI compatible API (Azure OpenAI, vLLM,
+/// LiteLLM, ...).
+pub struct OpenAiProvider {
+    api_key: String,
+    base_url: String,
+    api_version: Option<String>,
+    extra_headers: Option<EncryptedHeaders>,
+}
+
+// The extra headers as stored in the settings, only decrypted to prepare a
+// request.
+struct EncryptedHeaders {
+    cipher: KeyCipher,
+    stored: String,
+    context: String,
+}
+
+/// What the extra headers of a user are encrypted with.
+pub fn extra_headers_context(user_id: i64) -> String {
+    format!("openai:headers:{}", user_id)
+}
+
+/// Check a base URL set by a user: http or https only, and a public host
+/// unless `allow_private`. The server sends the requests, a private address
+/// would reach the services next to it. Host names are checked as written,
+/// not by what they resolve to.
+pub fn check_base_url(base_url: &str, allow_private: bool) -> Result<(), &'static str> {
+    let url = reqwest::Url::parse(base_url).map_err(|_| "invalid URL")?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err("only http and https URLs are allowed");
+    }
+    let host = url.host_str().ok_or("the URL has no host")?;
+    let private = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(IpAddr::V4(ip)) => is_private_ipv4(ip),
+        Ok(IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_ipv4(ip),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // Unique local fc00::/7 and link local fe80::/10
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+        Err(_) => {
+            let domain = host.trim_end_matches('.').to_lowercase();
+            domain == "localhost" || domain.ends_with(".localhost")
+        }
+    };
+    if private && !allow_private {
+        return Err("private and loopback hosts aren't allowed");
+    }
+    Ok(())
+}
+
+fn is_private_ipv4(ip: Ipv4Addr) -> bool {
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // Carrier-grade NAT 100.64.0.0/10
+        || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+}
+
+impl OpenAiProvider {
+    pub fn new(api_key: &str) -> Self {
+        Self {
+            api_key: api_key.to_string(),
+            base_url: OPENAI_BASE_URL.to_string(),
+            api_version: None,
+            extra_headers: None,
+        }
+    }
+
+    /// Build the provider from the user settings and decrypted key, the extra
+    /// headers are decrypted with `cipher` when sending requests.
+    pub fn for_user(user: &User, api_key: &str, cipher: &KeyCipher) -> Self {
+        let mut provider = Self::new(api_key);
+        if let Some(base_url) = user.openai_base_url.as_deref() {
+            provider = provider.with_base_url(base_url);
+        }
+        if let Some(api_version) = user.openai_api_version.as_deref() {
+            provider = provider.with_api_version(api_version);
+        }
+        if let Some(stored) = user.openai_extra_headers.as_deref() {
+            provider.extra_headers = Some(EncryptedHeaders {
+                cipher: cipher.clone(),
+                stored: stored.to_string(),
+                context: extra_headers_context(user.id),
+            });
+        }
+        provider
+    }
+
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_api_version(mut self, api_version: &str) -> Self {
+        self.api_version = Some(api_version.to_string());
+        self
+    }
+
+    /// Add the authentication, the extra headers and the api-version query to
+    /// a request. Azure (api-version set) expects the key in `api-key`.
+    fn prepare(&self, mut request: RequestBuilder) -> Result<RequestBuilder, ProviderError> {
+        let mut headers = HeaderMap::new();
+        match self.api_version {
+            Some(ref api_version) => {
+                headers.insert("api-key", HeaderValue::from_str(&self.api_key)?);
+                request = request.query(&[("api-version", api_version)]);
+            }
+            None => {
+                headers.insert(
+                    AUTHORIZATION,
+                    HeaderValue::from_str(&format!("Bearer {}", self.api_key))?,
+                );
+            }
+        }
+
+        let extra_headers = match self.extra_headers {
+            Some(ref encrypted) => parse_headers(
+                &encrypted
+                    .cipher
+                    .decrypt(&encrypted.stored, &encrypted.context)?,
+            ),
+            None => Vec::new(),
+        };
+        for (name, value) in &extra_headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+
+        Ok(request.headers(headers))
+    }
+}
+
+/// Parse extra headers given one `Name: value` per line, skipping blank or
+/// malformed lines.
+pub fn parse_headers(headers: &str) -> Vec<(String, String)> {
+    headers
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+/// The names of the extra headers, shown in the settings instead of their
+/// values.
+pub fn header_names(headers: &str) -> String {
+    parse_headers(headers)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    async fn list_models(&self) -> Result<Vec<Model>, ProviderError> {
+        let client = reqwest::Client::new();
+        let request = self.prepare(client.get(format!("{}/models", self.base_url)))?;
+        let res: ModelList = request.send().await?.error_for_status()?.json().await?;
+
+        Ok(res
+            .data
+            .into_iter()
+            .map(|m| Model {
+                id: m.id,
+                owned_by: m.owned_by,
+            })
+            .collect())
+    }
+
+    async fn verify(&self) -> Result<(), ProviderError> {
+        match self.list_models().await {
+            Ok(_) => Ok(()),
+            // Some compatible gateways (Azure deployments, ...) don't expose the
+            // models endpoint, only a rejected key is an error there.
+            Err(e)
+                if self.base_url != OPENAI_BASE_URL
+                    && e.downcast_ref::<reqwest::Error>()
+                        .and_then(|e| e.status())
+                        .is_some_and(|status| status == reqwest::StatusCode::NOT_FOUND) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn generate(
+        &self,
+        model: &str,
+        messages: Vec<ChatMessagePair>,
+        sender: mpsc::Sender<Result<GenerationEvent, axum::Error>>,
+        cancel: CancellationToken,
+    ) -> Result<(), ProviderError> {
+        // The API endpoint for chat completions
+        let url = format!("{}/chat/completions", self.base_url);
+
+        // Prepare the request body
+        let body = json!({
+            "model": model,
+            "messages": chat_messages(&messages),
+            "stream": true
+        });
+
+        // Create a client
+        let client = reqwest::Client::new();
+
+        // Create a request
+        let request = self
+            .prepare(client.post(url))?
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .body(body.to_string());
+
+        // Start streaming
+        let mut stream = ReqwestEventSource::new(request)?;
+
+        // Handle streaming events
+        loop {
+            let event = tokio::select! {
+                _ = cancel.cancelled() => {
+                    stream.close();
+                    break;
+                }
+                event = stream.next() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+            };
+            match event {
+                Ok(ReqwestEvent::Open) => {}
+                Ok(ReqwestEvent::Message(message)) => {
+                    if message.data.trim() == "[DONE]" {
+                        stream.close();
+                        // The receiver may already be gone, we are done either way.
+                        let _ = sender.send(Ok(GenerationEvent::End)).await;
+                        break;
+                    }
+
+                    let m: Value = serde_json::from_str(&message.data)?;
+                    if let Some(text) = m["choices"][0]["delta"]["content"].as_str() {
+                        if sender
+                            .send(Ok(GenerationEvent::Text(text.to_string())))
+                            .await
+                            .is_err()
+                        {
+                            break; // Receiver has dropped, stop sending.
+                        }
+                    }
+                }
+                Err(err) => {
+                    tracing::error!("openai stream error: {}", err);
+                    stream.close();
+                    if sender.send(Err(axum::Error::new(err))).await.is_err() {
+                        break; // Receiver has dropped, stop sending.
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        http::StatusCode,
+        response::{IntoResponse, Response},
+        routing::post,
+        Json, Router,
+    };
+    use tokio_stream::wrappers::ReceiverStream;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_headers() {
+        let headers =
+            parse_headers("X-Team: platform\n\nnot a header\n Authorization : Bearer abc:def \n");
+        assert_eq!(
+            headers,
+            vec![
+                ("X-Team".to_string(), "platform".to_string()),
+                ("Authorization".to_string(), "Bearer abc:def".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prepare_extra_headers() {
+        let cipher = KeyCipher::new(&[7; 32]);
+        let context = extra_headers_context(1);
+        let mut provider = OpenAiProvider::new("secret");
+        provider.extra_headers = Some(EncryptedHeaders {
+            stored: cipher.encrypt("X-Team: platform\nX-Gateway-Key: abc", &context),
+            cipher: cipher.clone(),
+            context,
+        });
+        let client = reqwest::Client::new();
+        let request = provider
+            .prepare(client.get(OPENAI_BASE_URL))
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.headers()["x-team"], "platform");
+        assert_eq!(request.headers()["x-gateway-key"], "abc");
+
+        // Headers of another user don't decrypt
+        provider.extra_headers.as_mut().unwrap().context = extra_headers_context(2);
+        assert!(provider.prepare(client.get(OPENAI_BASE_URL)).is_err());
+    }
+
+    #[test]
+    fn test_header_names() {
+        assert_eq!(
+            header_names("X-Team: platform\nbroken\nAuthorization: Bearer abc"),
+            "X-Team, Authorization"
+        );
+        assert_eq!(header_names(""), "");
+    }
+
+    #[test]
+    fn test_check_base_url() {
+        assert!(check_base_url("https://example.openai.azure.com/openai", false).is_ok());
+        assert!(check_base_url("http://vllm.example.com:8000/v1", false).is_ok());
+        assert!(check_base_url("file:///etc/passwd", true).is_err());
+        assert!(check_base_url("ftp://example.com/v1", true).is_err());
+        assert!(check_base_url("data:text/plain,hello", true).is_err());
+        assert!(check_base_url("not a url", true).is_err());
+
+        // Private hosts only when allowed
+        for url in [
+            "http://localhost:8000/v1",
+            "http://127.0.0.1:11434/v1",
+            "http://10.0.0.5/v1",
+            "http://169.254.169.254/latest",
+            "http://[::1]:8000/v1",
+            "http://[fd00::1]/v1",
+            "http://[::ffff:192.168.1.1]/v1",
+            "http://0.0.0.0/v1",
+        ] {
+            assert!(check_base_url(url, false).is_err(), "{}", url);
+            assert!(check_base_url(url, true).is_ok(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn test_prepare_azure() {
+        let provider = OpenAiProvider::new("secret")
+            .with_base_url("https://example.openai.azure.com/openai/deployments/gpt-4/")
+            .with_api_version("2023-05-15");
+        let client = reqwest::Client::new();
+        let request = provider
+            .prepare(client.get(format!("{}/chat/completions", provider.base_url)))
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            request.url().as_str(),
+            "https://example.openai.azure.com/openai/deployments/gpt-4/chat/completions?api-version=2023-05-15"
+        );
+        assert_eq!(request.headers()["api-key"], "secret");
+        assert!(request.headers().get(AUTHORIZATION).is_none());
+    }
+
+    // Answer like the chat completions endpoint, when called with the key
+    async fn completions(headers: HeaderMap, Json(body): Json<Value>) -> Response {
+        let authorized =
+            headers.get(AUTHORIZATION).map(|h| h.to_str().unwrap()) == Some("Bearer secret");
+        if !authorized || body["model"] != "gpt-4" || body["stream"] != true {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        let body = concat!(
+            "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi \"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"there!\"}}]}\n\n",
+            "data: [DONE]\n\n",
+        );
+        ([(CONTENT_TYPE, "text/event-stream")], body).into_response()
+    }
+
+    #[tokio::test]
+    async fn test_generate() {
+        let app = Router::new().route("/v1/chat/completions", post(completions));
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let base_url = format!("http://{}/v1", server.local_addr());
+        tokio::spawn(server);
+
+        let pairs = vec![ChatMessagePair {
+            id: 1,
+            chat_id: 1,
+            message_block_id: 1,
+            provider: "openai".to_string(),
+            model: "gpt-4".to_string(),
+            human_message: "Hello".to_string(),
+            ai_message: None,
+            status: "pending".to_string(),
+            block_rank: 1,
+            block_size: 1,
+            prev_pair_id: None,
+            next_pair_id: None,
+        }];
+        let (sender, receiver) = mpsc::channel(10);
+        let provider = OpenAiProvider::new("secret").with_base_url(&base_url);
+        provider
+            .generate("gpt-4", pairs, sender, CancellationToken::new())
+            .await
+            .unwrap();
+
+        let events: Vec<_> = ReceiverStream::new(receiver)
+            .map(|event| match event.unwrap() {
+                GenerationEvent::Text(text) => text,
+                GenerationEvent::End => "<end>".to_string(),
+            })
+            .collect()
+            .await;
+        assert_eq!(events, vec!["Hi ", "there!", "<end>"]);
+    }
+}
@@ -0,0 +1,357 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{data::model::ModelMetadata, security::secret::KeyCipher, User};
+
+use super::{
+    anthropic::AnthropicProvider,
+    ollama::OllamaProvider,
+    openai::OpenAiProvider,
+    stream::{api_key, key_owner, KeyOwner, LlmProvider, Model, ProviderKind},
+};
+
+// How long the models listed by a provider are reused for a given key.
+const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+// How long a failed listing is remembered, so pages don't call the provider
+// on every load while it is down or the key is wrong.
+const FAILURE_TTL: Duration = Duration::from_secs(30);
+
+// Listed models by cache key, with the time they were listed. `None` when the
+// listing failed.
+type ModelCache = HashMap<u64, (Instant, Option<Vec<Model>>)>;
+
+fn cache_ttl(listed: &Option<Vec<Model>>) -> Duration {
+    verify_ttl(listed.is_some())
+}
+
+// Whether the credentials were valid by cache key, with the time they were
+// checked.
+type VerifyCache = HashMap<u64, (Instant, bool)>;
+
+fn verify_ttl(valid: bool) -> Duration {
+    if valid {
+        CACHE_TTL
+    } else {
+        FAILURE_TTL
+    }
+}
+
+/// A model as shown in the model picker.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub provider: ProviderKind,
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub context_window: Option<i64>,
+    pub input_price: Option<f64>,
+    pub output_price: Option<f64>,
+}
+
+impl ModelInfo {
+    fn unknown(provider: ProviderKind, id: &str) -> Self {
+        Self {
+            provider,
+            id: id.to_string(),
+            name: id.to_string(),
+            description: String::new(),
+            context_window: None,
+            input_price: None,
+            output_price: None,
+        }
+    }
+
+    fn from_metadata(provider: ProviderKind, metadata: &ModelMetadata) -> Self {
+        Self {
+            provider,
+            id: metadata.model_id.clone(),
+            name: metadata.display_name.clone(),
+            description: metadata.description.clone(),
+            context_window: metadata.context_window,
+            input_price: metadata.input_price,
+            output_price: metadata.output_price,
+        }
+    }
+}
+
+/// Merges what the providers list for the user credentials with the model
+/// metadata stored in the `models` table.
+#[derive(Clone)]
+pub struct ModelRegistry {
+    pool: Arc<SqlitePool>,
+    ollama_url: Option<String>,
+    cipher: KeyCipher,
+    cache: Arc<Mutex<ModelCache>>,
+    verified: Arc<Mutex<VerifyCache>>,
+}
+
+impl ModelRegistry {
+    pub fn new(pool: Arc<SqlitePool>, ollama_url: Option<String>, cipher: KeyCipher) -> Self {
+        Self {
+            pool,
+            ollama_url,
+            cipher,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            verified: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The provider to use for `kind` with the user settings, `None` when it
+    /// isn't configured.
+    pub fn provider(&self, user: &User, kind: ProviderKind) -> Option<Arc<dyn LlmProvider>> {
+        match kind {
+            ProviderKind::OpenAi => {
+                let key = api_key(&self.cipher, user, kind).unwrap_or_default();
+                match key_owner(user, kind) {
+                    KeyOwner::User => {
+                        Some(Arc::new(OpenAiProvider::for_user(user, &key, &self.cipher)))
+                    }
+                    KeyOwner::Organization(_) => Some(Arc::new(OpenAiProvider::new(&key))),
+                }
+            }
+            ProviderKind::Anthropic => api_key(&self.cipher, user, kind)
+                .map(|key| Arc::new(AnthropicProvider::new(&key)) as Arc<dyn LlmProvider>),
+            ProviderKind::Ollama => self
+                .ollama_url
+                .as_ref()
+                .map(|url| Arc::new(OllamaProvider::new(url)) as Arc<dyn LlmProvider>),
+        }
+    }
+
+    /// Whether the provider for `kind` is configured and accepts the user
+    /// credentials, remembered like the listed models.
+    pub async fn verify(&self, user: &User, kind: ProviderKind) -> bool {
+        let Some(provider) = self.provider(user, kind) else {
+            return false;
+        };
+        let key = cache_key(user, kind);
+
+        if let Some((at, valid)) = self.verified.lock().unwrap().get(&key) {
+            if at.elapsed() < verify_ttl(*valid) {
+                return *valid;
+            }
+        }
+
+        let valid = provider.verify().await.is_ok();
+
+        let mut verified = self.verified.lock().unwrap();
+        verified.retain(|_, (at, valid)| at.elapsed() < verify_ttl(*valid));
+        verified.insert(key, (Instant::now(), valid));
+
+        valid
+    }
+
+    /// The models the user can pick, in the order of the `models` table.
+    pub async fn models_for(&self, user: &User) -> sqlx::Result<Vec<ModelInfo>> {
+        let mut models = Vec::new();
+
+        for kind in [
+            ProviderKind::OpenAi,
+            ProviderKind::Anthropic,
+            ProviderKind::Ollama,
+        ] {
+            let Some(provider) = self.provider(user, kind) else {
+                continue;
+            };
+
+            let listed = self.listed_models(user, kind, provider.as_ref()).await;
+            let metadata = self.metadata(kind).await?;
+            models.extend(merge(kind, listed, &metadata));
+        }
+
+        Ok(models)
+    }
+
+    /// Look a model up without calling the provider, unknown models get a
+    /// bare entry named after their id.
+    pub async fn find(&self, kind: ProviderKind, id: &str) -> sqlx::Result<ModelInfo> {
+        let provider = kind.as_str();
+        let metadata = sqlx::query_as!(
+            ModelMetadata,
+            "SELECT model_id, display_name, description, context_window, input_price, output_price FROM models WHERE provider = ? AND model_id = ?",
+            provider,
+            id
+        )
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        Ok(metadata
+            .map(|m| ModelInfo::from_metadata(kind, &m))
+            .unwrap_or_else(|| ModelInfo::unknown(kind, id)))
+    }
+
+    async fn metadata(&self, kind: ProviderKind) -> sqlx::Result<Vec<ModelMetadata>> {
+        let provider = kind.as_str();
+        sqlx::query_as!(
+            ModelMetadata,
+            "SELECT model_id, display_name, description, context_window, input_price, output_price FROM models WHERE provider = ? ORDER BY id",
+            provider
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // The models listed by the provider, `None` when the listing failed.
+    async fn listed_models(
+        &self,
+        user: &User,
+        kind: ProviderKind,
+        provider: &dyn LlmProvider,
+    ) -> Option<Vec<Model>> {
+        let key = cache_key(user, kind);
+
+        if let Some((at, listed)) = self.cache.lock().unwrap().get(&key) {
+            if at.elapsed() < cache_ttl(listed) {
+                return listed.clone();
+            }
+        }
+
+        let listed = match provider.list_models().await {
+            Ok(models) => Some(models),
+            Err(e) => {
+                eprintln!("Error listing {} models: {:?}", kind.as_str(), e);
+                None
+            }
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (at, listed)| at.elapsed() < cache_ttl(listed));
+        cache.insert(key, (Instant::now(), listed.clone()));
+
+        listed
+    }
+}
+
+// The listed models only depend on the provider, its endpoint and the key
+// (encrypted, it changes with the key).
+fn cache_key(user: &User, kind: ProviderKind) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    kind.as_str().hash(&mut hasher);
+    if let KeyOwner::Organization(organization_id) = key_owner(user, kind) {
+        organization_id.hash(&mut hasher);
+        user.organization_openai_api_key.hash(&mut hasher);
+        user.organization_anthropic_api_key.hash(&mut hasher);
+        return hasher.finish();
+    }
+    match kind {
+        ProviderKind::OpenAi => {
+            user.openai_api_key.hash(&mut hasher);
+            user.openai_base_url.hash(&mut hasher);
+            user.openai_api_version.hash(&mut hasher);
+            user.openai_extra_headers.hash(&mut hasher);
+        }
+        ProviderKind::Anthropic => user.anthropic_api_key.hash(&mut hasher),
+        ProviderKind::Ollama => {}
+    }
+    hasher.finish()
+}
+
+/// Combine the listed models with their metadata:
+/// - listed models with metadata are kept, in the metadata order,
+/// - when none of the listed models is known (local servers, gateways...) all
+///   of them are kept as is,
+/// - when the listing failed we fall back to the metadata alone.
+fn merge(
+    kind: ProviderKind,
+    listed: Option<Vec<Model>>,
+    metadata: &[ModelMetadata],
+) -> Vec<ModelInfo> {
+    let Some(listed) = listed else {
+        return metadata
+            .iter()
+            .map(|m| ModelInfo::from_metadata(kind, m))
+            .collect();
+    };
+
+    let known = metadata
+        .iter()
+        .filter(|m| listed.iter().any(|l| l.id == m.model_id))
+        .map(|m| ModelInfo::from_metadata(kind, m))
+        .collect::<Vec<_>>();
+
+    if !known.is_empty() {
+        return known;
+    }
+
+    listed
+        .iter()
+        .map(|m| ModelInfo::unknown(kind, &m.id))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(id: &str) -> ModelMetadata {
+        ModelMetadata {
+            model_id: id.to_string(),
+            display_name: id.to_uppercase(),
+            description: String::new(),
+            context_window: Some(8192),
+            input_price: None,
+            output_price: None,
+        }
+    }
+
+    fn listed(ids: &[&str]) -> Option<Vec<Model>> {
+        Some(
+            ids.iter()
+                .map(|id| Model {
+                    id: id.to_string(),
+                    owned_by: "test".to_string(),
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_merge_known_models() {
+        let metadata = vec![metadata("gpt-4"), metadata("gpt-3.5-turbo")];
+        let models = merge(
+            ProviderKind::OpenAi,
+            listed(&["whisper-1", "gpt-3.5-turbo", "gpt-4"]),
+            &metadata,
+        );
+
+        let ids = models.iter().map(|m| m.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["gpt-4", "gpt-3.5-turbo"]);
+        assert_eq!(models[0].name, "GPT-4");
+        assert_eq!(models[0].context_window, Some(8192));
+    }
+
+    #[test]
+    fn test_merge_unknown_models() {
+        let metadata = vec![metadata("gpt-4")];
+        let models = merge(
+            ProviderKind::OpenAi,
+            listed(&["mistral-7b-instruct"]),
+            &metadata,
+        );
+
+        assert_eq!(
+            models,
+            vec![ModelInfo::unknown(
+                ProviderKind::OpenAi,
+                "mistral-7b-instruct"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_merge_listing_failed() {
+        let metadata = vec![metadata("gpt-4")];
+        let models = merge(ProviderKind::OpenAi, None, &metadata);
+
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].id, "gpt-4");
+    }
+}
@@ -1,211 +1,348 @@
-use axum::Error;
-use reqwest::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
-use reqwest_eventsource::{Event as ReqwestEvent, EventSource as ReqwestEventSource};
+use std::str::FromStr;
+
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
 use tokio::sync::mpsc;
-use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    data::model::{ChatMessagePair, PairStatus},
+    security::secret::KeyCipher,
+    User,
+};
+
+pub type ProviderError = Box<dyn std::error::Error + Send + Sync>;
+
+pub const SYSTEM_PROMPT: &str = "You are a helpful assistant.";
+
+/// The backends a chat can be generated with, stored in `chats.provider`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    OpenAi,
+    Anthropic,
+    Ollama,
+}
+
+impl ProviderKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProviderKind::OpenAi => "openai",
+            ProviderKind::Anthropic => "anthropic",
+            ProviderKind::Ollama => "ollama",
+        }
+    }
+
+    /// What the user API key for the provider is encrypted with, so a stored
+    /// key only decrypts for its provider and user.
+    pub fn key_context(&self, user_id: i64) -> String {
+        format!("{}:{}", self.as_str(), user_id)
+    }
+
+    /// What the organization API key for the provider is encrypted with.
+    pub fn organization_key_context(&self, organization_id: i64) -> String {
+        format!("{}:organization:{}", self.as_str(), organization_id)
+    }
+}
 
-use crate::data::model::ChatMessagePair;
+impl FromStr for ProviderKind {
+    type Err = String;
 
-// Define a struct to represent a model.
-#[derive(Serialize, Deserialize, Debug)]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "openai" => Ok(ProviderKind::OpenAi),
+            "anthropic" => Ok(ProviderKind::Anthropic),
+            "ollama" => Ok(ProviderKind::Ollama),
+            _ => Err(format!("unknown provider: {}", s)),
+        }
+    }
+}
+
+// Define a struct to represent a model exposed by a provider.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Model {
     pub id: String,
-    pub object: String,
-    pub created: i64,
     pub owned_by: String,
 }
 
-// Define a struct to represent the list of models.
-#[derive(Serialize, Deserialize, Debug)]
-struct ModelList {
-    object: String,
-    data: Vec<Model>,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    pub role: String,
+    pub content: String,
 }
 
-pub async fn list_engines(api_key: &str) -> Result<Vec<Model>, reqwest::Error> {
-    let client = reqwest::Client::new();
-    let res: ModelList = client
-        .get("https://api.openai.com/v1/models")
-        .bearer_auth(api_key)
-        .send()
-        .await?
-        .json()
-        .await?;
-
-    Ok(res.data)
+#[derive(Debug, Clone)]
+pub enum GenerationEvent {
+    Text(String),
+    End,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    role: String,
-    content: String,
+/// A backend able to list its models and stream chat completions.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// List the models available with the provider credentials.
+    async fn list_models(&self) -> Result<Vec<Model>, ProviderError>;
+
+    /// Check the provider is reachable with valid credentials.
+    async fn verify(&self) -> Result<(), ProviderError> {
+        self.list_models().await.map(|_| ())
+    }
+
+    /// Stream a completion for the conversation into `sender`, ending with
+    /// `GenerationEvent::End` once the upstream stream is done. Cancelling
+    /// `cancel` aborts the upstream request without sending `End`.
+    async fn generate(
+        &self,
+        model: &str,
+        messages: Vec<ChatMessagePair>,
+        sender: mpsc::Sender<Result<GenerationEvent, axum::Error>>,
+        cancel: CancellationToken,
+    ) -> Result<(), ProviderError>;
 }
 
-#[derive(Debug)]
-pub enum GenerationEvent {
-    Text(String),
-    End(String),
+/// Whose API key a provider is used with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyOwner {
+    User,
+    Organization(i64),
 }
 
-pub async fn generate_sse_stream(
-    api_key: &str,
-    model: &str,
-    messages: Vec<ChatMessagePair>,
-    sender: mpsc::Sender<Result<GenerationEvent, Error>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Your OpenAI API key
-
-    // The API endpoint for chat completions
-    let url = "https://api.openai.com/v1/chat/completions";
-
-    let system_message = json!({"role": "system", "content": "You are a helpful assistant."});
-    let system_message_iter = std::iter::once(Some(system_message));
-
-    // Create an iterator over the messages
-    let messages_iter = messages.iter().flat_map(|msg| {
-        let user_message = Some(json!({
-            "role": "user",
-            "content": msg.human_message
-        }));
-
-        let ai_message = msg.ai_message.as_ref().map(|ai_msg| {
-            json!({
-                "role": "assistant",
-                "content": ai_msg
-            })
-        });
+/// The organization key is only used without a personal key, nor for OpenAI
+/// a personal endpoint: it is never sent to an endpoint the user picked.
+pub fn key_owner(user: &User, kind: ProviderKind) -> KeyOwner {
+    let (personal, organization) = match kind {
+        ProviderKind::OpenAi => (
+            user.openai_api_key
+                .as_deref()
+                .is_some_and(|k| !k.is_empty())
+                || user.openai_base_url.is_some(),
+            user.organization_openai_api_key.as_deref(),
+        ),
+        ProviderKind::Anthropic => (
+            user.anthropic_api_key
+                .as_deref()
+                .is_some_and(|k| !k.is_empty()),
+            user.organization_anthropic_api_key.as_deref(),
+        ),
+        ProviderKind::Ollama => (true, None),
+    };
 
-        std::iter::once(user_message).chain(std::iter::once(ai_message))
-    });
-
-    // Chain the system message with the user and AI messages, filter out the Nones, and collect into a Vec<Value>
-    let body_messages = system_message_iter
-        .chain(messages_iter)
-        .flatten() // This removes any None values
-        .collect::<Vec<Value>>();
-
-    // Prepare the request body
-    let body = json!({
-        "model": model,
-        // "model": "gpt-4",
-        "messages": body_messages,
-        "stream": true
-    });
-
-    println!("body: {}", body);
-
-    // Create a client
-    let client = reqwest::Client::new();
-
-    // Create a request
-    let request = client
-        .post(url)
-        .header(
-            AUTHORIZATION,
-            HeaderValue::from_str(&format!("Bearer {}", api_key))?,
-        )
-        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-        .body(body.to_string());
-
-    // Start streaming
-    let mut stream = ReqwestEventSource::new(request)?;
-
-    // Handle streaming events
-    while let Some(event) = stream.next().await {
-        match event {
-            Ok(ReqwestEvent::Open) => println!("Connection Open!"),
-            Ok(ReqwestEvent::Message(message)) => {
-                if message.data.trim() == "[DONE]" {
-                    println!("Stream completed.");
-                    stream.close();
-                    if sender
-                        // .send(Ok(Event::default()
-                        //     .data(r#"<div id="sse-listener" hx-swap-oob="true"></div>"#)))
-                        .send(Ok(GenerationEvent::End(
-                            r#"<div id="sse-listener" hx-swap-oob="true"></div>"#.to_string(),
-                        )))
-                        .await
-                        .is_err()
-                    {
-                        break; // Receiver has dropped, stop sending.
-                    }
-                    break;
-                } else {
-                    let m: Value = serde_json::from_str(&message.data).unwrap();
-                    if let Some(text) = m["choices"][0]["delta"]["content"].as_str() {
-                        // let text = text.to_string().replace(' ', "&nbsp;");
-                        // // print debug text
-                        // println!("text: {:?}", text);
-                        // println!("text: {}", text);
-
-                        // if sender.send(Ok(Event::default().data(text))).await.is_err() {
-                        if sender
-                            .send(Ok(GenerationEvent::Text(text.to_string())))
-                            .await
-                            .is_err()
-                        {
-                            break; // Receiver has dropped, stop sending.
-                        }
-                    }
-                }
-            }
-            Err(err) => {
-                println!("Error: {}", err);
-                stream.close();
-                if sender.send(Err(axum::Error::new(err))).await.is_err() {
-                    break; // Receiver has dropped, stop sending.
-                }
-            }
-            _ => (),
+    match (user.organization_id, organization) {
+        (Some(organization_id), Some(key)) if !personal && !key.is_empty() => {
+            KeyOwner::Organization(organization_id)
         }
+        _ => KeyOwner::User,
     }
+}
+
+/// Decrypt the API key for a provider, of the user or else of their
+/// organization, `None` when it isn't set or can't be decrypted. Keys are only
+/// decrypted here, to build upstream requests.
+pub fn api_key(cipher: &KeyCipher, user: &User, kind: ProviderKind) -> Option<String> {
+    let (stored, context) = match key_owner(user, kind) {
+        KeyOwner::User => (
+            match kind {
+                ProviderKind::OpenAi => user.openai_api_key.as_deref(),
+                ProviderKind::Anthropic => user.anthropic_api_key.as_deref(),
+                ProviderKind::Ollama => None,
+            },
+            kind.key_context(user.id),
+        ),
+        KeyOwner::Organization(organization_id) => (
+            match kind {
+                ProviderKind::OpenAi => user.organization_openai_api_key.as_deref(),
+                ProviderKind::Anthropic => user.organization_anthropic_api_key.as_deref(),
+                ProviderKind::Ollama => None,
+            },
+            kind.organization_key_context(organization_id),
+        ),
+    };
+    let stored = stored.filter(|stored| !stored.is_empty())?;
+
+    match cipher.decrypt(stored, &context) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            eprintln!("Error decrypting the {} API key: {}", kind.as_str(), e);
+            None
+        }
+    }
+}
+
+/// A rough token count, about four characters per token, for the quotas of
+/// the organization keys.
+pub fn estimate_tokens(text: &str) -> i64 {
+    (text.chars().count() as i64 + 3) / 4
+}
+
+/// Flatten the message pairs of a chat into a list of role/content messages,
+/// starting with the system prompt. The pairs without an answer, failed or
+/// empty, are left out but for the last one being answered: providers reject
+/// empty turns, or two user turns in a row like Anthropic.
+pub fn chat_messages(pairs: &[ChatMessagePair]) -> Vec<Message> {
+    let system_message = Message {
+        role: "system".to_string(),
+        content: SYSTEM_PROMPT.to_string(),
+    };
+
+    let last = pairs.len().saturating_sub(1);
+    let messages_iter = pairs
+        .iter()
+        .enumerate()
+        .filter(|(i, pair)| *i == last || answer(pair).is_some())
+        .flat_map(|(_, pair)| {
+            let user_message = Some(Message {
+                role: "user".to_string(),
+                content: pair.human_message.clone(),
+            });
+
+            let ai_message = answer(pair).map(|ai_msg| Message {
+                role: "assistant".to_string(),
+                content: ai_msg.to_string(),
+            });
 
-    Ok(())
+            std::iter::once(user_message).chain(std::iter::once(ai_message))
+        });
+
+    std::iter::once(system_message)
+        .chain(messages_iter.flatten())
+        .collect()
+}
+
+// The AI message of a pair, unless its generation failed or it is empty.
+fn answer(pair: &ChatMessagePair) -> Option<&str> {
+    pair.ai_message
+        .as_deref()
+        .filter(|ai_msg| pair.status != PairStatus::Failed.as_str() && !ai_msg.trim().is_empty())
 }
 
 #[cfg(test)]
 mod tests {
-    use tokio_stream::wrappers::ReceiverStream;
+    use chrono::NaiveDateTime;
 
     use super::*;
 
-    #[tokio::test]
-    async fn test_something_async() {
-        // Create a channel for sending SSE events
-        let (_sender, receiver) = mpsc::channel::<Result<GenerationEvent, axum::Error>>(10);
+    #[test]
+    fn test_chat_messages() {
+        let pairs = vec![
+            ChatMessagePair {
+                id: 1,
+                chat_id: 1,
+                message_block_id: 1,
+                provider: "openai".to_string(),
+                model: "gpt-4".to_string(),
+                human_message: "Hello".to_string(),
+                ai_message: Some("Hi there!".to_string()),
+                status: "complete".to_string(),
+                block_rank: 1,
+                block_size: 1,
+                prev_pair_id: None,
+                next_pair_id: None,
+            },
+            ChatMessagePair {
+                id: 2,
+                chat_id: 1,
+                message_block_id: 2,
+                provider: "openai".to_string(),
+                model: "gpt-4".to_string(),
+                human_message: "How are you?".to_string(),
+                ai_message: None,
+                status: "pending".to_string(),
+                block_rank: 1,
+                block_size: 1,
+                prev_pair_id: None,
+                next_pair_id: None,
+            },
+        ];
 
-        // Convert the receiver end into a Stream
-        let mut stream = ReceiverStream::new(receiver);
+        let messages = chat_messages(&pairs);
+        let roles = messages.iter().map(|m| m.role.as_str()).collect::<Vec<_>>();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+        assert_eq!(messages[0].content, SYSTEM_PROMPT);
+        assert_eq!(messages[3].content, "How are you?");
+    }
 
-        // Read api key from .env
-        let _api_key = dotenv::var("OPENAI_API_KEY").unwrap();
+    #[test]
+    fn test_chat_messages_without_answers() {
+        let pair = |id: i64, human_message: &str, ai_message: Option<&str>, status: &str| {
+            ChatMessagePair {
+                id,
+                chat_id: 1,
+                message_block_id: id,
+                provider: "anthropic".to_string(),
+                model: "claude-3-haiku-20240307".to_string(),
+                human_message: human_message.to_string(),
+                ai_message: ai_message.map(str::to_string),
+                status: status.to_string(),
+                block_rank: 1,
+                block_size: 1,
+                prev_pair_id: None,
+                next_pair_id: None,
+            }
+        };
+        let pairs = vec![
+            pair(1, "Hello", Some(""), "failed"),
+            pair(2, "Hello?", Some("Hi there!"), "complete"),
+            pair(3, "Tell me a story", Some("Once upon"), "failed"),
+            pair(4, "Anyone?", Some(" "), "complete"),
+            pair(5, "How are you?", None, "pending"),
+        ];
 
-        let _pairs = vec![ChatMessagePair {
-            id: 1,
-            chat_id: 1,
-            message_block_id: 1,
-            model: "gpt-4".to_string(),
-            human_message: "Hello".to_string(),
-            ai_message: Some("Hi there!".to_string()),
-            block_rank: 1,
-            block_size: 1,
-        }];
-
-        tokio::spawn(async move {
-            generate_sse_stream(&_api_key, "gpt-4", _pairs, _sender)
-                .await
-                .unwrap();
-        });
+        // The user and assistant turns alternate, none of them is empty
+        let messages = chat_messages(&pairs);
+        let roles = messages.iter().map(|m| m.role.as_str()).collect::<Vec<_>>();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+        assert_eq!(messages[1].content, "Hello?");
+        assert_eq!(messages[3].content, "How are you?");
+    }
 
-        while let Some(event) = stream.next().await {
-            match event {
-                Ok(sse_event) => {
-                    println!("Received event: {:?}", sse_event)
-                }
-                Err(_e) => {}
-            }
+    fn user() -> User {
+        User {
+            id: 1,
+            email: "test@test.com".to_string(),
+            password: String::new(),
+            created_at: NaiveDateTime::default(),
+            email_verified_at: None,
+            role: "user".to_string(),
+            disabled_at: None,
+            openai_api_key: None,
+            anthropic_api_key: None,
+            openai_api_key_hint: None,
+            anthropic_api_key_hint: None,
+            openai_base_url: None,
+            openai_api_version: None,
+            openai_extra_headers: None,
+            openai_extra_header_names: None,
+            organization_id: Some(7),
+            organization_openai_api_key: Some("encrypted".to_string()),
+            organization_anthropic_api_key: None,
         }
     }
+
+    #[test]
+    fn test_key_owner() {
+        let mut user = user();
+        assert_eq!(
+            key_owner(&user, ProviderKind::OpenAi),
+            KeyOwner::Organization(7)
+        );
+        assert_eq!(key_owner(&user, ProviderKind::Anthropic), KeyOwner::User);
+        assert_eq!(key_owner(&user, ProviderKind::Ollama), KeyOwner::User);
+
+        // The personal key, or endpoint, wins
+        user.openai_api_key = Some(String::new());
+        assert_eq!(
+            key_owner(&user, ProviderKind::OpenAi),
+            KeyOwner::Organization(7)
+        );
+        user.openai_base_url = Some("http://localhost:8000/v1".to_string());
+        assert_eq!(key_owner(&user, ProviderKind::OpenAi), KeyOwner::User);
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("Hi"), 1);
+        assert_eq!(estimate_tokens("Hello world!"), 3);
+        assert_eq!(estimate_tokens("héllo"), 2);
+    }
 }
@@ -1,4 +1,4 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
@@ -6,7 +6,7 @@ use sqlx::FromRow;
 pub struct User {
     pub id: i64,
     pub email: String,
-    pub password: String, // Note: Storing plain-text passwords is not recommended. Use hashed passwords instead.
+    pub password: String, // argon2 PHC string
     pub created_at: DateTime<Utc>,
 }
 
@@ -15,16 +15,240 @@ pub struct Chat {
     pub id: i64,
     pub name: String,
     pub user_id: i64,
+    pub provider: String,
+    pub model: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
 pub struct ChatMessagePair {
     pub id: i64,
+    pub provider: String,
     pub model: String,
     pub message_block_id: i64,
     pub chat_id: i64,
     pub human_message: String,
     pub ai_message: Option<String>,
+    pub status: String,
     pub block_rank: i64,
     pub block_size: i64,
+    pub prev_pair_id: Option<i64>,
+    pub next_pair_id: Option<i64>,
+}
+
+/// A session of the user, as listed in the settings.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct Session {
+    pub id: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub current: bool,
+}
+
+/// What a user can do, stored in `users.role`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// A user as listed in the admin console, with their usage.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct UserSummary {
+    pub id: i64,
+    pub email: String,
+    pub role: String,
+    pub created_at: NaiveDateTime,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub disabled_at: Option<NaiveDateTime>,
+    pub chat_count: i64,
+    pub message_count: i64,
+    pub last_seen_at: Option<NaiveDateTime>,
+}
+
+/// An API token of the user, as listed in the settings.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scopes: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub expired: bool,
+}
+
+/// An organization, as listed in the admin console.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct Organization {
+    pub id: i64,
+    pub name: String,
+    pub openai_api_key_hint: Option<String>,
+    pub anthropic_api_key_hint: Option<String>,
+    pub member_count: i64,
+    pub created_at: NaiveDateTime,
+}
+
+/// A member of an organization with their quotas, `None` is unlimited, and
+/// what they used of the organization keys this month.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct OrganizationMember {
+    pub user_id: i64,
+    pub email: String,
+    pub organization_id: i64,
+    pub organization_name: String,
+    pub monthly_request_quota: Option<i64>,
+    pub monthly_token_quota: Option<i64>,
+    pub requests: i64,
+    pub tokens: i64,
+}
+
+impl OrganizationMember {
+    /// Whether a quota is used up, which stops new generations with the
+    /// organization keys until the next month.
+    pub fn quota_exceeded(&self) -> bool {
+        self.monthly_request_quota
+            .is_some_and(|quota| self.requests >= quota)
+            || self
+                .monthly_token_quota
+                .is_some_and(|quota| self.tokens >= quota)
+    }
+}
+
+/// An invite code, as listed in the admin console.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct InviteCode {
+    pub id: i64,
+    pub created_by_email: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub used_by_email: Option<String>,
+    pub used_at: Option<NaiveDateTime>,
+    pub expired: bool,
+}
+
+/// Everything stored about a user, as they download it from the settings.
+/// Secrets are left out: the password hash, the encrypted API keys and extra
+/// headers, whose hints and names are kept.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccountExport {
+    pub exported_at: NaiveDateTime,
+    pub user: ExportedUser,
+    pub settings: Option<ExportedSettings>,
+    pub chats: Vec<ExportedChat>,
+    pub message_blocks: Vec<ExportedMessageBlock>,
+    pub message_pairs: Vec<ExportedMessagePair>,
+    pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ExportedUser {
+    pub id: i64,
+    pub email: String,
+    pub role: String,
+    pub created_at: NaiveDateTime,
+    pub email_verified_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ExportedSettings {
+    pub openai_api_key_hint: Option<String>,
+    pub anthropic_api_key_hint: Option<String>,
+    pub openai_base_url: Option<String>,
+    pub openai_api_version: Option<String>,
+    pub openai_extra_header_names: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ExportedChat {
+    pub id: i64,
+    pub name: String,
+    pub provider: String,
+    pub model: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ExportedMessageBlock {
+    pub id: i64,
+    pub chat_id: i64,
+    pub parent_pair_id: Option<i64>,
+    pub selected_pair_id: Option<i64>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ExportedMessagePair {
+    pub id: i64,
+    pub message_block_id: i64,
+    pub human_message_id: i64,
+    pub ai_message_id: Option<i64>,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ExportedMessage {
+    pub id: i64,
+    pub message: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Failed logins for a throttling key, see `security::throttle`.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct LoginAttempts {
+    pub key: String,
+    pub failures: i64,
+    pub last_failure_at: NaiveDateTime,
+}
+
+/// The TOTP enrolment of a user, the secret is encrypted.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct UserTotp {
+    pub secret: String,
+    pub enabled: bool,
+    pub last_used_step: Option<i64>,
+}
+
+/// Where the generation of the AI message of a pair is, stored in
+/// `message_pairs.status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairStatus {
+    Pending,
+    Streaming,
+    Complete,
+    Failed,
+}
+
+impl PairStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PairStatus::Pending => "pending",
+            PairStatus::Streaming => "streaming",
+            PairStatus::Complete => "complete",
+            PairStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ModelMetadata {
+    pub model_id: String,
+    pub display_name: String,
+    pub description: String,
+    pub context_window: Option<i64>,
+    pub input_price: Option<f64>,
+    pub output_price: Option<f64>,
 }
@@ -1,9 +1,27 @@
 use std::sync::Arc;
 
+use chrono::NaiveDateTime;
+
 use sqlx::sqlite::SqlitePool;
-use sqlx::{Row, Sqlite, Transaction};
+use sqlx::{Sqlite, Transaction};
 
-use super::model::{Chat, ChatMessagePair};
+use super::model::{
+    AccountExport, ApiToken, Chat, ChatMessagePair, ExportedChat, ExportedMessage,
+    ExportedMessageBlock, ExportedMessagePair, ExportedSettings, ExportedUser, InviteCode,
+    LoginAttempts, Organization, OrganizationMember, PairStatus, Role, Session, UserSummary,
+    UserTotp,
+};
+use crate::{
+    ai::{
+        openai::{extra_headers_context, header_names},
+        stream::ProviderKind,
+    },
+    security::{
+        api_token::{parse_scopes, ApiTokenAuth},
+        secret::{is_encrypted, key_hint, KeyCipher},
+    },
+    User,
+};
 
 #[derive(Clone)]
 pub struct ChatRepository {
@@ -14,87 +32,356 @@ impl ChatRepository {
     pub async fn get_all_chats(&self, user_id: i64) -> sqlx::Result<Vec<Chat>> {
         sqlx::query_as!(
             Chat,
-            "SELECT id, user_id, name FROM chats WHERE user_id = ? ORDER BY created_at DESC",
+            "SELECT id, user_id, name, provider, model FROM chats WHERE user_id = ? ORDER BY created_at DESC",
             user_id
         )
         .fetch_all(&*self.pool)
         .await
     }
 
-    pub async fn delete_chat(&self, chat_id: i64) -> sqlx::Result<u64> {
-        let rows_affected = sqlx::query!("DELETE FROM chats WHERE id = ?", chat_id)
-            .execute(&*self.pool)
-            .await
-            .unwrap()
-            .rows_affected();
+    pub async fn get_chat(&self, user_id: i64, chat_id: i64) -> sqlx::Result<Chat> {
+        sqlx::query_as!(
+            Chat,
+            "SELECT id, user_id, name, provider, model FROM chats WHERE id = ? AND user_id = ?",
+            chat_id,
+            user_id
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    /// Delete a chat with its messages, which aren't removed by the cascade.
+    pub async fn delete_chat(&self, user_id: i64, chat_id: i64) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        sqlx::query!(
+            r#"
+            DELETE FROM messages
+            WHERE id IN (
+              SELECT message_pairs.human_message_id FROM message_pairs
+              JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+              JOIN chats ON chats.id = message_blocks.chat_id
+              WHERE chats.id = ? AND chats.user_id = ?
+              UNION
+              SELECT message_pairs.ai_message_id FROM message_pairs
+              JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+              JOIN chats ON chats.id = message_blocks.chat_id
+              WHERE chats.id = ? AND chats.user_id = ?
+            );
+            "#,
+            chat_id,
+            user_id,
+            chat_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let rows_affected = sqlx::query!(
+            "DELETE FROM chats WHERE id = ? AND user_id = ?",
+            chat_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
         Ok(rows_affected)
     }
 
-    pub async fn retrieve_chat(&self, chat_id: i64) -> sqlx::Result<Vec<ChatMessagePair>> {
+    pub async fn retrieve_chat(
+        &self,
+        user_id: i64,
+        chat_id: i64,
+    ) -> sqlx::Result<Vec<ChatMessagePair>> {
         sqlx::query_as!(
             ChatMessagePair,
-            "SELECT * FROM v_chat_messages WHERE chat_id = ?",
-            chat_id
+            // the nullability and types of the view columns can't be inferred
+            r#"
+            SELECT id AS "id!", message_block_id AS "message_block_id!", chat_id AS "chat_id!",
+              provider AS "provider!", model AS "model!", human_message AS "human_message!",
+              ai_message AS "ai_message?", status AS "status!",
+              block_rank AS "block_rank!: i64", block_size AS "block_size!: i64",
+              prev_pair_id AS "prev_pair_id?: i64", next_pair_id AS "next_pair_id?: i64"
+            FROM v_chat_messages
+            WHERE chat_id = (SELECT id FROM chats WHERE id = ? AND user_id = ?)
+            ORDER BY depth;
+            "#,
+            chat_id,
+            user_id
         )
         .fetch_all(&*self.pool)
         .await
     }
-    pub async fn create_chat(&self, user_id: i64, name: &str, model: &str) -> sqlx::Result<i64> {
+    pub async fn create_chat(
+        &self,
+        user_id: i64,
+        name: &str,
+        provider: &str,
+        model: &str,
+    ) -> sqlx::Result<i64> {
         //create chat
-        let chat = sqlx::query!(
+        // `execute` runs the insert to completion, so the chat is committed
+        // before other connections of the pool look it up.
+        let chat_id = sqlx::query!(
             r#"
-            INSERT INTO chats (user_id, name, model)
-            VALUES (?, ?, ?) RETURNING id;
+            INSERT INTO chats (user_id, name, provider, model)
+            VALUES (?, ?, ?, ?);
             "#,
             user_id,
             name,
+            provider,
             model
         )
-        .fetch_one(&*self.pool)
+        .execute(&*self.pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(chat_id)
+    }
+    /// Save the AI message of a pair along with the status of its generation,
+    /// the message is updated in place while it streams. The pair comes from
+    /// `retrieve_chat`, which checked the chat owner.
+    pub async fn save_ai_message(
+        &self,
+        pair_id: i64,
+        message: &str,
+        status: PairStatus,
+    ) -> sqlx::Result<i64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let pair = sqlx::query!(
+            "SELECT ai_message_id FROM message_pairs WHERE id = ?",
+            pair_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let message_id = match pair.ai_message_id {
+            Some(message_id) => {
+                sqlx::query!(
+                    "UPDATE messages SET message = ? WHERE id = ?",
+                    message,
+                    message_id
+                )
+                .execute(&mut *tx)
+                .await?;
+                message_id
+            }
+            None => {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO messages (message)
+                    VALUES (?) RETURNING id;
+                    "#,
+                    message
+                )
+                .fetch_one(&mut *tx)
+                .await?
+                .id
+            }
+        };
+
+        let status = status.as_str();
+        sqlx::query!(
+            r#"
+            UPDATE message_pairs
+            SET ai_message_id = ?, status = ?
+            WHERE id = ?;
+            "#,
+            message_id,
+            status,
+            pair_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(message_id)
+    }
+
+    /// Mark a pair left streaming by a generation that is gone (server
+    /// restart, crash) as failed. Returns whether the pair was streaming.
+    pub async fn fail_streaming_pair(&self, pair_id: i64) -> sqlx::Result<bool> {
+        let rows_affected = sqlx::query!(
+            "UPDATE message_pairs SET status = 'failed' WHERE id = ? AND status = 'streaming'",
+            pair_id
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+
+        Ok(rows_affected > 0)
+    }
+
+    /// Add a new pair with the same human message to the block of `pair_id`
+    /// and select it, the AI message is left to generate.
+    pub async fn add_alternative_pair(
+        &self,
+        user_id: i64,
+        chat_id: i64,
+        pair_id: i64,
+    ) -> sqlx::Result<i64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let pair = sqlx::query!(
+            r#"
+            SELECT message_pairs.human_message_id, message_pairs.message_block_id
+            FROM message_pairs
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE message_pairs.id = ? AND message_blocks.chat_id = ? AND chats.user_id = ?;
+            "#,
+            pair_id,
+            chat_id,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let message_pair = sqlx::query!(
+            r#"
+            INSERT INTO message_pairs (human_message_id, message_block_id)
+            VALUES (?, ?) RETURNING id;
+            "#,
+            pair.human_message_id,
+            pair.message_block_id,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE message_blocks
+            SET selected_pair_id = ?, updated_at = CURRENT_TIMESTAMP
+            WHERE id = ?;
+            "#,
+            message_pair.id,
+            pair.message_block_id
+        )
+        .execute(&mut *tx)
         .await?;
 
-        Ok(chat.id)
+        tx.commit().await?;
+
+        Ok(message_pair.id)
     }
-    pub async fn add_ai_message_to_pair(&self, pair_id: i64, message: &str) -> sqlx::Result<i64> {
+
+    /// Add a new pair with an edited human message to the block of `pair_id`
+    /// and select it. The blocks following `pair_id` stay on its branch.
+    pub async fn edit_pair(
+        &self,
+        user_id: i64,
+        chat_id: i64,
+        pair_id: i64,
+        human_message: &str,
+    ) -> sqlx::Result<i64> {
         let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
 
+        let pair = sqlx::query!(
+            r#"
+            SELECT message_pairs.message_block_id
+            FROM message_pairs
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE message_pairs.id = ? AND message_blocks.chat_id = ? AND chats.user_id = ?;
+            "#,
+            pair_id,
+            chat_id,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
         let message = sqlx::query!(
             r#"
             INSERT INTO messages (message)
             VALUES (?) RETURNING id;
             "#,
-            message
+            human_message
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let message_pair = sqlx::query!(
+            r#"
+            INSERT INTO message_pairs (human_message_id, message_block_id)
+            VALUES (?, ?) RETURNING id;
+            "#,
+            message.id,
+            pair.message_block_id,
         )
         .fetch_one(&mut *tx)
         .await?;
 
         sqlx::query!(
             r#"
-            UPDATE message_pairs
-            SET ai_message_id = ?
+            UPDATE message_blocks
+            SET selected_pair_id = ?, updated_at = CURRENT_TIMESTAMP
             WHERE id = ?;
             "#,
-            message.id,
-            pair_id
+            message_pair.id,
+            pair.message_block_id
         )
         .execute(&mut *tx)
         .await?;
 
         tx.commit().await?;
 
-        Ok(message.id)
+        Ok(message_pair.id)
+    }
+
+    /// Make `pair_id` the selected pair of its block.
+    pub async fn select_pair(&self, user_id: i64, chat_id: i64, pair_id: i64) -> sqlx::Result<()> {
+        let rows_affected = sqlx::query!(
+            r#"
+            UPDATE message_blocks
+            SET selected_pair_id = ?, updated_at = CURRENT_TIMESTAMP
+            WHERE chat_id = (SELECT id FROM chats WHERE id = ? AND user_id = ?)
+              AND id = (SELECT message_block_id FROM message_pairs WHERE id = ?);
+            "#,
+            pair_id,
+            chat_id,
+            user_id,
+            pair_id
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+
+        if rows_affected == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        Ok(())
     }
 
-    pub async fn add_message_block(&self, chat_id: i64, human_message: &str) -> sqlx::Result<i64> {
+    pub async fn add_message_block(
+        &self,
+        user_id: i64,
+        chat_id: i64,
+        human_message: &str,
+    ) -> sqlx::Result<i64> {
         //create chat
         let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
 
+        // The chat must belong to the user
+        sqlx::query!(
+            "SELECT id FROM chats WHERE id = ? AND user_id = ?",
+            chat_id,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // The new block follows the last pair of the displayed conversation
         let message_block = sqlx::query!(
             r#"
-            INSERT INTO message_blocks (chat_id)
-            VALUES (?) RETURNING id;
+            INSERT INTO message_blocks (chat_id, parent_pair_id)
+            VALUES (?, (SELECT id FROM v_chat_messages WHERE chat_id = ? ORDER BY depth DESC LIMIT 1))
+            RETURNING id;
             "#,
             chat_id,
+            chat_id,
         )
         .fetch_one(&mut *tx)
         .await?;
@@ -138,70 +425,2152 @@ impl ChatRepository {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::path::Path;
+#[derive(Clone)]
+pub struct SessionRepository {
+    pub pool: Arc<SqlitePool>,
+}
 
-    use sqlx::migrate::Migrator;
+impl SessionRepository {
+    /// Record a session for the user, lasting `days`.
+    pub async fn create_session(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+        days: i64,
+    ) -> sqlx::Result<i64> {
+        let lifetime = format!("{:+} days", days);
+        let session_id = sqlx::query!(
+            r#"
+            INSERT INTO sessions (user_id, token_hash, user_agent, ip, expires_at)
+            VALUES (?, ?, ?, ?, datetime('now', ?));
+            "#,
+            user_id,
+            token_hash,
+            user_agent,
+            ip,
+            lifetime
+        )
+        .execute(&*self.pool)
+        .await?
+        .last_insert_rowid();
 
-    use super::*;
+        Ok(session_id)
+    }
 
-    async fn setup() -> (Arc<SqlitePool>, ChatRepository, i64) {
-        let x = std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:db.db".to_string());
-        let pool = SqlitePool::connect(&x).await.unwrap();
+    /// The user of a session which hasn't expired, unless disabled.
+    pub async fn get_user(&self, token_hash: &str) -> sqlx::Result<Option<User>> {
+        sqlx::query_as!(
+            User,
+            r#"
+            SELECT users.*, settings.openai_api_key, settings.anthropic_api_key, settings.openai_api_key_hint, settings.anthropic_api_key_hint, settings.openai_base_url, settings.openai_api_version, settings.openai_extra_headers, settings.openai_extra_header_names, organization_members.organization_id AS "organization_id?", organizations.openai_api_key AS organization_openai_api_key, organizations.anthropic_api_key AS organization_anthropic_api_key
+            FROM sessions
+            JOIN users ON users.id = sessions.user_id
+            LEFT JOIN settings ON settings.user_id = users.id
+            LEFT JOIN organization_members ON organization_members.user_id = users.id
+            LEFT JOIN organizations ON organizations.id = organization_members.organization_id
+            WHERE sessions.token_hash = ? AND sessions.expires_at > CURRENT_TIMESTAMP AND users.disabled_at IS NULL;
+            "#,
+            token_hash
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
 
-        // let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
-        let pool = Arc::new(pool);
+    /// Update when the session was last used, at most once a minute.
+    pub async fn touch_session(&self, token_hash: &str) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET last_seen_at = CURRENT_TIMESTAMP
+            WHERE token_hash = ? AND last_seen_at < datetime('now', '-1 minute');
+            "#,
+            token_hash
+        )
+        .execute(&*self.pool)
+        .await?;
 
-        let migrator = Migrator::new(Path::new(dotenv::var("MIGRATIONS_PATH").unwrap().as_str()))
-            .await
-            .unwrap();
-        // Run the migrations.
-        migrator.run(&*pool).await.unwrap();
+        Ok(())
+    }
 
-        let user = sqlx::query!(
-            "INSERT INTO users (email, password) VALUES ($1, $2) RETURNING id",
-            "test@test.com",
-            "test"
+    pub async fn revoke_session(&self, token_hash: &str) -> sqlx::Result<u64> {
+        let rows_affected = sqlx::query!("DELETE FROM sessions WHERE token_hash = ?", token_hash)
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        Ok(rows_affected)
+    }
+
+    /// The sessions of the user which haven't expired, most recently used
+    /// first, flagging the one of `current_token_hash`.
+    pub async fn get_user_sessions(
+        &self,
+        user_id: i64,
+        current_token_hash: &str,
+    ) -> sqlx::Result<Vec<Session>> {
+        sqlx::query_as!(
+            Session,
+            r#"
+            SELECT id AS "id!", user_agent, ip, created_at, last_seen_at, token_hash = ? AS "current!: bool"
+            FROM sessions
+            WHERE user_id = ? AND expires_at > CURRENT_TIMESTAMP
+            ORDER BY last_seen_at DESC, id DESC;
+            "#,
+            current_token_hash,
+            user_id
         )
-        .fetch_one(&*pool)
+        .fetch_all(&*self.pool)
         .await
-        .unwrap();
+    }
 
-        let repo = ChatRepository { pool: pool.clone() };
+    /// Revoke a session of the user, other than the current one which is
+    /// ended by logging out.
+    pub async fn revoke_user_session(
+        &self,
+        user_id: i64,
+        session_id: i64,
+        current_token_hash: &str,
+    ) -> sqlx::Result<u64> {
+        let rows_affected = sqlx::query!(
+            "DELETE FROM sessions WHERE id = ? AND user_id = ? AND token_hash != ?",
+            session_id,
+            user_id,
+            current_token_hash
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
+    }
 
-        (pool, repo, user.id)
+    /// Revoke all the sessions of the user but the current one.
+    pub async fn revoke_other_sessions(
+        &self,
+        user_id: i64,
+        current_token_hash: &str,
+    ) -> sqlx::Result<u64> {
+        let rows_affected = sqlx::query!(
+            "DELETE FROM sessions WHERE user_id = ? AND token_hash != ?",
+            user_id,
+            current_token_hash
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
     }
 
-    #[tokio::test]
-    async fn test_create_chat() {
-        let (pool, repo, user_id) = setup().await;
-        let chat = repo.create_chat(user_id, "test", "gpt-4").await;
-        assert!(chat.is_ok(), "Failed to create chat");
+    /// Revoke all the sessions of the user, after a password reset.
+    pub async fn revoke_all_sessions(&self, user_id: i64) -> sqlx::Result<u64> {
+        let rows_affected = sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user_id)
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        Ok(rows_affected)
     }
 
-    #[tokio::test]
-    async fn test_add_message_block() {
-        let (pool, repo, user_id) = setup().await;
-        let chat = repo.create_chat(user_id, "test", "gpt-4").await;
-        assert!(chat.is_ok(), "Failed to create chat");
-        let chat_id = chat.unwrap();
+    pub async fn delete_expired_sessions(&self) -> sqlx::Result<u64> {
+        let rows_affected =
+            sqlx::query!("DELETE FROM sessions WHERE expires_at <= CURRENT_TIMESTAMP")
+                .execute(&*self.pool)
+                .await?
+                .rows_affected();
+        Ok(rows_affected)
+    }
+}
 
-        let message_block = repo.add_message_block(chat_id, "Test").await;
-        assert!(message_block.is_ok(), "Failed to add message_block")
+#[derive(Clone)]
+pub struct SettingsRepository {
+    pub pool: Arc<SqlitePool>,
+}
+
+impl SettingsRepository {
+    /// Encrypt the API keys and extra headers stored in plain text before
+    /// encryption, filling their hints. Run at startup, returns the number of
+    /// secrets encrypted.
+    pub async fn encrypt_plaintext_keys(&self, cipher: &KeyCipher) -> sqlx::Result<u64> {
+        let rows = sqlx::query!(
+            "SELECT user_id, openai_api_key, anthropic_api_key, openai_extra_headers FROM settings"
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let mut encrypted = 0;
+        for row in rows {
+            let openai_api_key = row.openai_api_key;
+            if !openai_api_key.is_empty() && !is_encrypted(&openai_api_key) {
+                let stored = cipher.encrypt(
+                    &openai_api_key,
+                    &ProviderKind::OpenAi.key_context(row.user_id),
+                );
+                let hint = key_hint(&openai_api_key);
+                sqlx::query!(
+                    "UPDATE settings SET openai_api_key = ?, openai_api_key_hint = ? WHERE user_id = ?",
+                    stored,
+                    hint,
+                    row.user_id
+                )
+                .execute(&*self.pool)
+                .await?;
+                encrypted += 1;
+            }
+
+            if let Some(anthropic_api_key) = row
+                .anthropic_api_key
+                .filter(|key| !key.is_empty() && !is_encrypted(key))
+            {
+                let stored = cipher.encrypt(
+                    &anthropic_api_key,
+                    &ProviderKind::Anthropic.key_context(row.user_id),
+                );
+                let hint = key_hint(&anthropic_api_key);
+                sqlx::query!(
+                    "UPDATE settings SET anthropic_api_key = ?, anthropic_api_key_hint = ? WHERE user_id = ?",
+                    stored,
+                    hint,
+                    row.user_id
+                )
+                .execute(&*self.pool)
+                .await?;
+                encrypted += 1;
+            }
+
+            if let Some(extra_headers) = row
+                .openai_extra_headers
+                .filter(|headers| !headers.is_empty() && !is_encrypted(headers))
+            {
+                let stored = cipher.encrypt(&extra_headers, &extra_headers_context(row.user_id));
+                let names = header_names(&extra_headers);
+                sqlx::query!(
+                    "UPDATE settings SET openai_extra_headers = ?, openai_extra_header_names = ? WHERE user_id = ?",
+                    stored,
+                    names,
+                    row.user_id
+                )
+                .execute(&*self.pool)
+                .await?;
+                encrypted += 1;
+            }
+        }
+
+        Ok(encrypted)
     }
+}
 
-    #[tokio::test]
-    async fn test_json() {
-        let (pool, repo, user_id) = setup().await;
-        let chat = repo.create_chat(user_id, "test", "gpt-4").await;
-        assert!(chat.is_ok(), "Failed to create chat");
-        let chat_id = chat.unwrap();
+#[derive(Clone)]
+pub struct LoginAttemptRepository {
+    pub pool: Arc<SqlitePool>,
+}
 
-        let message_block = repo.add_message_block(chat_id, "Test").await;
-        assert!(message_block.is_ok(), "Failed to add message_block");
+impl LoginAttemptRepository {
+    /// The keys with a failure since `since`.
+    pub async fn get_recent(&self, since: NaiveDateTime) -> sqlx::Result<Vec<LoginAttempts>> {
+        sqlx::query_as!(
+            LoginAttempts,
+            "SELECT key, failures, last_failure_at FROM login_attempts WHERE last_failure_at > ?",
+            since
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
 
-        let chat_message_pairs = repo.retrieve_chat(chat_id).await;
-        print!("{:#?}", chat_message_pairs)
+    pub async fn save(
+        &self,
+        key: &str,
+        failures: i64,
+        last_failure_at: NaiveDateTime,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO login_attempts (key, failures, last_failure_at) VALUES (?, ?, ?) ON CONFLICT (key) DO UPDATE SET failures = excluded.failures, last_failure_at = excluded.last_failure_at",
+            key,
+            failures,
+            last_failure_at
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, key: &str) -> sqlx::Result<()> {
+        sqlx::query!("DELETE FROM login_attempts WHERE key = ?", key)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_older_than(&self, before: NaiveDateTime) -> sqlx::Result<u64> {
+        let rows_affected = sqlx::query!(
+            "DELETE FROM login_attempts WHERE last_failure_at <= ?",
+            before
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
+    }
+}
+
+#[derive(Clone)]
+pub struct TwoFactorRepository {
+    pub pool: Arc<SqlitePool>,
+}
+
+impl TwoFactorRepository {
+    pub async fn get_totp(&self, user_id: i64) -> sqlx::Result<Option<UserTotp>> {
+        sqlx::query_as!(
+            UserTotp,
+            "SELECT secret, enabled, last_used_step FROM user_totp WHERE user_id = ?",
+            user_id
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    pub async fn is_enabled(&self, user_id: i64) -> sqlx::Result<bool> {
+        Ok(self
+            .get_totp(user_id)
+            .await?
+            .is_some_and(|totp| totp.enabled))
+    }
+
+    /// Store a new secret waiting for a first code, replacing a previous
+    /// enrolment which wasn't finished.
+    pub async fn start_enrolment(&self, user_id: i64, secret: &str) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO user_totp (user_id, secret) VALUES (?, ?) ON CONFLICT (user_id) DO UPDATE SET secret = excluded.secret, last_used_step = NULL WHERE enabled = FALSE",
+            user_id,
+            secret
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Enable the enrolment once its first code is checked, with new recovery
+    /// codes.
+    pub async fn enable(
+        &self,
+        user_id: i64,
+        step: i64,
+        recovery_code_hashes: &[String],
+    ) -> sqlx::Result<()> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        sqlx::query!(
+            "UPDATE user_totp SET enabled = TRUE, last_used_step = ? WHERE user_id = ?",
+            step,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        for code_hash in recovery_code_hashes {
+            sqlx::query!(
+                "INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?)",
+                user_id,
+                code_hash
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Record the time step of a used code, `false` when a code of this step
+    /// or a later one was already used.
+    pub async fn use_step(&self, user_id: i64, step: i64) -> sqlx::Result<bool> {
+        let rows_affected = sqlx::query!(
+            "UPDATE user_totp SET last_used_step = ? WHERE user_id = ? AND (last_used_step IS NULL OR last_used_step < ?)",
+            step,
+            user_id,
+            step
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected == 1)
+    }
+
+    /// Use a recovery code, `false` when it doesn't exist or was used.
+    pub async fn use_recovery_code(&self, user_id: i64, code_hash: &str) -> sqlx::Result<bool> {
+        let rows_affected = sqlx::query!(
+            "UPDATE recovery_codes SET used_at = CURRENT_TIMESTAMP WHERE user_id = ? AND code_hash = ? AND used_at IS NULL",
+            user_id,
+            code_hash
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected == 1)
+    }
+
+    pub async fn remaining_recovery_codes(&self, user_id: i64) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM recovery_codes WHERE user_id = ? AND used_at IS NULL",
+            user_id
+        )
+        .fetch_one(&*self.pool)
+        .await
+        .map(i64::from)
+    }
+
+    pub async fn disable(&self, user_id: i64) -> sqlx::Result<()> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        sqlx::query!("DELETE FROM user_totp WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct IdentityRepository {
+    pub pool: Arc<SqlitePool>,
+}
+
+impl IdentityRepository {
+    /// The user an identity of the provider is linked to.
+    pub async fn find_user(&self, issuer: &str, subject: &str) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar!(
+            "SELECT user_id FROM user_identities WHERE issuer = ? AND subject = ?",
+            issuer,
+            subject
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    /// Whether the user has an identity of the provider linked.
+    pub async fn has_identity(&self, user_id: i64) -> sqlx::Result<bool> {
+        let identities = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM user_identities WHERE user_id = ?",
+            user_id
+        )
+        .fetch_one(&*self.pool)
+        .await?;
+        Ok(identities > 0)
+    }
+
+    /// The user with an email, to link a new identity to.
+    pub async fn find_user_by_email(&self, email: &str) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar!(r#"SELECT id AS "id!" FROM users WHERE email = ?"#, email)
+            .fetch_optional(&*self.pool)
+            .await
+    }
+
+    /// Create a user for an identity, its email is verified by the provider.
+    /// `password` is a hash nobody knows the password of, until it is reset.
+    /// With an invite code, which is claimed like `InviteRepository` does,
+    /// `None` when the code can't be used.
+    pub async fn create_user(
+        &self,
+        email: &str,
+        password: &str,
+        issuer: &str,
+        subject: &str,
+        invite_hash: Option<&str>,
+    ) -> sqlx::Result<Option<i64>> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        if let Some(code_hash) = invite_hash {
+            if !claim_invite(&mut tx, code_hash).await? {
+                return Ok(None);
+            }
+        }
+        let user_id = sqlx::query!(
+            "INSERT INTO users (email, password, email_verified_at) VALUES (?, ?, CURRENT_TIMESTAMP)",
+            email,
+            password
+        )
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        sqlx::query!(
+            "INSERT INTO user_identities (user_id, issuer, subject) VALUES (?, ?, ?)",
+            user_id,
+            issuer,
+            subject
+        )
+        .execute(&mut *tx)
+        .await?;
+        if let Some(code_hash) = invite_hash {
+            set_invite_user(&mut tx, code_hash, user_id).await?;
+        }
+        tx.commit().await?;
+        Ok(Some(user_id))
+    }
+
+    /// Link an identity to an existing user, only if the user verified its
+    /// email: anyone can sign up with an email they don't own. `false` when
+    /// the user isn't verified.
+    pub async fn link(&self, user_id: i64, issuer: &str, subject: &str) -> sqlx::Result<bool> {
+        let linked = sqlx::query!(
+            "INSERT INTO user_identities (user_id, issuer, subject) SELECT id, ?, ? FROM users WHERE id = ? AND email_verified_at IS NOT NULL",
+            issuer,
+            subject,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(linked == 1)
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiTokenRepository {
+    pub pool: Arc<SqlitePool>,
+}
+
+impl ApiTokenRepository {
+    /// Record a token for the user, expiring after `days` unless `None`.
+    pub async fn create_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        token_hash: &str,
+        scopes: &str,
+        days: Option<i64>,
+    ) -> sqlx::Result<i64> {
+        let lifetime = days.map(|days| format!("{:+} days", days));
+        let token_id = sqlx::query!(
+            r#"
+            INSERT INTO api_tokens (user_id, name, token_hash, scopes, expires_at)
+            VALUES (?, ?, ?, ?, datetime('now', ?));
+            "#,
+            user_id,
+            name,
+            token_hash,
+            scopes,
+            lifetime
+        )
+        .execute(&*self.pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(token_id)
+    }
+
+    /// The user and scopes of a token which hasn't expired, unless the user
+    /// is disabled, recording its use at most once a minute.
+    pub async fn authenticate(
+        &self,
+        token_hash: &str,
+    ) -> sqlx::Result<Option<(User, ApiTokenAuth)>> {
+        let Some(token) = sqlx::query!(
+            r#"
+            SELECT id AS "id!", user_id, scopes FROM api_tokens
+            WHERE token_hash = ? AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP);
+            "#,
+            token_hash
+        )
+        .fetch_optional(&*self.pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let Some(user) = sqlx::query_as!(
+            User,
+            r#"
+            SELECT users.*, settings.openai_api_key, settings.anthropic_api_key, settings.openai_api_key_hint, settings.anthropic_api_key_hint, settings.openai_base_url, settings.openai_api_version, settings.openai_extra_headers, settings.openai_extra_header_names, organization_members.organization_id AS "organization_id?", organizations.openai_api_key AS organization_openai_api_key, organizations.anthropic_api_key AS organization_anthropic_api_key
+            FROM users
+            LEFT JOIN settings ON settings.user_id = users.id
+            LEFT JOIN organization_members ON organization_members.user_id = users.id
+            LEFT JOIN organizations ON organizations.id = organization_members.organization_id
+            WHERE users.id = ? AND users.disabled_at IS NULL;
+            "#,
+            token.user_id
+        )
+        .fetch_optional(&*self.pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        sqlx::query!(
+            r#"
+            UPDATE api_tokens
+            SET last_used_at = CURRENT_TIMESTAMP
+            WHERE id = ? AND (last_used_at IS NULL OR last_used_at < datetime('now', '-1 minute'));
+            "#,
+            token.id
+        )
+        .execute(&*self.pool)
+        .await?;
+
+        let auth = ApiTokenAuth {
+            token_id: token.id,
+            scopes: parse_scopes(&token.scopes),
+        };
+        Ok(Some((user, auth)))
+    }
+
+    /// The tokens of the user, newest first, expired ones included.
+    pub async fn get_user_tokens(&self, user_id: i64) -> sqlx::Result<Vec<ApiToken>> {
+        sqlx::query_as!(
+            ApiToken,
+            r#"
+            SELECT id AS "id!", name, scopes, created_at, last_used_at, expires_at,
+                   COALESCE(expires_at <= CURRENT_TIMESTAMP, FALSE) AS "expired!: bool"
+            FROM api_tokens
+            WHERE user_id = ?
+            ORDER BY created_at DESC, id DESC;
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn revoke_token(&self, user_id: i64, token_id: i64) -> sqlx::Result<u64> {
+        let rows_affected = sqlx::query!(
+            "DELETE FROM api_tokens WHERE id = ? AND user_id = ?",
+            token_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
+    }
+}
+
+#[derive(Clone)]
+pub struct AdminRepository {
+    pub pool: Arc<SqlitePool>,
+}
+
+impl AdminRepository {
+    /// All the users with their chat and message counts, oldest first.
+    pub async fn list_users(&self) -> sqlx::Result<Vec<UserSummary>> {
+        sqlx::query_as!(
+            UserSummary,
+            r#"
+            SELECT users.id AS "id!", users.email, users.role, users.created_at, users.email_verified_at, users.disabled_at,
+                   (SELECT COUNT(*) FROM chats WHERE chats.user_id = users.id) AS "chat_count!: i64",
+                   (SELECT COUNT(*) FROM message_pairs JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id JOIN chats ON chats.id = message_blocks.chat_id WHERE chats.user_id = users.id) AS "message_count!: i64",
+                   (SELECT MAX(last_seen_at) FROM sessions WHERE sessions.user_id = users.id) AS "last_seen_at: NaiveDateTime"
+            FROM users
+            ORDER BY users.id;
+            "#
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Make the users with these emails admins once they verified it, returns
+    /// how many were promoted.
+    pub async fn promote_admins(&self, emails: &[String]) -> sqlx::Result<u64> {
+        let mut promoted = 0;
+        for email in emails {
+            promoted += sqlx::query!(
+                "UPDATE users SET role = ? WHERE email = ? AND role <> ? AND email_verified_at IS NOT NULL",
+                "admin",
+                email,
+                "admin"
+            )
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        }
+        Ok(promoted)
+    }
+
+    pub async fn set_role(&self, user_id: i64, role: Role) -> sqlx::Result<u64> {
+        let role = role.as_str();
+        let rows_affected = sqlx::query!("UPDATE users SET role = ? WHERE id = ?", role, user_id)
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        Ok(rows_affected)
+    }
+
+    /// Disable or enable a user, disabling also ends their sessions.
+    pub async fn set_disabled(&self, user_id: i64, disabled: bool) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        let rows_affected = if disabled {
+            sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query!(
+                "UPDATE users SET disabled_at = COALESCE(disabled_at, CURRENT_TIMESTAMP) WHERE id = ?",
+                user_id
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+        } else {
+            sqlx::query!("UPDATE users SET disabled_at = NULL WHERE id = ?", user_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+        };
+        tx.commit().await?;
+        Ok(rows_affected)
+    }
+
+    /// Replace the password hash of a user and end their sessions.
+    pub async fn reset_password(&self, user_id: i64, password: &str) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        let rows_affected = sqlx::query!(
+            "UPDATE users SET password = ? WHERE id = ?",
+            password,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(rows_affected)
+    }
+}
+
+#[derive(Clone)]
+pub struct InviteRepository {
+    pub pool: Arc<SqlitePool>,
+}
+
+impl InviteRepository {
+    /// Record an invite code, expiring after `days` unless `None`.
+    pub async fn create_invite(
+        &self,
+        created_by: i64,
+        code_hash: &str,
+        days: Option<i64>,
+    ) -> sqlx::Result<i64> {
+        let lifetime = days.map(|days| format!("{:+} days", days));
+        let invite_id = sqlx::query!(
+            r#"
+            INSERT INTO invite_codes (created_by, code_hash, expires_at)
+            VALUES (?, ?, datetime('now', ?));
+            "#,
+            created_by,
+            code_hash,
+            lifetime
+        )
+        .execute(&*self.pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(invite_id)
+    }
+
+    /// All the invite codes, newest first, used and expired ones included.
+    pub async fn get_invites(&self) -> sqlx::Result<Vec<InviteCode>> {
+        sqlx::query_as!(
+            InviteCode,
+            r#"
+            SELECT invite_codes.id AS "id!", creators.email AS "created_by_email?", invite_codes.created_at,
+                   invite_codes.expires_at, users.email AS "used_by_email?", invite_codes.used_at,
+                   COALESCE(invite_codes.expires_at <= CURRENT_TIMESTAMP, FALSE) AS "expired!: bool"
+            FROM invite_codes
+            LEFT JOIN users creators ON creators.id = invite_codes.created_by
+            LEFT JOIN users ON users.id = invite_codes.used_by
+            ORDER BY invite_codes.created_at DESC, invite_codes.id DESC;
+            "#
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Delete an invite code which wasn't used.
+    pub async fn revoke_invite(&self, invite_id: i64) -> sqlx::Result<u64> {
+        let rows_affected = sqlx::query!(
+            "DELETE FROM invite_codes WHERE id = ? AND used_at IS NULL",
+            invite_id
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
+    }
+
+    /// Create a user with an invite code, which can't be used again. `None`
+    /// when the code is unknown, used or expired.
+    pub async fn create_user_with_invite(
+        &self,
+        email: &str,
+        password: &str,
+        code_hash: &str,
+    ) -> sqlx::Result<Option<i64>> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        if !claim_invite(&mut tx, code_hash).await? {
+            return Ok(None);
+        }
+
+        let user_id = sqlx::query!(
+            "INSERT INTO users (email, password) VALUES (?, ?)",
+            email,
+            password
+        )
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        set_invite_user(&mut tx, code_hash, user_id).await?;
+        tx.commit().await?;
+
+        Ok(Some(user_id))
+    }
+}
+
+// Mark an invite code used, `false` when it is unknown, used or expired.
+async fn claim_invite(tx: &mut Transaction<'_, Sqlite>, code_hash: &str) -> sqlx::Result<bool> {
+    let claimed = sqlx::query!(
+        r#"
+        UPDATE invite_codes SET used_at = CURRENT_TIMESTAMP
+        WHERE code_hash = ? AND used_at IS NULL
+          AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP);
+        "#,
+        code_hash
+    )
+    .execute(&mut **tx)
+    .await?
+    .rows_affected();
+    Ok(claimed == 1)
+}
+
+async fn set_invite_user(
+    tx: &mut Transaction<'_, Sqlite>,
+    code_hash: &str,
+    user_id: i64,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "UPDATE invite_codes SET used_by = ? WHERE code_hash = ?",
+        user_id,
+        code_hash
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct OrganizationRepository {
+    pub pool: Arc<SqlitePool>,
+}
+
+impl OrganizationRepository {
+    pub async fn create_organization(&self, name: &str) -> sqlx::Result<i64> {
+        let organization_id = sqlx::query!("INSERT INTO organizations (name) VALUES (?)", name)
+            .execute(&*self.pool)
+            .await?
+            .last_insert_rowid();
+
+        Ok(organization_id)
+    }
+
+    pub async fn get_organizations(&self) -> sqlx::Result<Vec<Organization>> {
+        sqlx::query_as!(
+            Organization,
+            r#"
+            SELECT organizations.id AS "id!", organizations.name, organizations.openai_api_key_hint,
+                   organizations.anthropic_api_key_hint, organizations.created_at,
+                   (SELECT COUNT(*) FROM organization_members WHERE organization_members.organization_id = organizations.id) AS "member_count!: i64"
+            FROM organizations
+            ORDER BY organizations.name, organizations.id;
+            "#
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn get_organization(&self, organization_id: i64) -> sqlx::Result<Organization> {
+        sqlx::query_as!(
+            Organization,
+            r#"
+            SELECT organizations.id AS "id!", organizations.name, organizations.openai_api_key_hint,
+                   organizations.anthropic_api_key_hint, organizations.created_at,
+                   (SELECT COUNT(*) FROM organization_members WHERE organization_members.organization_id = organizations.id) AS "member_count!: i64"
+            FROM organizations
+            WHERE organizations.id = ?;
+            "#,
+            organization_id
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    /// Delete an organization, its members go back to their own keys.
+    pub async fn delete_organization(&self, organization_id: i64) -> sqlx::Result<u64> {
+        let rows_affected = sqlx::query!("DELETE FROM organizations WHERE id = ?", organization_id)
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        Ok(rows_affected)
+    }
+
+    /// Set the key of the organization for a provider, encrypted with
+    /// `ProviderKind::organization_key_context`. `None` removes it.
+    pub async fn set_api_key(
+        &self,
+        organization_id: i64,
+        kind: ProviderKind,
+        encrypted: Option<&str>,
+        hint: Option<&str>,
+    ) -> sqlx::Result<u64> {
+        let result = match kind {
+            ProviderKind::OpenAi => {
+                sqlx::query!(
+                    "UPDATE organizations SET openai_api_key = ?, openai_api_key_hint = ? WHERE id = ?",
+                    encrypted,
+                    hint,
+                    organization_id
+                )
+                .execute(&*self.pool)
+                .await?
+            }
+            ProviderKind::Anthropic => {
+                sqlx::query!(
+                    "UPDATE organizations SET anthropic_api_key = ?, anthropic_api_key_hint = ? WHERE id = ?",
+                    encrypted,
+                    hint,
+                    organization_id
+                )
+                .execute(&*self.pool)
+                .await?
+            }
+            ProviderKind::Ollama => return Ok(0),
+        };
+        Ok(result.rows_affected())
+    }
+
+    /// Add the user with this email to the organization, returns 0 when there
+    /// is no such user or they are already a member of an organization.
+    pub async fn add_member(
+        &self,
+        organization_id: i64,
+        email: &str,
+        monthly_request_quota: Option<i64>,
+        monthly_token_quota: Option<i64>,
+    ) -> sqlx::Result<u64> {
+        let rows_affected = sqlx::query!(
+            r#"
+            INSERT INTO organization_members (user_id, organization_id, monthly_request_quota, monthly_token_quota)
+            SELECT id, ?, ?, ? FROM users WHERE email = ?
+            ON CONFLICT (user_id) DO NOTHING;
+            "#,
+            organization_id,
+            monthly_request_quota,
+            monthly_token_quota,
+            email
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
+    }
+
+    pub async fn set_member_quotas(
+        &self,
+        organization_id: i64,
+        user_id: i64,
+        monthly_request_quota: Option<i64>,
+        monthly_token_quota: Option<i64>,
+    ) -> sqlx::Result<u64> {
+        let rows_affected = sqlx::query!(
+            r#"
+            UPDATE organization_members SET monthly_request_quota = ?, monthly_token_quota = ?
+            WHERE organization_id = ? AND user_id = ?;
+            "#,
+            monthly_request_quota,
+            monthly_token_quota,
+            organization_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
+    }
+
+    pub async fn remove_member(&self, organization_id: i64, user_id: i64) -> sqlx::Result<u64> {
+        let rows_affected = sqlx::query!(
+            "DELETE FROM organization_members WHERE organization_id = ? AND user_id = ?",
+            organization_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
+    }
+
+    /// The members of the organization with their usage this month.
+    pub async fn get_members(&self, organization_id: i64) -> sqlx::Result<Vec<OrganizationMember>> {
+        sqlx::query_as!(
+            OrganizationMember,
+            r#"
+            SELECT organization_members.user_id AS "user_id!", users.email, organization_members.organization_id,
+                   organizations.name AS organization_name,
+                   organization_members.monthly_request_quota, organization_members.monthly_token_quota,
+                   (SELECT COUNT(*) FROM usage_events WHERE usage_events.user_id = organization_members.user_id AND usage_events.organization_id = organization_members.organization_id AND usage_events.created_at >= datetime('now', 'start of month')) AS "requests!: i64",
+                   (SELECT COALESCE(SUM(input_tokens + output_tokens), 0) FROM usage_events WHERE usage_events.user_id = organization_members.user_id AND usage_events.organization_id = organization_members.organization_id AND usage_events.created_at >= datetime('now', 'start of month')) AS "tokens!: i64"
+            FROM organization_members
+            JOIN users ON users.id = organization_members.user_id
+            JOIN organizations ON organizations.id = organization_members.organization_id
+            WHERE organization_members.organization_id = ?
+            ORDER BY users.email;
+            "#,
+            organization_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// The membership of the user with their usage this month, if any.
+    pub async fn get_membership(&self, user_id: i64) -> sqlx::Result<Option<OrganizationMember>> {
+        sqlx::query_as!(
+            OrganizationMember,
+            r#"
+            SELECT organization_members.user_id AS "user_id!", users.email, organization_members.organization_id,
+                   organizations.name AS organization_name,
+                   organization_members.monthly_request_quota, organization_members.monthly_token_quota,
+                   (SELECT COUNT(*) FROM usage_events WHERE usage_events.user_id = organization_members.user_id AND usage_events.organization_id = organization_members.organization_id AND usage_events.created_at >= datetime('now', 'start of month')) AS "requests!: i64",
+                   (SELECT COALESCE(SUM(input_tokens + output_tokens), 0) FROM usage_events WHERE usage_events.user_id = organization_members.user_id AND usage_events.organization_id = organization_members.organization_id AND usage_events.created_at >= datetime('now', 'start of month')) AS "tokens!: i64"
+            FROM organization_members
+            JOIN users ON users.id = organization_members.user_id
+            JOIN organizations ON organizations.id = organization_members.organization_id
+            WHERE organization_members.user_id = ?;
+            "#,
+            user_id
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    /// Record a generation with an organization key as it starts, returns
+    /// the id to complete it with. `None` when a quota of the member is used
+    /// up: checked by the insert itself, so parallel requests can't all pass.
+    pub async fn start_usage(
+        &self,
+        user_id: i64,
+        organization_id: i64,
+        chat_id: i64,
+        provider: &str,
+        model: &str,
+    ) -> sqlx::Result<Option<i64>> {
+        let rows_affected = sqlx::query!(
+            r#"
+            INSERT INTO usage_events (user_id, organization_id, chat_id, provider, model)
+            SELECT ?, ?, ?, ?, ?
+            FROM organization_members
+            WHERE organization_members.user_id = ? AND organization_members.organization_id = ?
+              AND (monthly_request_quota IS NULL OR monthly_request_quota > (
+                SELECT COUNT(*) FROM usage_events WHERE usage_events.user_id = organization_members.user_id AND usage_events.organization_id = organization_members.organization_id AND usage_events.created_at >= datetime('now', 'start of month')))
+              AND (monthly_token_quota IS NULL OR monthly_token_quota > (
+                SELECT COALESCE(SUM(input_tokens + output_tokens), 0) FROM usage_events WHERE usage_events.user_id = organization_members.user_id AND usage_events.organization_id = organization_members.organization_id AND usage_events.created_at >= datetime('now', 'start of month')));
+            "#,
+            user_id,
+            organization_id,
+            chat_id,
+            provider,
+            model,
+            user_id,
+            organization_id
+        )
+        .execute(&*self.pool)
+        .await?;
+
+        Ok((rows_affected.rows_affected() == 1).then(|| rows_affected.last_insert_rowid()))
+    }
+
+    pub async fn finish_usage(
+        &self,
+        usage_id: i64,
+        input_tokens: i64,
+        output_tokens: i64,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE usage_events SET input_tokens = ?, output_tokens = ? WHERE id = ?",
+            input_tokens,
+            output_tokens,
+            usage_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct AccountRepository {
+    pub pool: Arc<SqlitePool>,
+}
+
+impl AccountRepository {
+    /// The data of the user from `users`, `settings`, `chats`,
+    /// `message_blocks`, `message_pairs` and `messages`.
+    pub async fn export(&self, user_id: i64) -> sqlx::Result<AccountExport> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let user = sqlx::query_as!(
+            ExportedUser,
+            "SELECT id, email, role, created_at, email_verified_at FROM users WHERE id = ?",
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let settings = sqlx::query_as!(
+            ExportedSettings,
+            r#"
+            SELECT openai_api_key_hint, anthropic_api_key_hint, openai_base_url, openai_api_version,
+                   openai_extra_header_names, created_at
+            FROM settings
+            WHERE user_id = ?;
+            "#,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let chats = sqlx::query_as!(
+            ExportedChat,
+            r#"
+            SELECT id AS "id!", name, provider, model, created_at
+            FROM chats
+            WHERE user_id = ?
+            ORDER BY id;
+            "#,
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let message_blocks = sqlx::query_as!(
+            ExportedMessageBlock,
+            r#"
+            SELECT message_blocks.id AS "id!", message_blocks.chat_id, message_blocks.parent_pair_id,
+                   message_blocks.selected_pair_id, message_blocks.created_at, message_blocks.updated_at
+            FROM message_blocks
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE chats.user_id = ?
+            ORDER BY message_blocks.id;
+            "#,
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let message_pairs = sqlx::query_as!(
+            ExportedMessagePair,
+            r#"
+            SELECT message_pairs.id AS "id!", message_pairs.message_block_id, message_pairs.human_message_id,
+                   message_pairs.ai_message_id, message_pairs.status, message_pairs.created_at
+            FROM message_pairs
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE chats.user_id = ?
+            ORDER BY message_pairs.id;
+            "#,
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let messages = sqlx::query_as!(
+            ExportedMessage,
+            r#"
+            SELECT messages.id AS "id!", messages.message, messages.created_at
+            FROM messages
+            WHERE messages.id IN (
+              SELECT message_pairs.human_message_id FROM message_pairs
+              JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+              JOIN chats ON chats.id = message_blocks.chat_id
+              WHERE chats.user_id = ?
+              UNION
+              SELECT message_pairs.ai_message_id FROM message_pairs
+              JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+              JOIN chats ON chats.id = message_blocks.chat_id
+              WHERE chats.user_id = ?
+            )
+            ORDER BY messages.id;
+            "#,
+            user_id,
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(AccountExport {
+            exported_at: chrono::Utc::now().naive_utc(),
+            user,
+            settings,
+            chats,
+            message_blocks,
+            message_pairs,
+            messages,
+        })
+    }
+
+    /// Delete the user, the rest of their data goes with the foreign keys
+    /// except for the messages, which don't reference their chat.
+    pub async fn delete_account(&self, user_id: i64) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        sqlx::query!(
+            r#"
+            DELETE FROM messages
+            WHERE id IN (
+              SELECT message_pairs.human_message_id FROM message_pairs
+              JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+              JOIN chats ON chats.id = message_blocks.chat_id
+              WHERE chats.user_id = ?
+              UNION
+              SELECT message_pairs.ai_message_id FROM message_pairs
+              JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+              JOIN chats ON chats.id = message_blocks.chat_id
+              WHERE chats.user_id = ?
+            );
+            "#,
+            user_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let rows_affected = sqlx::query!("DELETE FROM users WHERE id = ?", user_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok(rows_affected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use sqlx::migrate::Migrator;
+
+    use super::*;
+    use crate::security::session::generate_token;
+
+    async fn setup() -> (Arc<SqlitePool>, ChatRepository, i64) {
+        let x = std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:db.db".to_string());
+        let pool = SqlitePool::connect(&x).await.unwrap();
+
+        // let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let pool = Arc::new(pool);
+
+        let migrator = Migrator::new(Path::new(dotenv::var("MIGRATIONS_PATH").unwrap().as_str()))
+            .await
+            .unwrap();
+        // Run the migrations.
+        migrator.run(&*pool).await.unwrap();
+
+        // Tests share the database, emails are unique
+        let email = format!("test-{}@test.com", generate_token());
+        let user = sqlx::query!(
+            "INSERT INTO users (email, password) VALUES ($1, $2) RETURNING id",
+            email,
+            "test"
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+
+        let repo = ChatRepository { pool: pool.clone() };
+
+        (pool, repo, user.id)
+    }
+
+    #[tokio::test]
+    async fn test_create_chat() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat = repo.create_chat(user_id, "test", "openai", "gpt-4").await;
+        assert!(chat.is_ok(), "Failed to create chat");
+    }
+
+    #[tokio::test]
+    async fn test_add_message_block() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat = repo.create_chat(user_id, "test", "openai", "gpt-4").await;
+        assert!(chat.is_ok(), "Failed to create chat");
+        let chat_id = chat.unwrap();
+
+        let message_block = repo.add_message_block(user_id, chat_id, "Test").await;
+        assert!(message_block.is_ok(), "Failed to add message_block")
+    }
+
+    #[tokio::test]
+    async fn test_add_alternative_pair() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo
+            .create_chat(user_id, "test", "openai", "gpt-4")
+            .await
+            .unwrap();
+        let pair_id = repo
+            .add_message_block(user_id, chat_id, "Test")
+            .await
+            .unwrap();
+        repo.save_ai_message(pair_id, "Answer", PairStatus::Complete)
+            .await
+            .unwrap();
+
+        let new_pair_id = repo
+            .add_alternative_pair(user_id, chat_id, pair_id)
+            .await
+            .unwrap();
+        assert_ne!(pair_id, new_pair_id);
+
+        // The new pair is selected, with the same question and no answer yet
+        let pairs = repo.retrieve_chat(user_id, chat_id).await.unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].id, new_pair_id);
+        assert_eq!(pairs[0].human_message, "Test");
+        assert!(pairs[0].ai_message.is_none());
+
+        // The pair must belong to the chat
+        let other_chat_id = repo
+            .create_chat(user_id, "other", "openai", "gpt-4")
+            .await
+            .unwrap();
+        assert!(repo
+            .add_alternative_pair(user_id, other_chat_id, pair_id)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_select_pair() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo
+            .create_chat(user_id, "test", "openai", "gpt-4")
+            .await
+            .unwrap();
+        let first_id = repo
+            .add_message_block(user_id, chat_id, "Test")
+            .await
+            .unwrap();
+        let second_id = repo
+            .add_alternative_pair(user_id, chat_id, first_id)
+            .await
+            .unwrap();
+        let third_id = repo
+            .add_alternative_pair(user_id, chat_id, first_id)
+            .await
+            .unwrap();
+
+        let pairs = repo.retrieve_chat(user_id, chat_id).await.unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].id, third_id);
+        assert_eq!((pairs[0].block_rank, pairs[0].block_size), (3, 3));
+        assert_eq!(pairs[0].prev_pair_id, Some(second_id));
+        assert_eq!(pairs[0].next_pair_id, None);
+
+        repo.select_pair(user_id, chat_id, second_id).await.unwrap();
+        let pairs = repo.retrieve_chat(user_id, chat_id).await.unwrap();
+        assert_eq!(pairs[0].id, second_id);
+        assert_eq!(pairs[0].block_rank, 2);
+        assert_eq!(pairs[0].prev_pair_id, Some(first_id));
+        assert_eq!(pairs[0].next_pair_id, Some(third_id));
+
+        // The pair must belong to the chat
+        let other_chat_id = repo
+            .create_chat(user_id, "other", "openai", "gpt-4")
+            .await
+            .unwrap();
+        assert!(repo
+            .select_pair(user_id, other_chat_id, first_id)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_edit_pair() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo
+            .create_chat(user_id, "test", "openai", "gpt-4")
+            .await
+            .unwrap();
+        let first_id = repo
+            .add_message_block(user_id, chat_id, "Helo")
+            .await
+            .unwrap();
+        repo.save_ai_message(first_id, "Hi", PairStatus::Complete)
+            .await
+            .unwrap();
+        let second_id = repo
+            .add_message_block(user_id, chat_id, "How are you?")
+            .await
+            .unwrap();
+        repo.save_ai_message(second_id, "Fine", PairStatus::Complete)
+            .await
+            .unwrap();
+
+        // The edited pair is selected and starts a new branch
+        let edited_id = repo
+            .edit_pair(user_id, chat_id, first_id, "Hello")
+            .await
+            .unwrap();
+        let pairs = repo.retrieve_chat(user_id, chat_id).await.unwrap();
+        let ids = pairs.iter().map(|p| p.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![edited_id]);
+        assert_eq!(pairs[0].human_message, "Hello");
+        assert_eq!((pairs[0].block_rank, pairs[0].block_size), (2, 2));
+
+        // New messages continue the new branch
+        let third_id = repo
+            .add_message_block(user_id, chat_id, "What's up?")
+            .await
+            .unwrap();
+        let pairs = repo.retrieve_chat(user_id, chat_id).await.unwrap();
+        let ids = pairs.iter().map(|p| p.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![edited_id, third_id]);
+
+        // Going back to the original pair shows the old branch
+        repo.select_pair(user_id, chat_id, first_id).await.unwrap();
+        let pairs = repo.retrieve_chat(user_id, chat_id).await.unwrap();
+        let ids = pairs.iter().map(|p| p.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![first_id, second_id]);
+
+        // The pair must belong to the chat
+        let other_chat_id = repo
+            .create_chat(user_id, "other", "openai", "gpt-4")
+            .await
+            .unwrap();
+        assert!(repo
+            .edit_pair(user_id, other_chat_id, first_id, "Hey")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_save_ai_message() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo
+            .create_chat(user_id, "test", "openai", "gpt-4")
+            .await
+            .unwrap();
+        let pair_id = repo
+            .add_message_block(user_id, chat_id, "Test")
+            .await
+            .unwrap();
+        let pairs = repo.retrieve_chat(user_id, chat_id).await.unwrap();
+        assert_eq!(pairs[0].status, "pending");
+
+        // The partial message is updated in place while streaming
+        let message_id = repo
+            .save_ai_message(pair_id, "Ans", PairStatus::Streaming)
+            .await
+            .unwrap();
+        let same_id = repo
+            .save_ai_message(pair_id, "Answer", PairStatus::Streaming)
+            .await
+            .unwrap();
+        assert_eq!(message_id, same_id);
+        let pairs = repo.retrieve_chat(user_id, chat_id).await.unwrap();
+        assert_eq!(pairs[0].ai_message.as_deref(), Some("Answer"));
+        assert_eq!(pairs[0].status, "streaming");
+
+        // A streaming pair left behind fails, a complete one stays complete
+        assert!(repo.fail_streaming_pair(pair_id).await.unwrap());
+        let pairs = repo.retrieve_chat(user_id, chat_id).await.unwrap();
+        assert_eq!(pairs[0].status, "failed");
+
+        repo.save_ai_message(pair_id, "Answer", PairStatus::Complete)
+            .await
+            .unwrap();
+        assert!(!repo.fail_streaming_pair(pair_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_answer_failed_to_start() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo
+            .create_chat(user_id, "test", "openai", "gpt-4")
+            .await
+            .unwrap();
+        let pair_id = repo
+            .add_message_block(user_id, chat_id, "Hello")
+            .await
+            .unwrap();
+
+        // As the API does when the provider is refused, so the chat takes the
+        // next message
+        repo.save_ai_message(pair_id, "", PairStatus::Failed)
+            .await
+            .unwrap();
+        repo.add_message_block(user_id, chat_id, "Hello again")
+            .await
+            .unwrap();
+        let pairs = repo.retrieve_chat(user_id, chat_id).await.unwrap();
+        let statuses: Vec<_> = pairs.iter().map(|pair| pair.status.as_str()).collect();
+        assert_eq!(statuses, ["failed", "pending"]);
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let (pool, _repo, user_id) = setup().await;
+        let sessions = SessionRepository { pool: pool.clone() };
+
+        sessions
+            .create_session(user_id, "hash", Some("curl/8.0"), Some("127.0.0.1"), 30)
+            .await
+            .unwrap();
+        let user = sessions.get_user("hash").await.unwrap();
+        assert_eq!(user.map(|u| u.id), Some(user_id));
+        assert!(sessions.get_user("other").await.unwrap().is_none());
+        sessions.touch_session("hash").await.unwrap();
+
+        // Expired sessions are ignored, then cleaned up
+        sessions
+            .create_session(user_id, "expired", None, None, -1)
+            .await
+            .unwrap();
+        assert!(sessions.get_user("expired").await.unwrap().is_none());
+        assert_eq!(sessions.delete_expired_sessions().await.unwrap(), 1);
+
+        assert_eq!(sessions.revoke_session("hash").await.unwrap(), 1);
+        assert!(sessions.get_user("hash").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_sessions() {
+        let (pool, _repo, user_id) = setup().await;
+        let sessions = SessionRepository { pool: pool.clone() };
+        let email = format!("other-{}@test.com", user_id);
+        let other_user = sqlx::query!(
+            "INSERT INTO users (email, password) VALUES ($1, $2) RETURNING id",
+            email,
+            "test"
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+
+        let laptop = sessions
+            .create_session(user_id, "laptop", Some("Firefox"), None, 30)
+            .await
+            .unwrap();
+        sessions
+            .create_session(user_id, "phone", Some("Safari"), None, 30)
+            .await
+            .unwrap();
+        sessions
+            .create_session(user_id, "current", Some("Chrome"), None, 30)
+            .await
+            .unwrap();
+        let other = sessions
+            .create_session(other_user.id, "other-user", None, None, 30)
+            .await
+            .unwrap();
+
+        let listed = sessions
+            .get_user_sessions(user_id, "current")
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(listed.iter().filter(|s| s.current).count(), 1);
+
+        // Only the sessions of the user, but not the current one
+        assert_eq!(
+            sessions
+                .revoke_user_session(user_id, other, "current")
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            sessions
+                .revoke_user_session(user_id, laptop, "current")
+                .await
+                .unwrap(),
+            1
+        );
+        let current = listed.iter().find(|s| s.current).unwrap().id;
+        assert_eq!(
+            sessions
+                .revoke_user_session(user_id, current, "current")
+                .await
+                .unwrap(),
+            0
+        );
+
+        assert_eq!(
+            sessions
+                .revoke_other_sessions(user_id, "current")
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(sessions.get_user("current").await.unwrap().is_some());
+        assert!(sessions.get_user("phone").await.unwrap().is_none());
+        assert!(sessions.get_user("other-user").await.unwrap().is_some());
+
+        assert_eq!(sessions.revoke_all_sessions(user_id).await.unwrap(), 1);
+        assert!(sessions.get_user("current").await.unwrap().is_none());
+        assert!(sessions.get_user("other-user").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_chat_ownership() {
+        let (pool, repo, user_id) = setup().await;
+        let email = format!("intruder-{}@test.com", user_id);
+        let intruder = sqlx::query!(
+            "INSERT INTO users (email, password) VALUES ($1, $2) RETURNING id",
+            email,
+            "test"
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+
+        let chat_id = repo
+            .create_chat(user_id, "test", "openai", "gpt-4")
+            .await
+            .unwrap();
+        let pair_id = repo
+            .add_message_block(user_id, chat_id, "Test")
+            .await
+            .unwrap();
+
+        // Another user can neither read nor change the chat
+        let other = intruder.id;
+        assert!(matches!(
+            repo.get_chat(other, chat_id).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert!(repo.retrieve_chat(other, chat_id).await.unwrap().is_empty());
+        assert!(repo.add_message_block(other, chat_id, "Hi").await.is_err());
+        assert!(repo
+            .add_alternative_pair(other, chat_id, pair_id)
+            .await
+            .is_err());
+        assert!(repo.edit_pair(other, chat_id, pair_id, "Hi").await.is_err());
+        assert!(repo.select_pair(other, chat_id, pair_id).await.is_err());
+        assert_eq!(repo.delete_chat(other, chat_id).await.unwrap(), 0);
+        assert!(repo.get_all_chats(other).await.unwrap().is_empty());
+
+        // The owner still has it all
+        let pairs = repo.retrieve_chat(user_id, chat_id).await.unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].id, pair_id);
+        let message_id = sqlx::query_scalar!(
+            "SELECT human_message_id FROM message_pairs WHERE id = ?",
+            pair_id
+        )
+        .fetch_one(&*repo.pool)
+        .await
+        .unwrap();
+        assert_eq!(repo.delete_chat(user_id, chat_id).await.unwrap(), 1);
+
+        // The messages go with the chat
+        let messages =
+            sqlx::query_scalar!("SELECT COUNT(*) FROM messages WHERE id = ?", message_id)
+                .fetch_one(&*repo.pool)
+                .await
+                .unwrap();
+        assert_eq!(messages, 0);
+    }
+
+    #[tokio::test]
+    async fn test_json() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat = repo.create_chat(user_id, "test", "openai", "gpt-4").await;
+        assert!(chat.is_ok(), "Failed to create chat");
+        let chat_id = chat.unwrap();
+
+        let message_block = repo.add_message_block(user_id, chat_id, "Test").await;
+        assert!(message_block.is_ok(), "Failed to add message_block");
+
+        let chat_message_pairs = repo.retrieve_chat(user_id, chat_id).await;
+        print!("{:#?}", chat_message_pairs)
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_plaintext_keys() {
+        let (pool, _repo, user_id) = setup().await;
+        let settings = SettingsRepository { pool: pool.clone() };
+        let cipher = KeyCipher::new(&[1; 32]);
+
+        sqlx::query!(
+            "INSERT INTO settings (user_id, openai_api_key, anthropic_api_key) VALUES (?, ?, ?)",
+            user_id,
+            "sk-openai-1234",
+            "sk-ant-5678"
+        )
+        .execute(&*pool)
+        .await
+        .unwrap();
+
+        settings.encrypt_plaintext_keys(&cipher).await.unwrap();
+        let row = sqlx::query!(
+            "SELECT openai_api_key, anthropic_api_key, openai_api_key_hint, anthropic_api_key_hint FROM settings WHERE user_id = ?",
+            user_id
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+
+        let openai_context = ProviderKind::OpenAi.key_context(user_id);
+        assert_eq!(
+            cipher
+                .decrypt(&row.openai_api_key, &openai_context)
+                .unwrap(),
+            "sk-openai-1234"
+        );
+        let anthropic_api_key = row.anthropic_api_key.unwrap();
+        assert_eq!(
+            cipher
+                .decrypt(
+                    &anthropic_api_key,
+                    &ProviderKind::Anthropic.key_context(user_id)
+                )
+                .unwrap(),
+            "sk-ant-5678"
+        );
+        assert_eq!(row.openai_api_key_hint.as_deref(), Some("…1234"));
+        assert_eq!(row.anthropic_api_key_hint.as_deref(), Some("…5678"));
+
+        // Encrypted keys are left alone
+        settings.encrypt_plaintext_keys(&cipher).await.unwrap();
+        let openai_api_key = sqlx::query_scalar!(
+            "SELECT openai_api_key FROM settings WHERE user_id = ?",
+            user_id
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        assert_eq!(openai_api_key, row.openai_api_key);
+    }
+
+    #[tokio::test]
+    async fn test_login_attempts() {
+        let (pool, _repo, _user_id) = setup().await;
+        let attempts = LoginAttemptRepository { pool: pool.clone() };
+        let now = chrono::Utc::now().naive_utc();
+        let hour_ago = now - chrono::Duration::hours(1);
+
+        attempts.save("email:a@b.c", 1, hour_ago).await.unwrap();
+        attempts.save("email:a@b.c", 2, now).await.unwrap();
+        attempts.save("ip:1.1.1.1", 1, hour_ago).await.unwrap();
+
+        let recent = attempts
+            .get_recent(now - chrono::Duration::minutes(1))
+            .await
+            .unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].key, "email:a@b.c");
+        assert_eq!(recent[0].failures, 2);
+
+        assert_eq!(attempts.delete_older_than(hour_ago).await.unwrap(), 1);
+        attempts.delete("email:a@b.c").await.unwrap();
+        assert!(attempts.get_recent(hour_ago).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_two_factor() {
+        let (pool, _repo, user_id) = setup().await;
+        let two_factor = TwoFactorRepository { pool: pool.clone() };
+
+        assert!(two_factor.get_totp(user_id).await.unwrap().is_none());
+        two_factor.start_enrolment(user_id, "first").await.unwrap();
+        two_factor.start_enrolment(user_id, "second").await.unwrap();
+        let totp = two_factor.get_totp(user_id).await.unwrap().unwrap();
+        assert_eq!(totp.secret, "second");
+        assert!(!two_factor.is_enabled(user_id).await.unwrap());
+
+        let codes = vec!["code-1".to_string(), "code-2".to_string()];
+        two_factor.enable(user_id, 10, &codes).await.unwrap();
+        assert!(two_factor.is_enabled(user_id).await.unwrap());
+
+        // An enabled secret isn't replaced
+        two_factor.start_enrolment(user_id, "third").await.unwrap();
+        let totp = two_factor.get_totp(user_id).await.unwrap().unwrap();
+        assert_eq!(totp.secret, "second");
+
+        // Steps and recovery codes are single use
+        assert!(!two_factor.use_step(user_id, 10).await.unwrap());
+        assert!(two_factor.use_step(user_id, 11).await.unwrap());
+        assert!(two_factor
+            .use_recovery_code(user_id, "code-1")
+            .await
+            .unwrap());
+        assert!(!two_factor
+            .use_recovery_code(user_id, "code-1")
+            .await
+            .unwrap());
+        assert!(!two_factor
+            .use_recovery_code(user_id, "code-3")
+            .await
+            .unwrap());
+        assert_eq!(
+            two_factor.remaining_recovery_codes(user_id).await.unwrap(),
+            1
+        );
+
+        two_factor.disable(user_id).await.unwrap();
+        assert!(two_factor.get_totp(user_id).await.unwrap().is_none());
+        assert_eq!(
+            two_factor.remaining_recovery_codes(user_id).await.unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_identities() {
+        let (pool, _repo, user_id) = setup().await;
+        let identities = IdentityRepository { pool: pool.clone() };
+        let issuer = "https://idp.example.com";
+        let subject = format!("user-{}", user_id);
+
+        assert_eq!(identities.find_user(issuer, &subject).await.unwrap(), None);
+        // An unverified account isn't linked, it could be anyone's
+        assert!(!identities.link(user_id, issuer, &subject).await.unwrap());
+        assert_eq!(identities.find_user(issuer, &subject).await.unwrap(), None);
+        assert!(!identities.has_identity(user_id).await.unwrap());
+
+        sqlx::query!(
+            "UPDATE users SET email_verified_at = CURRENT_TIMESTAMP WHERE id = ?",
+            user_id
+        )
+        .execute(&*pool)
+        .await
+        .unwrap();
+        assert!(identities.link(user_id, issuer, &subject).await.unwrap());
+        assert_eq!(
+            identities.find_user(issuer, &subject).await.unwrap(),
+            Some(user_id)
+        );
+        assert!(identities.has_identity(user_id).await.unwrap());
+        // An identity is linked to one user
+        assert!(identities.link(user_id, issuer, &subject).await.is_err());
+
+        let email = format!("sso-{}@example.com", user_id);
+        let subject = format!("new-{}", user_id);
+        let new_user_id = identities
+            .create_user(&email, "hash", issuer, &subject, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            identities.find_user_by_email(&email).await.unwrap(),
+            Some(new_user_id)
+        );
+        assert_eq!(
+            identities.find_user(issuer, &subject).await.unwrap(),
+            Some(new_user_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_api_tokens() {
+        let (pool, _repo, user_id) = setup().await;
+        let tokens = ApiTokenRepository { pool: pool.clone() };
+        let token_hash = format!("api-token-{}", user_id);
+
+        let token_id = tokens
+            .create_token(user_id, "script", &token_hash, "chats:read", Some(30))
+            .await
+            .unwrap();
+        let (user, auth) = tokens.authenticate(&token_hash).await.unwrap().unwrap();
+        assert_eq!(user.id, user_id);
+        assert_eq!(auth.token_id, token_id);
+        assert_eq!(auth.scopes, parse_scopes("chats:read"));
+        assert!(tokens.authenticate("other").await.unwrap().is_none());
+
+        // Expired tokens are listed but don't authenticate
+        let expired_hash = format!("api-token-expired-{}", user_id);
+        tokens
+            .create_token(user_id, "old", &expired_hash, "chats:read", Some(-1))
+            .await
+            .unwrap();
+        assert!(tokens.authenticate(&expired_hash).await.unwrap().is_none());
+        let listed = tokens.get_user_tokens(user_id).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed.iter().any(|token| token.expired));
+        assert!(listed[1].last_used_at.is_some());
+
+        // Only the owner revokes a token
+        assert_eq!(tokens.revoke_token(user_id + 1, token_id).await.unwrap(), 0);
+        assert_eq!(tokens.revoke_token(user_id, token_id).await.unwrap(), 1);
+        assert!(tokens.authenticate(&token_hash).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invites() {
+        let (pool, _, user_id) = setup().await;
+        let invites = InviteRepository { pool: pool.clone() };
+        let code_hash = format!("invite-{}", user_id);
+        let expired_hash = format!("invite-expired-{}", user_id);
+        invites
+            .create_invite(user_id, &code_hash, Some(7))
+            .await
+            .unwrap();
+        invites
+            .create_invite(user_id, &expired_hash, Some(-1))
+            .await
+            .unwrap();
+
+        let email = format!("invited-{}@test.com", user_id);
+        assert!(invites
+            .create_user_with_invite(&email, "hash", &expired_hash)
+            .await
+            .unwrap()
+            .is_none());
+        let invited = invites
+            .create_user_with_invite(&email, "hash", &code_hash)
+            .await
+            .unwrap()
+            .unwrap();
+        // Codes are single use, with single sign-on too
+        assert!(invites
+            .create_user_with_invite("other@test.com", "hash", &code_hash)
+            .await
+            .unwrap()
+            .is_none());
+        let identities = IdentityRepository { pool: pool.clone() };
+        let issuer = "https://idp.example.com";
+        let subject = format!("invited-{}", user_id);
+        let sso_email = format!("invited-sso-{}@test.com", user_id);
+        assert!(identities
+            .create_user(&sso_email, "hash", issuer, &subject, Some(&code_hash))
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(identities.find_user(issuer, &subject).await.unwrap(), None);
+
+        let sso_hash = format!("invite-sso-{}", user_id);
+        invites
+            .create_invite(user_id, &sso_hash, None)
+            .await
+            .unwrap();
+        let sso_user = identities
+            .create_user(&sso_email, "hash", issuer, &subject, Some(&sso_hash))
+            .await
+            .unwrap();
+        assert!(sso_user.is_some());
+
+        let listed = invites.get_invites().await.unwrap();
+        let used = listed
+            .iter()
+            .find(|invite| invite.used_by_email.as_deref() == Some(email.as_str()))
+            .unwrap();
+        assert!(used.used_at.is_some());
+        assert!(listed.iter().any(|invite| invite.expired));
+        // Used codes stay listed
+        assert_eq!(invites.revoke_invite(used.id).await.unwrap(), 0);
+        assert_ne!(invited, user_id);
+    }
+
+    #[tokio::test]
+    async fn test_organizations() {
+        let (pool, repo, user_id) = setup().await;
+        let organizations = OrganizationRepository { pool: pool.clone() };
+        let sessions = SessionRepository { pool: pool.clone() };
+        let token_hash = format!("organization-session-{}", user_id);
+        sessions
+            .create_session(user_id, &token_hash, None, None, 1)
+            .await
+            .unwrap();
+
+        let email = format!("member-{}@test.com", user_id);
+        sqlx::query!("UPDATE users SET email = ? WHERE id = ?", email, user_id)
+            .execute(&*pool)
+            .await
+            .unwrap();
+
+        let organization_id = organizations.create_organization("Acme").await.unwrap();
+        organizations
+            .set_api_key(
+                organization_id,
+                ProviderKind::OpenAi,
+                Some("encrypted"),
+                Some("...abcd"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            organizations
+                .add_member(organization_id, "nobody@test.com", None, None)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            organizations
+                .add_member(organization_id, &email, Some(2), None)
+                .await
+                .unwrap(),
+            1
+        );
+
+        // The organization key comes with the user
+        let user = sessions.get_user(&token_hash).await.unwrap().unwrap();
+        assert_eq!(user.organization_id, Some(organization_id));
+        assert_eq!(
+            user.organization_openai_api_key.as_deref(),
+            Some("encrypted")
+        );
+
+        let chat_id = repo
+            .create_chat(user_id, "test", "openai", "gpt-4")
+            .await
+            .unwrap();
+        let usage_id = organizations
+            .start_usage(user_id, organization_id, chat_id, "openai", "gpt-4")
+            .await
+            .unwrap()
+            .unwrap();
+        let member = organizations
+            .get_membership(user_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((member.requests, member.tokens), (1, 0));
+        assert!(!member.quota_exceeded());
+
+        organizations.finish_usage(usage_id, 10, 5).await.unwrap();
+        assert!(organizations
+            .start_usage(user_id, organization_id, chat_id, "openai", "gpt-4")
+            .await
+            .unwrap()
+            .is_some());
+        let members = organizations.get_members(organization_id).await.unwrap();
+        assert_eq!((members[0].requests, members[0].tokens), (2, 15));
+        assert!(members[0].quota_exceeded());
+
+        // Parallel requests don't get past the quota
+        organizations
+            .set_member_quotas(organization_id, user_id, Some(4), None)
+            .await
+            .unwrap();
+        let started = futures::future::join_all((0..5).map(|_| {
+            organizations.start_usage(user_id, organization_id, chat_id, "openai", "gpt-4")
+        }))
+        .await;
+        let started = started
+            .into_iter()
+            .filter(|usage_id| usage_id.as_ref().unwrap().is_some())
+            .count();
+        assert_eq!(started, 2);
+        let member = organizations
+            .get_membership(user_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(member.requests, 4);
+
+        // Deleting the organization removes the memberships
+        organizations
+            .delete_organization(organization_id)
+            .await
+            .unwrap();
+        assert!(organizations
+            .get_membership(user_id)
+            .await
+            .unwrap()
+            .is_none());
+        let user = sessions.get_user(&token_hash).await.unwrap().unwrap();
+        assert!(user.organization_id.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let (pool, repo, user_id) = setup().await;
+        let admin = AdminRepository { pool: pool.clone() };
+        let sessions = SessionRepository { pool: pool.clone() };
+        let token_hash = format!("admin-session-{}", user_id);
+        sessions
+            .create_session(user_id, &token_hash, None, None, 1)
+            .await
+            .unwrap();
+        let chat_id = repo
+            .create_chat(user_id, "test", "openai", "gpt-4")
+            .await
+            .unwrap();
+        repo.add_message_block(user_id, chat_id, "hello")
+            .await
+            .unwrap();
+
+        let users = admin.list_users().await.unwrap();
+        let summary = users.iter().find(|user| user.id == user_id).unwrap();
+        assert_eq!((summary.chat_count, summary.message_count), (1, 1));
+        assert_eq!(summary.role, "user");
+        assert!(summary.last_seen_at.is_some());
+
+        // Only verified emails are promoted, anyone can sign up with an email
+        let email = sessions.get_user(&token_hash).await.unwrap().unwrap().email;
+        assert_eq!(admin.promote_admins(&[email.clone()]).await.unwrap(), 0);
+        sqlx::query!(
+            "UPDATE users SET email_verified_at = CURRENT_TIMESTAMP WHERE id = ?",
+            user_id
+        )
+        .execute(&*pool)
+        .await
+        .unwrap();
+        assert_eq!(admin.promote_admins(&[email]).await.unwrap(), 1);
+        let user = sessions.get_user(&token_hash).await.unwrap().unwrap();
+        assert_eq!(user.role, "admin");
+
+        admin.set_role(user_id, Role::User).await.unwrap();
+        admin.set_role(user_id, Role::Admin).await.unwrap();
+        let user = sessions.get_user(&token_hash).await.unwrap().unwrap();
+        assert_eq!(user.role, "admin");
+
+        // Disabled users lose their sessions
+        admin.set_disabled(user_id, true).await.unwrap();
+        assert!(sessions.get_user(&token_hash).await.unwrap().is_none());
+        admin.set_disabled(user_id, false).await.unwrap();
+        let users = admin.list_users().await.unwrap();
+        let summary = users.iter().find(|user| user.id == user_id).unwrap();
+        assert!(summary.disabled_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_account() {
+        let (pool, repo, user_id) = setup().await;
+        let accounts = AccountRepository { pool: pool.clone() };
+        let chat_id = repo
+            .create_chat(user_id, "test", "openai", "gpt-4")
+            .await
+            .unwrap();
+        let pair_id = repo
+            .add_message_block(user_id, chat_id, "hello")
+            .await
+            .unwrap();
+        let ai_message_id = repo
+            .save_ai_message(pair_id, "hi", PairStatus::Complete)
+            .await
+            .unwrap();
+
+        let export = accounts.export(user_id).await.unwrap();
+        assert_eq!(export.user.id, user_id);
+        assert!(export.settings.is_none());
+        assert_eq!(export.chats.len(), 1);
+        assert_eq!(export.message_blocks.len(), 1);
+        assert_eq!(export.message_pairs[0].ai_message_id, Some(ai_message_id));
+        let messages = export
+            .messages
+            .iter()
+            .map(|message| message.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["hello", "hi"]);
+
+        // The messages go with the account
+        assert_eq!(accounts.delete_account(user_id).await.unwrap(), 1);
+        let left = sqlx::query_scalar!("SELECT COUNT(*) FROM messages WHERE id = ?", ai_message_id)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(left, 0);
+        assert!(repo.get_chat(user_id, chat_id).await.is_err());
+        assert!(accounts.export(user_id).await.is_err());
     }
 }
@@ -1,4 +1,4 @@
-use axum::{http::StatusCode, Router};
+use axum::Router;
 use serde::Serialize;
 use sqlx::{
     migrate::Migrator,
@@ -12,13 +12,30 @@ use tower_http::services::ServeDir;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod router;
-use router::app_router;
+use router::{api_router, app_router};
 use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
 mod ai;
+use ai::{generation::GenerationRegistry, registry::ModelRegistry};
 mod middleware;
-use middleware::extract_user;
+use middleware::{csrf, extract_user};
 mod data;
-use data::repository::ChatRepository;
+use data::repository::{
+    AccountRepository, AdminRepository, ApiTokenRepository, ChatRepository, IdentityRepository,
+    InviteRepository, LoginAttemptRepository, OrganizationRepository, SessionRepository,
+    SettingsRepository, TwoFactorRepository,
+};
+mod mail;
+mod security;
+use mail::{mailer_from_env, Mailer};
+use security::{
+    csrf::CsrfSigner,
+    oidc::{OidcClient, OidcConfig},
+    registration::RegistrationPolicy,
+    secret::{load_master_key, KeyCipher},
+    throttle::LoginThrottle,
+    token::TokenSigner,
+    totp::PendingLogins,
+};
 
 use crate::middleware::handle_error;
 
@@ -27,6 +44,31 @@ struct AppState {
     pool: Arc<Pool<Sqlite>>,
     tera: Tera,
     chat_repo: ChatRepository,
+    session_repo: SessionRepository,
+    two_factor_repo: TwoFactorRepository,
+    identity_repo: IdentityRepository,
+    api_token_repo: ApiTokenRepository,
+    admin_repo: AdminRepository,
+    invite_repo: InviteRepository,
+    organization_repo: OrganizationRepository,
+    account_repo: AccountRepository,
+    model_registry: ModelRegistry,
+    key_cipher: KeyCipher,
+    token_signer: TokenSigner,
+    csrf_signer: CsrfSigner,
+    mailer: Arc<dyn Mailer>,
+    app_url: String,
+    admin_emails: Vec<String>,
+    registration_policy: RegistrationPolicy,
+    login_throttle: LoginThrottle,
+    // Every password reset email counts, kept apart from the failed logins
+    reset_throttle: LoginThrottle,
+    pending_logins: PendingLogins,
+    oidc: Option<OidcClient>,
+    generations: GenerationRegistry,
+    ollama_url: Option<String>,
+    // Whether users can point the OpenAI endpoint at private hosts
+    allow_private_endpoints: bool,
 }
 
 #[tokio::main]
@@ -62,7 +104,58 @@ async fn main() {
 
     let pool = Arc::new(pool);
 
+    // Master key the provider API keys are encrypted with, and the tokens
+    // sent by email signed with
+    let master_key = load_master_key().expect("can't load the secret key");
+    let key_cipher = KeyCipher::new(&master_key);
+    let token_signer = TokenSigner::new(&master_key);
+    let csrf_signer = CsrfSigner::new(&master_key);
+    let mailer = mailer_from_env().expect("can't configure the mailer");
+    // Where the app is reached, for the links sent by email
+    let app_url = dotenv::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let app_url = app_url.trim_end_matches('/').to_string();
+    // Who can sign up
+    let registration_policy =
+        RegistrationPolicy::from_env().expect("can't configure the registration policy");
+    // Optional single sign-on with an OpenID Connect provider
+    let oidc = OidcConfig::from_env(&app_url).map(OidcClient::new);
+    let encrypted = SettingsRepository { pool: pool.clone() }
+        .encrypt_plaintext_keys(&key_cipher)
+        .await
+        .unwrap();
+    if encrypted > 0 {
+        tracing::info!("encrypted {} plain text secrets", encrypted);
+    }
+
+    // Failed logins are only kept in memory unless asked otherwise
+    let login_throttle = match dotenv::var("PERSIST_LOGIN_ATTEMPTS").as_deref() {
+        Ok("true") => LoginThrottle::persistent(LoginAttemptRepository { pool: pool.clone() })
+            .await
+            .unwrap(),
+        _ => LoginThrottle::default(),
+    };
+
     let chat_repo = ChatRepository { pool: pool.clone() };
+    let session_repo = SessionRepository { pool: pool.clone() };
+    let two_factor_repo = TwoFactorRepository { pool: pool.clone() };
+    let identity_repo = IdentityRepository { pool: pool.clone() };
+    let api_token_repo = ApiTokenRepository { pool: pool.clone() };
+    let admin_repo = AdminRepository { pool: pool.clone() };
+    let invite_repo = InviteRepository { pool: pool.clone() };
+    let organization_repo = OrganizationRepository { pool: pool.clone() };
+    let account_repo = AccountRepository { pool: pool.clone() };
+
+    // Users made admins at startup and when they log in
+    let admin_emails: Vec<String> = dotenv::var("ADMIN_EMAILS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|email| email.trim().to_string())
+        .filter(|email| !email.is_empty())
+        .collect();
+    let promoted = admin_repo.promote_admins(&admin_emails).await.unwrap();
+    if promoted > 0 {
+        tracing::info!("promoted {} users to admin", promoted);
+    }
 
     let static_files = ServeDir::new("assets");
 
@@ -74,10 +167,41 @@ async fn main() {
         }
     };
 
+    // Optional Ollama compatible server for local models
+    let ollama_url = dotenv::var("OLLAMA_BASE_URL").ok();
+    let allow_private_endpoints = matches!(
+        dotenv::var("ALLOW_PRIVATE_ENDPOINTS").as_deref(),
+        Ok("true")
+    );
+    let model_registry = ModelRegistry::new(pool.clone(), ollama_url.clone(), key_cipher.clone());
+
     let state = AppState {
         pool,
         tera,
         chat_repo,
+        session_repo,
+        two_factor_repo,
+        identity_repo,
+        api_token_repo,
+        admin_repo,
+        invite_repo,
+        organization_repo,
+        account_repo,
+        model_registry,
+        key_cipher,
+        token_signer,
+        csrf_signer,
+        mailer,
+        app_url,
+        admin_emails,
+        registration_policy,
+        login_throttle,
+        reset_throttle: LoginThrottle::default(),
+        pending_logins: PendingLogins::default(),
+        oidc,
+        generations: GenerationRegistry::default(),
+        ollama_url,
+        allow_private_endpoints,
     };
     let shared_app_state = Arc::new(state);
 
@@ -97,6 +221,13 @@ async fn main() {
             shared_app_state.clone(),
             handle_error,
         ))
+        .layer(axum::middleware::from_fn_with_state(
+            shared_app_state.clone(),
+            csrf,
+        ))
+        // API tokens can't be sent by another site, the API skips the CSRF
+        // check and answers with its own JSON errors
+        .merge(api_router(shared_app_state.clone()))
         .layer(axum::middleware::from_fn_with_state(
             shared_app_state.clone(),
             extract_user,
@@ -107,7 +238,7 @@ async fn main() {
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
     tracing::debug!("listening on {}", addr);
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
@@ -118,14 +249,22 @@ pub struct User {
     email: String,
     password: String,
     created_at: NaiveDateTime,
+    email_verified_at: Option<NaiveDateTime>,
+    // `user` or `admin`, see `data::model::Role`
+    role: String,
+    disabled_at: Option<NaiveDateTime>,
+    // Encrypted with the master key, see `ai::stream::api_key`
     openai_api_key: Option<String>,
-}
-
-/// Utility function for mapping any error into a `500 Internal Server Error`
-/// response.
-fn internal_error<E>(err: E) -> (StatusCode, String)
-where
-    E: std::error::Error,
-{
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+    anthropic_api_key: Option<String>,
+    openai_api_key_hint: Option<String>,
+    anthropic_api_key_hint: Option<String>,
+    openai_base_url: Option<String>,
+    openai_api_version: Option<String>,
+    // Encrypted like the API keys, with their names for display
+    openai_extra_headers: Option<String>,
+    openai_extra_header_names: Option<String>,
+    // The organization of the user and its keys, encrypted like theirs
+    organization_id: Option<i64>,
+    organization_openai_api_key: Option<String>,
+    organization_anthropic_api_key: Option<String>,
 }
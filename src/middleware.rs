@@ -1,17 +1,28 @@
 use axum::{
+    body::Body,
     extract::State,
-    http::{HeaderValue, Request, StatusCode},
+    http::{header::CONTENT_TYPE, HeaderValue, Method, Request, StatusCode},
     middleware::Next,
     response::{Html, IntoResponse, Redirect, Response},
     Extension,
 };
 
+use http_body::{LengthLimitError, Limited};
 use tera::Context;
 use tower_cookies::Cookies;
 
 use std::sync::Arc;
 
-use crate::{AppState, User};
+use crate::{
+    ai::stream::ProviderKind,
+    data::model::Role,
+    security::{
+        api_token::{bearer_token, ApiTokenAuth},
+        csrf::{csrf_cookie, form_token, tokens_match, CsrfToken, CSRF_COOKIE, CSRF_HEADER},
+        session::{generate_token, hash_token, SESSION_COOKIE},
+    },
+    AppState, User,
+};
 
 pub fn error_response(code: u16, message: &str) -> Response {
     let to = format!("/error?code={}&message={}", code, message);
@@ -31,34 +42,126 @@ pub async fn extract_user<B>(
 where
     B: Send + 'static,
 {
-    let session = cookies.get("rust-gpt-session");
-
-    let id = session.map_or(-1, |x| x.value().parse::<i64>().unwrap_or(-1));
-
-    // Get the user
-    match sqlx::query_as!(
-        User,
-        "SELECT users.*, settings.openai_api_key FROM users LEFT JOIN settings ON settings.user_id=users.id WHERE users.id = $1",
-        id
-    )
-    .fetch_one(&*state.pool)
-    .await
-    {
-        Ok(current_user) => {
-            // insert the current user into a request extension so the handler can
-            // extract it, and make sure `user` is not used after this point
-            req.extensions_mut().insert(Some(current_user));
-            Ok(next.run(req).await)
+    // Scripts authenticate with an API token, browsers with the session cookie
+    if let Some(token) = bearer_token(req.headers()) {
+        let (current_user, api_token) =
+            match state.api_token_repo.authenticate(&hash_token(token)).await {
+                Ok(Some((user, auth))) => (Some(user), Some(auth)),
+                Ok(None) => (None, None),
+                Err(e) => {
+                    eprintln!("Error authenticating the API token: {:?}", e);
+                    (None, None)
+                }
+            };
+        req.extensions_mut().insert(current_user);
+        req.extensions_mut().insert(api_token);
+        return Ok(next.run(req).await);
+    }
+
+    let token_hash = cookies
+        .get(SESSION_COOKIE)
+        .map(|cookie| hash_token(cookie.value()));
+
+    // Get the user of the session
+    let current_user = match token_hash {
+        Some(token_hash) => match state.session_repo.get_user(&token_hash).await {
+            Ok(Some(current_user)) => {
+                if let Err(e) = state.session_repo.touch_session(&token_hash).await {
+                    eprintln!("Error updating the session: {:?}", e);
+                }
+                Some(current_user)
+            }
+            _ => None,
+        },
+        None => None,
+    };
+
+    // insert the current user into a request extension so the handler can
+    // extract it
+    req.extensions_mut().insert(current_user);
+    req.extensions_mut().insert(None::<ApiTokenAuth>);
+    Ok(next.run(req).await)
+}
+
+// The largest form body read for its CSRF token, the default limit of the
+// axum extractors.
+const MAX_FORM_BODY: usize = 2 * 1024 * 1024;
+
+pub async fn csrf(
+    State(state): State<Arc<AppState>>,
+    cookies: Cookies,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, StatusCode> {
+    // Logged in users get the token of their session, others the token of
+    // their cookie, issued to browsers without one
+    let logged_in = req
+        .extensions()
+        .get::<Option<User>>()
+        .is_some_and(Option::is_some);
+    let token = match (cookies.get(SESSION_COOKIE), cookies.get(CSRF_COOKIE)) {
+        (Some(session), _) if logged_in => state
+            .csrf_signer
+            .session_token(&hash_token(session.value())),
+        (_, Some(cookie)) => cookie.value().to_string(),
+        (_, None) => {
+            let token = generate_token();
+            cookies.add(csrf_cookie(token.clone()));
+            token
         }
+    };
+
+    let mut req = match *req.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => req,
         _ => {
-            req.extensions_mut().insert(None::<User>);
-            Ok(next.run(req).await)
+            let (submitted, req) = submitted_token(req).await?;
+            if !submitted.is_some_and(|submitted| tokens_match(&token, &submitted)) {
+                return Ok(error_response(
+                    403,
+                    "Your form expired, reload the page and try again",
+                ));
+            }
+            req
         }
+    };
+
+    req.extensions_mut().insert(CsrfToken(token));
+    Ok(next.run(req).await)
+}
+
+// htmx sends the token as a header, plain forms as a field of the body which
+// has to be buffered and put back for the handler.
+async fn submitted_token(
+    req: Request<Body>,
+) -> Result<(Option<String>, Request<Body>), StatusCode> {
+    if let Some(header) = req.headers().get(CSRF_HEADER) {
+        let submitted = header.to_str().ok().map(str::to_string);
+        return Ok((submitted, req));
+    }
+
+    let is_form = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok((None, req));
     }
+
+    let (parts, body) = req.into_parts();
+    let bytes = hyper::body::to_bytes(Limited::new(body, MAX_FORM_BODY))
+        .await
+        .map_err(|e| match e.downcast_ref::<LengthLimitError>() {
+            Some(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            None => StatusCode::BAD_REQUEST,
+        })?;
+    let submitted = form_token(&bytes);
+    Ok((submitted, Request::from_parts(parts, Body::from(bytes))))
 }
 
 pub async fn auth<B>(
     Extension(current_user): Extension<Option<User>>,
+    Extension(api_token): Extension<Option<ApiTokenAuth>>,
     req: Request<B>,
     next: Next<B>,
 ) -> Response
@@ -71,46 +174,66 @@ where
     let h = r.headers_mut();
     h.insert("HX-Redirect", HeaderValue::from_str(&to).unwrap());
 
+    // API tokens are only for the JSON API
     match current_user {
-        Some(_user) => next.run(req).await,
+        Some(_user) if api_token.is_none() => next.run(req).await,
         _ => error_response(401, "You need to log in to view this page"),
     }
 }
 
-pub async fn valid_openai_api_key<B>(
+/// Only lets admins logged in with a session through.
+pub async fn admin<B>(
     Extension(current_user): Extension<Option<User>>,
+    Extension(api_token): Extension<Option<ApiTokenAuth>>,
     req: Request<B>,
     next: Next<B>,
 ) -> Response
 where
     B: Send + 'static,
 {
-    let key = current_user
-        .unwrap()
-        .openai_api_key
-        .unwrap_or(String::new());
-
-    let client = reqwest::Client::new();
-    match client
-        .get("https://api.openai.com/v1/engines")
-        .bearer_auth(&key)
-        .send()
-        .await
-    {
-        Ok(res) => {
-            if res.status().is_success() {
-                next.run(req).await
-            } else {
-                println!("failure!");
-                error_response(403, "You API key is not set or invalid. Go to Settings.")
-            }
+    match current_user {
+        Some(user) if api_token.is_none() && user.role == Role::Admin.as_str() => {
+            next.run(req).await
+        }
+        Some(_) if api_token.is_none() => {
+            error_response(403, "You need to be an admin to view this page")
         }
-        Err(_) => error_response(403, "You API key is not set or invalid. Go to Settings"),
+        _ => error_response(401, "You need to log in to view this page"),
+    }
+}
+
+pub async fn valid_openai_api_key<B>(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response
+where
+    B: Send + 'static,
+{
+    // Local models and Anthropic don't need an OpenAI key, chats on OpenAI
+    // models are still checked when generating.
+    let has_anthropic_key = current_user.as_ref().is_some_and(|user| {
+        user.anthropic_api_key.is_some() || user.organization_anthropic_api_key.is_some()
+    });
+    if state.ollama_url.is_some() || has_anthropic_key {
+        return next.run(req).await;
+    }
+
+    let valid = state
+        .model_registry
+        .verify(current_user.as_ref().unwrap(), ProviderKind::OpenAi)
+        .await;
+    if valid {
+        next.run(req).await
+    } else {
+        error_response(403, "You API key is not set or invalid. Go to Settings.")
     }
 }
 
 pub async fn handle_error<B>(
     Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
     State(state): State<Arc<AppState>>,
     req: Request<B>,
     next: Next<B>,
@@ -134,6 +257,7 @@ where
             let mut context = Context::new();
             context.insert("view", &error);
             context.insert("current_user", &current_user);
+            context.insert("csrf_token", &csrf_token);
             context.insert("with_footer", &true);
             let rendered = state.tera.render("views/main.html", &context).unwrap();
             let h = Html(rendered).into_response();
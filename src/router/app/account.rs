@@ -0,0 +1,138 @@
+use axum::{
+    extract::{ConnectInfo, Extension, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Form,
+};
+
+use serde::Deserialize;
+use tera::Context;
+use tower_cookies::Cookies;
+
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    security::{
+        csrf::CsrfToken,
+        password::{verify_password, PasswordCheck},
+    },
+    AppState, User,
+};
+
+use super::{
+    auth::{clear_session_cookies, render_notice, LogInError, Notice},
+    settings::render_settings,
+    two_factor::verify_second_factor,
+};
+
+const ACCOUNT_DELETED: Notice = Notice {
+    title: "Account deleted",
+    message: "Your account and all your chats were deleted.",
+    link: "/",
+    link_text: "Back to RustGPT",
+};
+
+#[axum::debug_handler]
+pub async fn settings_export(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response, StatusCode> {
+    let id = current_user.unwrap().id;
+    let export = state
+        .account_repo
+        .export(id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let body =
+        serde_json::to_string_pretty(&export).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let disposition = format!(
+        "attachment; filename=\"rustgpt-data-{}.json\"",
+        export.exported_at.format("%Y-%m-%d")
+    );
+    Ok((
+        [
+            (CONTENT_TYPE, "application/json".to_string()),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteAccount {
+    password: String,
+    // Only asked with two-factor authentication enabled
+    code: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn settings_delete_account(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cookies: Cookies,
+    Form(delete_account): Form<DeleteAccount>,
+) -> Result<Response, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    let ip = addr.ip().to_string();
+
+    // The password is checked again, throttled like logins
+    let error = match state.login_throttle.check(&ip, &user.email) {
+        Err(retry_after) => Some(LogInError::TooManyAttempts(retry_after).to_string()),
+        Ok(()) => match verify_password(&delete_account.password, &user.password) {
+            PasswordCheck::Invalid => {
+                state.login_throttle.record_failure(&ip, &user.email).await;
+                Some("Wrong password.".to_string())
+            }
+            PasswordCheck::Valid | PasswordCheck::ValidLegacy => None,
+        },
+    };
+    let error = match error {
+        None => second_factor_error(&state, user, delete_account.code.as_deref()).await?,
+        error => error,
+    };
+    if let Some(error) = error {
+        let mut context = Context::new();
+        context.insert("delete_account_error", &error);
+        let rendered = render_settings(&state, user, &cookies, &csrf_token, context).await?;
+        return Ok(rendered.into_response());
+    }
+
+    state
+        .account_repo
+        .delete_account(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let csrf_token = clear_session_cookies(&cookies);
+    Ok(render_notice(&state, &csrf_token, &ACCOUNT_DELETED).into_response())
+}
+
+// The error of the second factor of the deletion, if the user has one.
+async fn second_factor_error(
+    state: &AppState,
+    user: &User,
+    code: Option<&str>,
+) -> Result<Option<String>, StatusCode> {
+    let enabled = state
+        .two_factor_repo
+        .is_enabled(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !enabled {
+        return Ok(None);
+    }
+
+    let code = code.map(str::trim).unwrap_or_default();
+    let valid = !code.is_empty()
+        && verify_second_factor(state, user.id, code)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((!valid).then(|| LogInError::InvalidCode.to_string()))
+}
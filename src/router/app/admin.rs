@@ -0,0 +1,232 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
+    Form,
+};
+
+use serde::Deserialize;
+use tera::Context;
+
+use std::sync::Arc;
+
+use crate::{
+    data::model::Role,
+    security::{
+        csrf::CsrfToken,
+        password::hash_password,
+        registration::{generate_invite_code, INVITE_LIFETIMES},
+        session::{generate_token, hash_token},
+    },
+    AppState, User,
+};
+
+#[axum::debug_handler]
+pub async fn admin_users(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+) -> Result<Html<String>, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    render_admin(&state, user, &csrf_token, Context::new()).await
+}
+
+/// The user management page, `context` can hold the outcome of an action
+/// (`admin_error`, `temporary_password`, `new_invite_link`).
+async fn render_admin(
+    state: &AppState,
+    user: &User,
+    csrf_token: &CsrfToken,
+    mut context: Context,
+) -> Result<Html<String>, StatusCode> {
+    let users = state
+        .admin_repo
+        .list_users()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let organizations = state
+        .organization_repo
+        .get_organizations()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let invites = state
+        .invite_repo
+        .get_invites()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    context.insert("users", &users);
+    context.insert("organizations", &organizations);
+    context.insert("invites", &invites);
+    context.insert("registration_policy", state.registration_policy.as_str());
+    context.insert("current_user_id", &user.id);
+    let admin = state.tera.render("views/admin.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &admin);
+    context.insert("current_user", user);
+    context.insert("csrf_token", csrf_token);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+// The error of an action on the admin's own account, which would lock them
+// out of the console.
+fn own_account_error(user: &User, user_id: i64) -> Option<Context> {
+    if user.id != user_id {
+        return None;
+    }
+    let mut context = Context::new();
+    context.insert("admin_error", "You can't do this to your own account.");
+    Some(context)
+}
+
+#[axum::debug_handler]
+pub async fn admin_disable_user(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Path(user_id): Path<i64>,
+) -> Result<Response, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    if let Some(context) = own_account_error(user, user_id) {
+        return Ok(render_admin(&state, user, &csrf_token, context)
+            .await?
+            .into_response());
+    }
+    state
+        .admin_repo
+        .set_disabled(user_id, true)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/admin").into_response())
+}
+
+#[axum::debug_handler]
+pub async fn admin_enable_user(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    state
+        .admin_repo
+        .set_disabled(user_id, false)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/admin"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetRole {
+    role: String,
+}
+
+#[axum::debug_handler]
+pub async fn admin_set_role(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Path(user_id): Path<i64>,
+    Form(set_role): Form<SetRole>,
+) -> Result<Response, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    if let Some(context) = own_account_error(user, user_id) {
+        return Ok(render_admin(&state, user, &csrf_token, context)
+            .await?
+            .into_response());
+    }
+    let role = match set_role.role.as_str() {
+        "admin" => Role::Admin,
+        "user" => Role::User,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    state
+        .admin_repo
+        .set_role(user_id, role)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/admin").into_response())
+}
+
+#[axum::debug_handler]
+pub async fn admin_reset_password(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Path(user_id): Path<i64>,
+) -> Result<Html<String>, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    if let Some(context) = own_account_error(user, user_id) {
+        return render_admin(&state, user, &csrf_token, context).await;
+    }
+    // A random password shown once, for the admin to hand over
+    let password = generate_token()[..20].to_string();
+    let hash = hash_password(&password).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let updated = state
+        .admin_repo
+        .reset_password(user_id, &hash)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if updated == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let mut context = Context::new();
+    context.insert("temporary_password", &password);
+    context.insert("temporary_password_user_id", &user_id);
+    render_admin(&state, user, &csrf_token, context).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewInvite {
+    // Days, empty for a code which doesn't expire
+    expires_in: String,
+}
+
+#[axum::debug_handler]
+pub async fn admin_create_invite(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Form(new_invite): Form<NewInvite>,
+) -> Result<Html<String>, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    let days = new_invite.expires_in.parse::<i64>().ok();
+    if !INVITE_LIFETIMES.contains(&days) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // Only shown now, the database keeps its hash
+    let code = generate_invite_code();
+    state
+        .invite_repo
+        .create_invite(user.id, &hash_token(&code), days)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut context = Context::new();
+    context.insert(
+        "new_invite_link",
+        &format!("{}/signup?invite={}", state.app_url, code),
+    );
+    render_admin(&state, user, &csrf_token, context).await
+}
+
+#[axum::debug_handler]
+pub async fn admin_revoke_invite(
+    State(state): State<Arc<AppState>>,
+    Path(invite_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    state
+        .invite_repo
+        .revoke_invite(invite_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/admin"))
+}
@@ -0,0 +1,314 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tokio::sync::broadcast::error::RecvError;
+
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
+use crate::{
+    ai::stream::{GenerationEvent, ProviderKind},
+    data::model::{Chat, ChatMessagePair, PairStatus},
+    security::api_token::{ApiScope, ApiTokenAuth},
+    AppState, User,
+};
+
+use super::chat::{pending_position, start_generation, ChatError, QUOTA_EXCEEDED};
+
+/// Errors of the JSON API, as `{"error": "..."}`.
+pub enum ApiError {
+    Unauthorized,
+    MissingScope(ApiScope),
+    NotFound,
+    BadRequest(&'static str),
+    InvalidAPIKey,
+    QuotaExceeded,
+    Internal,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                "Missing, invalid or expired API token".to_string(),
+            ),
+            ApiError::MissingScope(scope) => (
+                StatusCode::FORBIDDEN,
+                format!("The API token doesn't have the {} scope", scope),
+            ),
+            ApiError::NotFound => (StatusCode::NOT_FOUND, "Chat not found".to_string()),
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message.to_string()),
+            ApiError::InvalidAPIKey => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The API key of the model provider is not set or invalid".to_string(),
+            ),
+            ApiError::QuotaExceeded => (StatusCode::TOO_MANY_REQUESTS, QUOTA_EXCEEDED.to_string()),
+            ApiError::Internal => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal error".to_string(),
+            ),
+        };
+        (status, Json(ErrorBody { error: message })).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl From<ChatError> for ApiError {
+    fn from(e: ChatError) -> Self {
+        match e {
+            ChatError::NotFound => ApiError::NotFound,
+            ChatError::InvalidAPIKey => ApiError::InvalidAPIKey,
+            ChatError::QuotaExceeded => ApiError::QuotaExceeded,
+            ChatError::Other => ApiError::Internal,
+        }
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        ApiError::from(ChatError::from(e))
+    }
+}
+
+/// Rejects requests without a valid API token before the handlers parse
+/// their body, the scopes are checked by each handler.
+pub async fn api_auth<B>(
+    Extension(current_user): Extension<Option<User>>,
+    Extension(api_token): Extension<Option<ApiTokenAuth>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response
+where
+    B: Send + 'static,
+{
+    match (current_user, api_token) {
+        (Some(_), Some(_)) => next.run(req).await,
+        _ => ApiError::Unauthorized.into_response(),
+    }
+}
+
+// The user of the API token of the request, if it has the scope. Session
+// cookies aren't accepted, so the API needs no CSRF token.
+fn authorize(
+    current_user: Option<User>,
+    api_token: Option<ApiTokenAuth>,
+    scope: ApiScope,
+) -> Result<User, ApiError> {
+    match (current_user, api_token) {
+        (Some(user), Some(api_token)) if api_token.allows(scope) => Ok(user),
+        (Some(_), Some(_)) => Err(ApiError::MissingScope(scope)),
+        _ => Err(ApiError::Unauthorized),
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiChat {
+    id: i64,
+    name: String,
+    provider: String,
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    messages: Option<Vec<ApiMessage>>,
+}
+
+impl ApiChat {
+    fn new(chat: Chat, pairs: Option<Vec<ChatMessagePair>>) -> Self {
+        Self {
+            id: chat.id,
+            name: chat.name,
+            provider: chat.provider,
+            model: chat.model,
+            messages: pairs.map(|pairs| pairs.into_iter().map(ApiMessage::from).collect()),
+        }
+    }
+}
+
+/// A message and its answer, `status` is `pending`, `streaming`, `complete`
+/// or `failed`.
+#[derive(Serialize)]
+pub struct ApiMessage {
+    id: i64,
+    human_message: String,
+    ai_message: Option<String>,
+    status: String,
+}
+
+impl From<ChatMessagePair> for ApiMessage {
+    fn from(pair: ChatMessagePair) -> Self {
+        Self {
+            id: pair.id,
+            human_message: pair.human_message,
+            ai_message: pair.ai_message,
+            status: pair.status,
+        }
+    }
+}
+
+#[axum::debug_handler]
+pub async fn api_chats(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(api_token): Extension<Option<ApiTokenAuth>>,
+) -> Result<Json<Vec<ApiChat>>, ApiError> {
+    let user = authorize(current_user, api_token, ApiScope::ChatsRead)?;
+    let chats = state.chat_repo.get_all_chats(user.id).await?;
+
+    Ok(Json(
+        chats
+            .into_iter()
+            .map(|chat| ApiChat::new(chat, None))
+            .collect(),
+    ))
+}
+
+#[axum::debug_handler]
+pub async fn api_chat_by_id(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(api_token): Extension<Option<ApiTokenAuth>>,
+) -> Result<Json<ApiChat>, ApiError> {
+    let user = authorize(current_user, api_token, ApiScope::ChatsRead)?;
+    let chat = state.chat_repo.get_chat(user.id, chat_id).await?;
+    let pairs = state.chat_repo.retrieve_chat(user.id, chat_id).await?;
+
+    Ok(Json(ApiChat::new(chat, Some(pairs))))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApiNewChat {
+    message: String,
+    /// `<provider>:<model>`, as in the model picker.
+    model: String,
+}
+
+#[axum::debug_handler]
+pub async fn api_new_chat(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(api_token): Extension<Option<ApiTokenAuth>>,
+    Json(new_chat): Json<ApiNewChat>,
+) -> Result<(StatusCode, Json<ApiChat>), ApiError> {
+    let user = authorize(current_user, api_token, ApiScope::ChatsWrite)?;
+
+    let (provider, model) = new_chat
+        .model
+        .split_once(':')
+        .and_then(|(provider, model)| Some((provider.parse::<ProviderKind>().ok()?, model)))
+        .ok_or(ApiError::BadRequest("model must be <provider>:<model>"))?;
+    // The models the picker offers to the user
+    let models = state.model_registry.models_for(&user).await?;
+    if !models
+        .iter()
+        .any(|m| m.provider == provider && m.id == model)
+    {
+        return Err(ApiError::BadRequest("unknown model"));
+    }
+
+    let chat_id = state
+        .chat_repo
+        .create_chat(user.id, &new_chat.message, provider.as_str(), model)
+        .await?;
+    let answer = match state
+        .chat_repo
+        .add_message_block(user.id, chat_id, &new_chat.message)
+        .await
+    {
+        Ok(_) => generate_answer(&state, &user, chat_id).await,
+        Err(e) => Err(e.into()),
+    };
+    // A chat without an answer isn't kept, the request can be retried
+    if let Err(error) = answer {
+        state.chat_repo.delete_chat(user.id, chat_id).await?;
+        return Err(error);
+    }
+
+    let chat = state.chat_repo.get_chat(user.id, chat_id).await?;
+    let pairs = state.chat_repo.retrieve_chat(user.id, chat_id).await?;
+    Ok((StatusCode::CREATED, Json(ApiChat::new(chat, Some(pairs)))))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApiAddMessage {
+    message: String,
+}
+
+#[axum::debug_handler]
+pub async fn api_add_message(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(api_token): Extension<Option<ApiTokenAuth>>,
+    Json(add_message): Json<ApiAddMessage>,
+) -> Result<(StatusCode, Json<ApiMessage>), ApiError> {
+    let user = authorize(current_user, api_token, ApiScope::ChatsWrite)?;
+    let pairs = state.chat_repo.retrieve_chat(user.id, chat_id).await?;
+    if pending_position(&pairs).is_some() {
+        return Err(ApiError::BadRequest("an answer is still being generated"));
+    }
+
+    state
+        .chat_repo
+        .add_message_block(user.id, chat_id, &add_message.message)
+        .await?;
+    let pair_id = generate_answer(&state, &user, chat_id).await?;
+
+    let pair = state
+        .chat_repo
+        .retrieve_chat(user.id, chat_id)
+        .await?
+        .into_iter()
+        .find(|pair| pair.id == pair_id)
+        .ok_or(ApiError::Internal)?;
+    Ok((StatusCode::CREATED, Json(ApiMessage::from(pair))))
+}
+
+// Generate the answer of the pending pair of the chat and wait for it, the
+// browser follows the same generation when the chat is open. Returns the id
+// of the pair.
+async fn generate_answer(
+    state: &Arc<AppState>,
+    user: &User,
+    chat_id: i64,
+) -> Result<i64, ApiError> {
+    let chat = state.chat_repo.get_chat(user.id, chat_id).await?;
+    let mut pairs = state.chat_repo.retrieve_chat(user.id, chat_id).await?;
+    let pending = pending_position(&pairs).ok_or(ApiError::Internal)?;
+    pairs.truncate(pending + 1);
+    let pair_id = pairs[pending].id;
+
+    // A pair left pending would block the next messages of the chat
+    let generation = match start_generation(state, user, chat, pairs).await {
+        Ok(generation) => generation,
+        Err(error) => {
+            state
+                .chat_repo
+                .save_ai_message(pair_id, "", PairStatus::Failed)
+                .await?;
+            return Err(match error {
+                ChatError::QuotaExceeded => ApiError::QuotaExceeded,
+                error => error.into(),
+            });
+        }
+    };
+    if let Some(mut receiver) = generation.subscribe() {
+        loop {
+            match receiver.recv().await {
+                Ok(GenerationEvent::End) | Err(RecvError::Closed) => break,
+                Ok(GenerationEvent::Text(_)) | Err(RecvError::Lagged(_)) => {}
+            }
+        }
+    }
+
+    Ok(pair_id)
+}
@@ -1,25 +1,58 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{ConnectInfo, Extension, Query, State},
+    http::{header::USER_AGENT, HeaderMap, StatusCode},
     response::{Html, IntoResponse, Redirect, Response},
     Form, Json,
 };
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tera::Context;
-use tower_cookies::{Cookie, Cookies};
+use tower_cookies::{cookie::SameSite, Cookie, Cookies};
 
-use std::sync::Arc;
+use std::{fmt, net::SocketAddr, sync::Arc, time::Duration};
 
-use crate::{AppState, User};
+use crate::{
+    mail::Email,
+    security::{
+        csrf::{csrf_cookie, CsrfToken},
+        password::{hash_password, verify_dummy_password, verify_password, PasswordCheck},
+        registration::RegistrationPolicy,
+        session::{generate_token, hash_token, SESSION_COOKIE, SESSION_DAYS},
+        token::{token_user_id, TokenPurpose, EMAIL_VERIFICATION_TTL, PASSWORD_RESET_TTL},
+        totp::{PENDING_LOGIN_COOKIE, PENDING_LOGIN_TTL},
+    },
+    AppState, User,
+};
+
+use super::two_factor::verify_second_factor;
+
+pub async fn login(
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+) -> Html<String> {
+    render_login(&state, &csrf_token, None, None)
+}
 
-pub async fn login(State(state): State<Arc<AppState>>) -> Html<String> {
+/// The login page, with the email and the error of a failed attempt.
+pub fn render_login(
+    state: &AppState,
+    csrf_token: &CsrfToken,
+    email: Option<&str>,
+    error: Option<&LogInError>,
+) -> Html<String> {
     let mut context = Context::new();
     context.insert("name", "World");
+    context.insert("email", &email);
+    context.insert("error", &error.map(|e| e.to_string()));
+    context.insert(
+        "oidc_name",
+        &state.oidc.as_ref().map(|oidc| &oidc.config.name),
+    );
     let home = state.tera.render("views/login.html", &context).unwrap();
 
     let mut context = Context::new();
     context.insert("view", &home);
+    context.insert("csrf_token", csrf_token);
     let rendered = state.tera.render("views/main.html", &context).unwrap();
 
     Html(rendered)
@@ -28,9 +61,47 @@ pub async fn login(State(state): State<Arc<AppState>>) -> Html<String> {
 #[derive(Debug)]
 pub enum LogInError {
     InvalidCredentials,
+    InvalidCode,
+    TooManyAttempts(Duration),
+    AccountDisabled,
+    EmailNotVerified,
+    SingleSignOn(String),
     DatabaseError(String),
 }
 
+// A wait rounded up to the second, or to the minute past one minute.
+fn format_wait(wait: Duration) -> String {
+    let seconds = wait.as_secs().max(1);
+    let (count, unit) = match seconds {
+        1 => (1, "second"),
+        2..=59 => (seconds, "seconds"),
+        60 => (1, "minute"),
+        _ => ((seconds + 59) / 60, "minutes"),
+    };
+    format!("{} {}", count, unit)
+}
+
+impl fmt::Display for LogInError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogInError::InvalidCredentials => write!(f, "Invalid email or password."),
+            LogInError::InvalidCode => write!(f, "Invalid authentication code."),
+            LogInError::TooManyAttempts(retry_after) => write!(
+                f,
+                "Too many failed attempts, try again in {}.",
+                format_wait(*retry_after)
+            ),
+            LogInError::AccountDisabled => write!(f, "This account is disabled."),
+            LogInError::EmailNotVerified => write!(
+                f,
+                "Verify your email with the link we sent you first, or reset your password to get a new link."
+            ),
+            LogInError::SingleSignOn(message) => write!(f, "Single sign-on failed: {}.", message),
+            LogInError::DatabaseError(message) => write!(f, "{}", message),
+        }
+    }
+}
+
 impl IntoResponse for LogInError {
     fn into_response(self) -> Response {
         match self {
@@ -39,6 +110,18 @@ impl IntoResponse for LogInError {
                 Json("Invalid Username or Password"),
             )
                 .into_response(),
+            LogInError::InvalidCode => {
+                (StatusCode::BAD_REQUEST, Json(self.to_string())).into_response()
+            }
+            LogInError::TooManyAttempts(_) => {
+                (StatusCode::TOO_MANY_REQUESTS, Json(self.to_string())).into_response()
+            }
+            LogInError::AccountDisabled | LogInError::EmailNotVerified => {
+                (StatusCode::FORBIDDEN, Json(self.to_string())).into_response()
+            }
+            LogInError::SingleSignOn(_) => {
+                (StatusCode::BAD_GATEWAY, Json(self.to_string())).into_response()
+            }
             LogInError::DatabaseError(message) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, Json(message)).into_response()
             }
@@ -55,40 +138,323 @@ pub struct LogIn {
 #[axum::debug_handler]
 pub async fn login_form(
     cookies: Cookies,
-    state: State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Form(log_in): Form<LogIn>,
-) -> Result<Redirect, LogInError> {
+) -> Result<Response, LogInError> {
+    let ip = addr.ip().to_string();
+    let result = match state.login_throttle.check(&ip, &log_in.email) {
+        Err(retry_after) => Err(LogInError::TooManyAttempts(retry_after)),
+        Ok(()) => log_in_user(&cookies, &state, &ip, &headers, &log_in).await,
+    };
+
+    // Failed attempts are shown on the login page, with a 200 so the error
+    // handler doesn't replace it
+    match result {
+        Ok(LogInStep::Session) => {
+            state.login_throttle.record_success(&log_in.email).await;
+            Ok(Redirect::to("/").into_response())
+        }
+        Ok(LogInStep::SecondFactor) => Ok(Redirect::to("/login/2fa").into_response()),
+        Err(LogInError::InvalidCredentials) => {
+            state
+                .login_throttle
+                .record_failure(&ip, &log_in.email)
+                .await;
+            let error = LogInError::InvalidCredentials;
+            Ok(
+                render_login(&state, &csrf_token, Some(&log_in.email), Some(&error))
+                    .into_response(),
+            )
+        }
+        Err(
+            error @ (LogInError::TooManyAttempts(_)
+            | LogInError::AccountDisabled
+            | LogInError::EmailNotVerified),
+        ) => Ok(
+            render_login(&state, &csrf_token, Some(&log_in.email), Some(&error)).into_response(),
+        ),
+        Err(error) => Err(error),
+    }
+}
+
+/// Where a login is after checking the password.
+pub enum LogInStep {
+    Session,
+    SecondFactor,
+}
+
+async fn log_in_user(
+    cookies: &Cookies,
+    state: &AppState,
+    ip: &str,
+    headers: &HeaderMap,
+    log_in: &LogIn,
+) -> Result<LogInStep, LogInError> {
     // Verify password
     let user = sqlx::query_as!(
         User,
-        "SELECT users.*, settings.openai_api_key FROM users LEFT JOIN settings ON settings.user_id=users.id WHERE users.email = $1",
+        // the nullability of the id can't be inferred with the email index
+        r#"SELECT users.id AS "id!", users.email, users.password, users.created_at, users.email_verified_at, users.role, users.disabled_at, settings.openai_api_key, settings.anthropic_api_key, settings.openai_api_key_hint, settings.anthropic_api_key_hint, settings.openai_base_url, settings.openai_api_version, settings.openai_extra_headers, settings.openai_extra_header_names, organization_members.organization_id AS "organization_id?", organizations.openai_api_key AS organization_openai_api_key, organizations.anthropic_api_key AS organization_anthropic_api_key FROM users LEFT JOIN settings ON settings.user_id=users.id LEFT JOIN organization_members ON organization_members.user_id = users.id LEFT JOIN organizations ON organizations.id = organization_members.organization_id WHERE users.email = $1"#,
         log_in.email,
     ).fetch_one(&*state.pool).await
-    .map_err(|_| LogInError::InvalidCredentials)?;
+    .map_err(|e| match e {
+        sqlx::Error::RowNotFound => {
+            // Hash anyway, an unknown email shouldn't answer faster
+            verify_dummy_password(&log_in.password);
+            LogInError::InvalidCredentials
+        }
+        e => LogInError::DatabaseError(e.to_string()),
+    })?;
 
-    if user.password != log_in.password {
-        return Err(LogInError::InvalidCredentials);
+    match verify_password(&log_in.password, &user.password) {
+        PasswordCheck::Valid => {}
+        PasswordCheck::ValidLegacy => {
+            // Hash the plain text password stored before hashing
+            let hash = hash_password(&log_in.password)
+                .map_err(|e| LogInError::DatabaseError(e.to_string()))?;
+            sqlx::query!("UPDATE users SET password = ? WHERE id = ?", hash, user.id)
+                .execute(&*state.pool)
+                .await
+                .map_err(|e| LogInError::DatabaseError(e.to_string()))?;
+        }
+        PasswordCheck::Invalid => return Err(LogInError::InvalidCredentials),
     }
 
-    let cookie = Cookie::build("rust-gpt-session", user.id.to_string())
+    complete_login(cookies, state, user.id, &log_in.email, ip, headers).await
+}
+
+/// Log in a user whose first factor was checked: start the session, or the
+/// second step of the login when two-factor authentication is enabled.
+pub async fn complete_login(
+    cookies: &Cookies,
+    state: &AppState,
+    user_id: i64,
+    email: &str,
+    ip: &str,
+    headers: &HeaderMap,
+) -> Result<LogInStep, LogInError> {
+    let database_error = |e: sqlx::Error| LogInError::DatabaseError(e.to_string());
+    let user = sqlx::query!(
+        "SELECT disabled_at, email_verified_at FROM users WHERE id = ?",
+        user_id
+    )
+    .fetch_one(&*state.pool)
+    .await
+    .map_err(database_error)?;
+    if user.disabled_at.is_some() {
+        return Err(LogInError::AccountDisabled);
+    }
+    // Anyone can type an email of an allowed domain
+    if user.email_verified_at.is_none() && state.registration_policy.requires_verified_email() {
+        return Err(LogInError::EmailNotVerified);
+    }
+    // Users listed in `ADMIN_EMAILS` who signed up after the start, once
+    // they verified their email
+    if state.admin_emails.iter().any(|admin| admin == email) {
+        state
+            .admin_repo
+            .promote_admins(&[email.to_string()])
+            .await
+            .map_err(database_error)?;
+    }
+
+    let two_factor = state
+        .two_factor_repo
+        .is_enabled(user_id)
+        .await
+        .map_err(|e| LogInError::DatabaseError(e.to_string()))?;
+    if two_factor {
+        // The session is only started once the code is checked
+        let token = state.pending_logins.start(user_id, email);
+        let cookie = Cookie::build(PENDING_LOGIN_COOKIE, token)
+            .path("/login")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(tower_cookies::cookie::time::Duration::seconds(
+                PENDING_LOGIN_TTL.as_secs() as i64,
+            ))
+            .finish();
+        cookies.add(cookie);
+        return Ok(LogInStep::SecondFactor);
+    }
+
+    start_session(cookies, state, user_id, ip, headers).await?;
+    Ok(LogInStep::Session)
+}
+
+async fn start_session(
+    cookies: &Cookies,
+    state: &AppState,
+    user_id: i64,
+    ip: &str,
+    headers: &HeaderMap,
+) -> Result<(), LogInError> {
+    // Start a session, the cookie only holds its random token
+    let token = generate_token();
+    let user_agent = headers.get(USER_AGENT).and_then(|h| h.to_str().ok());
+    state
+        .session_repo
+        .create_session(
+            user_id,
+            &hash_token(&token),
+            user_agent,
+            Some(ip),
+            SESSION_DAYS,
+        )
+        .await
+        .map_err(|e| LogInError::DatabaseError(e.to_string()))?;
+    if let Err(e) = state.session_repo.delete_expired_sessions().await {
+        eprintln!("Error deleting expired sessions: {:?}", e);
+    }
+
+    let cookie = Cookie::build(SESSION_COOKIE, token)
         // .domain("www.rust-lang.org")
         .path("/")
         // .secure(true)
         .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(tower_cookies::cookie::time::Duration::days(SESSION_DAYS))
         .finish();
     cookies.add(cookie);
+    cookies.add(csrf_cookie(generate_token()));
 
-    Ok(Redirect::to("/"))
+    Ok(())
+}
+
+// The second step of a login, with the error of a wrong code.
+fn render_two_factor(
+    state: &AppState,
+    csrf_token: &CsrfToken,
+    error: Option<&LogInError>,
+) -> Html<String> {
+    let mut context = Context::new();
+    context.insert("error", &error.map(|e| e.to_string()));
+    let two_factor = state
+        .tera
+        .render("views/two-factor.html", &context)
+        .unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &two_factor);
+    context.insert("csrf_token", csrf_token);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Html(rendered)
 }
 
-pub async fn signup(State(state): State<Arc<AppState>>) -> Html<String> {
-    // TODO: Hash password
+pub async fn login_two_factor(
+    cookies: Cookies,
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+) -> Response {
+    let pending = cookies
+        .get(PENDING_LOGIN_COOKIE)
+        .and_then(|cookie| state.pending_logins.get(cookie.value()));
+    match pending {
+        Some(_) => render_two_factor(&state, &csrf_token, None).into_response(),
+        None => Redirect::to("/login").into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TwoFactorCode {
+    code: String,
+}
+
+#[axum::debug_handler]
+pub async fn login_two_factor_form(
+    cookies: Cookies,
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(two_factor_code): Form<TwoFactorCode>,
+) -> Result<Response, LogInError> {
+    let Some(token) = cookies
+        .get(PENDING_LOGIN_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+    else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let Some(pending) = state.pending_logins.get(&token) else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+
+    let ip = addr.ip().to_string();
+    if let Err(retry_after) = state.login_throttle.check(&ip, &pending.email) {
+        let error = LogInError::TooManyAttempts(retry_after);
+        return Ok(render_two_factor(&state, &csrf_token, Some(&error)).into_response());
+    }
+
+    let valid = verify_second_factor(&state, pending.user_id, &two_factor_code.code)
+        .await
+        .map_err(|e| LogInError::DatabaseError(e.to_string()))?;
+    if !valid {
+        state.pending_logins.fail(&token);
+        state
+            .login_throttle
+            .record_failure(&ip, &pending.email)
+            .await;
+        let error = LogInError::InvalidCode;
+        return Ok(render_two_factor(&state, &csrf_token, Some(&error)).into_response());
+    }
+
+    state.pending_logins.finish(&token);
+    let mut cookie = Cookie::build(PENDING_LOGIN_COOKIE, "")
+        .path("/login")
+        .finish();
+    cookie.make_removal();
+    cookies.add(cookie);
+
+    start_session(&cookies, &state, pending.user_id, &ip, &headers).await?;
+    state.login_throttle.record_success(&pending.email).await;
+
+    Ok(Redirect::to("/").into_response())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SignUpQuery {
+    invite: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn signup(
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Query(query): Query<SignUpQuery>,
+) -> Html<String> {
+    render_signup(&state, &csrf_token, None, query.invite.as_deref(), None)
+}
+
+/// The signup page, with the email, the invite code and the error of a
+/// failed attempt.
+fn render_signup(
+    state: &AppState,
+    csrf_token: &CsrfToken,
+    email: Option<&str>,
+    invite: Option<&str>,
+    error: Option<&SignUpError>,
+) -> Html<String> {
+    let policy = &state.registration_policy;
     let mut context = Context::new();
     context.insert("name", "World");
+    context.insert("email", &email);
+    context.insert("invite", &invite);
+    context.insert("error", &error.map(|e| e.to_string()));
+    context.insert("registration_policy", policy.as_str());
+    context.insert("allowed_domains", policy.allowed_domains());
+    context.insert(
+        "oidc_name",
+        &state.oidc.as_ref().map(|oidc| &oidc.config.name),
+    );
     let home = state.tera.render("views/signup.html", &context).unwrap();
 
     let mut context = Context::new();
     context.insert("view", &home);
+    context.insert("csrf_token", &csrf_token);
     let rendered = state.tera.render("views/main.html", &context).unwrap();
 
     Html(rendered)
@@ -97,15 +463,54 @@ pub async fn signup(State(state): State<Arc<AppState>>) -> Html<String> {
 #[derive(Debug)]
 pub enum SignUpError {
     PasswordMismatch,
+    RegistrationClosed,
+    InvalidInvite,
+    DomainNotAllowed,
+    EmailTaken,
     DatabaseError(String),
 }
 
+impl fmt::Display for SignUpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignUpError::PasswordMismatch => write!(f, "Passwords do not match."),
+            SignUpError::RegistrationClosed => write!(f, "Registration is closed."),
+            SignUpError::InvalidInvite => {
+                write!(f, "This invite code is invalid, used or expired.")
+            }
+            SignUpError::DomainNotAllowed => {
+                write!(f, "Signing up with this email domain isn't allowed.")
+            }
+            SignUpError::EmailTaken => write!(f, "An account already uses this email."),
+            SignUpError::DatabaseError(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<sqlx::Error> for SignUpError {
+    fn from(e: sqlx::Error) -> Self {
+        // Emails are unique
+        match e.as_database_error() {
+            Some(e) if e.is_unique_violation() => SignUpError::EmailTaken,
+            _ => SignUpError::DatabaseError(e.to_string()),
+        }
+    }
+}
+
 impl IntoResponse for SignUpError {
     fn into_response(self) -> Response {
         match self {
             SignUpError::PasswordMismatch => {
                 (StatusCode::BAD_REQUEST, Json("Passwords do not match.")).into_response()
             }
+            SignUpError::RegistrationClosed
+            | SignUpError::InvalidInvite
+            | SignUpError::DomainNotAllowed => {
+                (StatusCode::FORBIDDEN, Json(self.to_string())).into_response()
+            }
+            SignUpError::EmailTaken => {
+                (StatusCode::CONFLICT, Json(self.to_string())).into_response()
+            }
             SignUpError::DatabaseError(message) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, Json(message)).into_response()
             }
@@ -118,45 +523,462 @@ pub struct SignUp {
     email: String,
     password: String,
     password_confirmation: String,
+    invite: Option<String>,
 }
 
 #[axum::debug_handler]
 pub async fn form_signup(
-    state: State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
     Form(sign_up): Form<SignUp>,
-) -> Result<Redirect, SignUpError> {
+) -> Result<Response, SignUpError> {
+    // Refused signups are shown on the signup page, with a 200 so the error
+    // handler doesn't replace it
+    match sign_up_user(&state, &sign_up).await {
+        Ok(user_id) => {
+            send_verification_email(&state, user_id, &sign_up.email);
+            if state.registration_policy.requires_verified_email() {
+                return Ok(render_notice(&state, &csrf_token, &CHECK_EMAIL).into_response());
+            }
+            Ok(Redirect::to("/login").into_response())
+        }
+        Err(SignUpError::DatabaseError(message)) => Err(SignUpError::DatabaseError(message)),
+        Err(error) => Ok(render_signup(
+            &state,
+            &csrf_token,
+            Some(&sign_up.email),
+            sign_up.invite.as_deref(),
+            Some(&error),
+        )
+        .into_response()),
+    }
+}
+
+async fn sign_up_user(state: &AppState, sign_up: &SignUp) -> Result<i64, SignUpError> {
+    let policy = &state.registration_policy;
+    if *policy == RegistrationPolicy::Closed {
+        return Err(SignUpError::RegistrationClosed);
+    }
+    if !policy.allows_email(&sign_up.email) {
+        return Err(SignUpError::DomainNotAllowed);
+    }
     if sign_up.password != sign_up.password_confirmation {
         return Err(SignUpError::PasswordMismatch);
     }
 
+    let password =
+        hash_password(&sign_up.password).map_err(|e| SignUpError::DatabaseError(e.to_string()))?;
+
+    if *policy == RegistrationPolicy::Invite {
+        let invite = sign_up.invite.as_deref().unwrap_or_default().trim();
+        return match state
+            .invite_repo
+            .create_user_with_invite(&sign_up.email, &password, &hash_token(invite))
+            .await
+        {
+            Ok(Some(user_id)) => Ok(user_id),
+            Ok(None) => Err(SignUpError::InvalidInvite),
+            Err(e) => Err(SignUpError::from(e)),
+        };
+    }
+
     // insert into db
     match sqlx::query!(
         "INSERT INTO users (email, password) VALUES ($1, $2) RETURNING id",
         sign_up.email,
-        sign_up.password
+        password
     )
     .fetch_one(&*state.pool)
     .await
     {
-        Ok(_) => Ok(Redirect::to("/login")),
-        Err(_e) => {
-            // Handle database error, for example, a unique constraint violation
-            Err(SignUpError::DatabaseError("Dat".to_string()))
-        }
+        Ok(user) => Ok(user.id),
+        Err(e) => Err(SignUpError::from(e)),
     }
 }
 
 #[axum::debug_handler]
-pub async fn logout(cookies: Cookies) -> Result<Redirect, StatusCode> {
-    let mut cookie = Cookie::build("rust-gpt-session", "")
+pub async fn logout(
+    cookies: Cookies,
+    State(state): State<Arc<AppState>>,
+) -> Result<Redirect, StatusCode> {
+    if let Some(session) = cookies.get(SESSION_COOKIE) {
+        state
+            .session_repo
+            .revoke_session(&hash_token(session.value()))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    clear_session_cookies(&cookies);
+
+    Ok(Redirect::to("/"))
+}
+
+/// Remove the session cookie and replace the CSRF token, returns the new
+/// token for a page rendered right away.
+pub(super) fn clear_session_cookies(cookies: &Cookies) -> CsrfToken {
+    let mut cookie = Cookie::build(SESSION_COOKIE, "")
         // .domain("www.rust-lang.org")
         .path("/")
         // .secure(true)
         .http_only(true)
         .finish();
     cookie.make_removal();
-
     cookies.add(cookie);
 
-    Ok(Redirect::to("/"))
+    let token = generate_token();
+    cookies.add(csrf_cookie(token.clone()));
+    CsrfToken(token)
+}
+
+// A page with a short message and a link, after following an email link or
+// deleting an account.
+pub(super) fn render_notice(
+    state: &AppState,
+    csrf_token: &CsrfToken,
+    notice: &Notice,
+) -> Html<String> {
+    let mut context = Context::new();
+    context.insert("notice", notice);
+    let view = state.tera.render("views/notice.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("csrf_token", csrf_token);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Html(rendered)
+}
+
+#[derive(Serialize)]
+pub(super) struct Notice {
+    pub(super) title: &'static str,
+    pub(super) message: &'static str,
+    pub(super) link: &'static str,
+    pub(super) link_text: &'static str,
+}
+
+const CHECK_EMAIL: Notice = Notice {
+    title: "Check your email",
+    message: "We sent you a link to verify your email, you can log in once it is verified.",
+    link: "/login",
+    link_text: "Back to login",
+};
+
+const INVALID_LINK: Notice = Notice {
+    title: "Invalid link",
+    message: "This link is invalid or has expired.",
+    link: "/",
+    link_text: "Back to RustGPT",
+};
+
+// Render an email from `templates/emails` and send it, failures are only
+// logged so they don't tell whether an account exists.
+// Sent in the background: the request doesn't wait for the mail server, and
+// its response time doesn't tell whether an email was sent.
+fn send_email(state: &AppState, to: &str, subject: &str, template: &str, link: &str) {
+    let mut context = Context::new();
+    context.insert("link", link);
+    let body = state.tera.render(template, &context).unwrap();
+
+    let email = Email {
+        to: to.to_string(),
+        subject: subject.to_string(),
+        body,
+    };
+    let mailer = Arc::clone(&state.mailer);
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(email).await {
+            eprintln!("Error sending email: {:?}", e);
+        }
+    });
+}
+
+pub fn send_verification_email(state: &AppState, user_id: i64, email: &str) {
+    let token = state.token_signer.sign(
+        TokenPurpose::EmailVerification,
+        user_id,
+        email,
+        EMAIL_VERIFICATION_TTL,
+    );
+    let link = format!("{}/verify-email?token={}", state.app_url, token);
+    send_email(
+        state,
+        email,
+        "Verify your email address",
+        "emails/verify-email.txt",
+        &link,
+    );
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenParams {
+    token: String,
+}
+
+pub async fn verify_email(
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Query(params): Query<TokenParams>,
+) -> Result<Html<String>, StatusCode> {
+    let user = match token_user_id(&params.token) {
+        Some(user_id) => sqlx::query!("SELECT id, email FROM users WHERE id = ?", user_id)
+            .fetch_optional(&*state.pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        None => None,
+    };
+
+    // The token is bound to the email, it stops working if it changes
+    let Some(user) = user.filter(|user| {
+        state
+            .token_signer
+            .verify(TokenPurpose::EmailVerification, &params.token, &user.email)
+    }) else {
+        return Ok(render_notice(&state, &csrf_token, &INVALID_LINK));
+    };
+
+    sqlx::query!(
+        "UPDATE users SET email_verified_at = COALESCE(email_verified_at, CURRENT_TIMESTAMP) WHERE id = ?",
+        user.id
+    )
+    .execute(&*state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if state.admin_emails.iter().any(|admin| *admin == user.email) {
+        state
+            .admin_repo
+            .promote_admins(&[user.email])
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    let notice = Notice {
+        title: "Email verified",
+        message: "Thank you, your email address is verified.",
+        link: "/",
+        link_text: "Back to RustGPT",
+    };
+    Ok(render_notice(&state, &csrf_token, &notice))
+}
+
+#[axum::debug_handler]
+pub async fn resend_verification_email(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Redirect {
+    let user = current_user.unwrap();
+    if user.email_verified_at.is_none() {
+        send_verification_email(&state, user.id, &user.email);
+    }
+    Redirect::to("/settings")
+}
+
+// The forgot password page, `sent` once the form is submitted.
+fn render_forgot_password(
+    state: &AppState,
+    csrf_token: &CsrfToken,
+    sent: bool,
+    error: Option<&str>,
+) -> Html<String> {
+    let mut context = Context::new();
+    context.insert("sent", &sent);
+    context.insert("error", &error);
+    let view = state
+        .tera
+        .render("views/forgot-password.html", &context)
+        .unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("csrf_token", csrf_token);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Html(rendered)
+}
+
+pub async fn forgot_password(
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+) -> Html<String> {
+    render_forgot_password(&state, &csrf_token, false, None)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ForgotPassword {
+    email: String,
+}
+
+#[axum::debug_handler]
+pub async fn forgot_password_form(
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(forgot_password): Form<ForgotPassword>,
+) -> Result<Html<String>, StatusCode> {
+    // Throttled by ip and by email whether the account exists or not, so
+    // the inbox of a user can't be flooded
+    let ip = addr.ip().to_string();
+    if let Err(retry_after) = state.reset_throttle.check(&ip, &forgot_password.email) {
+        let error = format!(
+            "Too many reset requests, try again in {}.",
+            format_wait(retry_after)
+        );
+        return Ok(render_forgot_password(
+            &state,
+            &csrf_token,
+            false,
+            Some(&error),
+        ));
+    }
+    state
+        .reset_throttle
+        .record_failure(&ip, &forgot_password.email)
+        .await;
+
+    let user = sqlx::query!(
+        r#"SELECT id AS "id!", email, password FROM users WHERE email = ?"#,
+        forgot_password.email
+    )
+    .fetch_optional(&*state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // The same page is shown whether the account exists or not, as fast
+    if let Some(user) = user {
+        // Bound to the password hash, the link works once
+        let token = state.token_signer.sign(
+            TokenPurpose::PasswordReset,
+            user.id,
+            &user.password,
+            PASSWORD_RESET_TTL,
+        );
+        let link = format!("{}/reset-password?token={}", state.app_url, token);
+        send_email(
+            &state,
+            &user.email,
+            "Reset your password",
+            "emails/password-reset.txt",
+            &link,
+        );
+    }
+
+    Ok(render_forgot_password(&state, &csrf_token, true, None))
+}
+
+// The new password form, with the token of the link.
+fn render_reset_password(
+    state: &AppState,
+    csrf_token: &CsrfToken,
+    token: &str,
+    error: Option<&str>,
+) -> Html<String> {
+    let mut context = Context::new();
+    context.insert("token", token);
+    context.insert("error", &error);
+    let view = state
+        .tera
+        .render("views/reset-password.html", &context)
+        .unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("csrf_token", csrf_token);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Html(rendered)
+}
+
+// The id of the user a password reset token is valid for.
+async fn password_reset_user(state: &AppState, token: &str) -> sqlx::Result<Option<i64>> {
+    let Some(user_id) = token_user_id(token) else {
+        return Ok(None);
+    };
+    let user = sqlx::query!("SELECT id, password FROM users WHERE id = ?", user_id)
+        .fetch_optional(&*state.pool)
+        .await?;
+
+    Ok(user
+        .filter(|user| {
+            state
+                .token_signer
+                .verify(TokenPurpose::PasswordReset, token, &user.password)
+        })
+        .map(|user| user.id))
+}
+
+pub async fn reset_password(
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Query(params): Query<TokenParams>,
+) -> Result<Html<String>, StatusCode> {
+    let user_id = password_reset_user(&state, &params.token)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    match user_id {
+        Some(_) => Ok(render_reset_password(
+            &state,
+            &csrf_token,
+            &params.token,
+            None,
+        )),
+        None => Ok(render_notice(&state, &csrf_token, &INVALID_LINK)),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResetPassword {
+    token: String,
+    password: String,
+    password_confirmation: String,
+}
+
+#[axum::debug_handler]
+pub async fn reset_password_form(
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Form(reset_password): Form<ResetPassword>,
+) -> Result<Html<String>, StatusCode> {
+    let user_id = password_reset_user(&state, &reset_password.token)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some(user_id) = user_id else {
+        return Ok(render_notice(&state, &csrf_token, &INVALID_LINK));
+    };
+
+    if reset_password.password != reset_password.password_confirmation {
+        return Ok(render_reset_password(
+            &state,
+            &csrf_token,
+            &reset_password.token,
+            Some("Passwords do not match."),
+        ));
+    }
+
+    let password =
+        hash_password(&reset_password.password).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Following the link proves the email address too
+    sqlx::query!(
+        "UPDATE users SET password = ?, email_verified_at = COALESCE(email_verified_at, CURRENT_TIMESTAMP) WHERE id = ?",
+        password,
+        user_id
+    )
+    .execute(&*state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Whoever knew the old password is logged out
+    state
+        .session_repo
+        .revoke_all_sessions(user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let notice = Notice {
+        title: "Password changed",
+        message: "Your password was changed, you can log in with it.",
+        link: "/login",
+        link_text: "Log in",
+    };
+    Ok(render_notice(&state, &csrf_token, &notice))
 }
@@ -9,7 +9,7 @@ use tokio::fs;
 
 use std::{path::PathBuf, sync::Arc};
 
-use crate::{AppState, User};
+use crate::{security::csrf::CsrfToken, AppState, User};
 
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
@@ -22,6 +22,7 @@ struct BlogArticlePreview {
 pub async fn blog(
     State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
 ) -> Result<Html<String>, StatusCode> {
     // list all directories in ./templates/articles, extract a tuple (dir_name, serde_parsed dir_name/body.json)
     let mut previews: Vec<(String, BlogArticlePreview)> = Vec::new();
@@ -70,6 +71,7 @@ pub async fn blog(
     let mut context = Context::new();
     context.insert("view", &home);
     context.insert("current_user", &current_user);
+    context.insert("csrf_token", &csrf_token);
     context.insert("with_footer", &true);
     let rendered = state.tera.render("views/main.html", &context).unwrap();
 
@@ -81,6 +83,7 @@ pub async fn blog_by_slug(
     Path(slug): Path<String>,
     State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
 ) -> Result<Html<String>, StatusCode> {
     let template = format!("articles/{}/body.md", slug);
 
@@ -101,6 +104,7 @@ pub async fn blog_by_slug(
             let mut context = Context::new();
             context.insert("view", &blog);
             context.insert("current_user", &current_user);
+            context.insert("csrf_token", &csrf_token);
             context.insert("with_footer", &true);
             let rendered = state.tera.render("views/main.html", &context).unwrap();
 
@@ -4,26 +4,43 @@ use axum::{
     response::{sse::Event, Html, IntoResponse, Response, Sse},
     Form, Json,
 };
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast::error::RecvError, mpsc};
 
-use futures::stream::{self};
+use futures::stream::{self, BoxStream, StreamExt};
 use serde::{Deserialize, Serialize};
 use tera::Context;
-use tokio_stream::wrappers::ReceiverStream; // This brings the necessary stream combinators into scope
 
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    ai::stream::{generate_sse_stream, list_engines, GenerationEvent},
-    data::model::ChatMessagePair,
+    ai::{
+        generation::Generation,
+        stream::{
+            chat_messages, estimate_tokens, key_owner, GenerationEvent, KeyOwner, LlmProvider,
+            ProviderKind,
+        },
+    },
+    data::model::{Chat, ChatMessagePair, PairStatus},
+    security::csrf::CsrfToken,
     AppState, User,
 };
 
-use tokio_stream::StreamExt as TokioStreamExt;
+// How often the AI message is saved while it streams.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub const QUOTA_EXCEEDED: &str =
+    "Your monthly quota for the API keys of your organization is used up.";
+
+type EventStream = BoxStream<'static, Result<Event, axum::Error>>;
 
 pub enum ChatError {
     Other,
     InvalidAPIKey,
+    NotFound,
+    QuotaExceeded,
 }
 // Implement Display for ChatError to provide user-facing error messages.
 
@@ -34,44 +51,48 @@ impl IntoResponse for ChatError {
             ChatError::InvalidAPIKey => {
                 (StatusCode::UNAUTHORIZED, Json("Chat Errror")).into_response()
             }
+            ChatError::NotFound => (StatusCode::NOT_FOUND, Json("Chat not found")).into_response(),
+            ChatError::QuotaExceeded => {
+                (StatusCode::TOO_MANY_REQUESTS, Json(QUOTA_EXCEEDED)).into_response()
+            }
         }
     }
 }
 
-const MODELS: [(&str, &str, &str); 4] = [
-    (
-        "GPT-4-Preview",
-        "gpt-4-1106-preview",
-        "This is the preview version of the GPT-4 model.",
-    ),
-    ("GPT-4", "gpt-4", "Latest generation GPT-4 model."),
-    (
-        "GPT-3.5-16K",
-        "gpt-3.5-turbo-16k",
-        "An enhanced GPT-3.5 model with 16K token limit.",
-    ),
-    (
-        "GPT-3.5",
-        "gpt-3.5-turbo",
-        "Standard GPT-3.5 model with turbo features.",
-    ),
-];
+// Chats of other users are reported as missing.
+impl From<sqlx::Error> for ChatError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => ChatError::NotFound,
+            _ => ChatError::Other,
+        }
+    }
+}
 
 #[axum::debug_handler]
 pub async fn chat(
     State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
-) -> Html<String> {
+    Extension(csrf_token): Extension<CsrfToken>,
+) -> Result<Html<String>, ChatError> {
     let user_chats = state
         .chat_repo
         .get_all_chats(current_user.as_ref().unwrap().id)
-        .await
-        .unwrap();
-
-    let selected_model = MODELS.iter().filter(|f| f.1 == "gpt-4").collect::<Vec<_>>()[0];
+        .await?;
+
+    // A failed provider listing falls back to the known models, only the
+    // database can fail here
+    let models = state
+        .model_registry
+        .models_for(current_user.as_ref().unwrap())
+        .await?;
+    let selected_model = models
+        .iter()
+        .find(|m| m.provider == ProviderKind::OpenAi && m.id == "gpt-4")
+        .or(models.first());
 
     let mut context = Context::new();
-    context.insert("models", &MODELS);
+    context.insert("models", &models);
     context.insert("selected_model", &selected_model);
     context.insert("user_chats", &user_chats);
     let home = state.tera.render("views/chat.html", &context).unwrap();
@@ -79,9 +100,10 @@ pub async fn chat(
     let mut context = Context::new();
     context.insert("view", &home);
     context.insert("current_user", &current_user);
+    context.insert("csrf_token", &csrf_token);
     let rendered = state.tera.render("views/main.html", &context).unwrap();
 
-    Html(rendered)
+    Ok(Html(rendered))
 }
 
 #[derive(Deserialize, Debug)]
@@ -98,17 +120,23 @@ pub async fn new_chat(
 ) -> Result<Response<String>, ChatError> {
     let current_user = current_user.unwrap();
 
+    // The model picker submits `<provider>:<model>`, model ids may contain colons.
+    let (provider, model) = new_chat
+        .model
+        .split_once(':')
+        .and_then(|(provider, model)| Some((provider.parse::<ProviderKind>().ok()?, model)))
+        .ok_or(ChatError::Other)?;
+
     let chat_id = state
         .chat_repo
-        .create_chat(current_user.id, &new_chat.message, &new_chat.model)
+        .create_chat(current_user.id, &new_chat.message, provider.as_str(), model)
         .await
         .map_err(|_| ChatError::Other)?;
 
     state
         .chat_repo
-        .add_message_block(chat_id, &new_chat.message)
-        .await
-        .map_err(|_| ChatError::Other)?;
+        .add_message_block(current_user.id, chat_id, &new_chat.message)
+        .await?;
 
     Ok(Response::builder()
         .status(StatusCode::OK)
@@ -124,30 +152,8 @@ struct ParsedMessagePair {
     ai_message_html: String,
 }
 
-#[axum::debug_handler]
-pub async fn chat_by_id(
-    Path(chat_id): Path<i64>,
-    State(state): State<Arc<AppState>>,
-    Extension(current_user): Extension<Option<User>>,
-) -> Result<Html<String>, ChatError> {
-    let chat_message_pairs = state
-        .chat_repo
-        .retrieve_chat(chat_id)
-        .await
-        .map_err(|_| ChatError::Other)?;
-
-    let user_chats = state
-        .chat_repo
-        .get_all_chats(current_user.as_ref().unwrap().id)
-        .await
-        .unwrap();
-
-    let selected_model = MODELS
-        .iter()
-        .filter(|f| f.1 == chat_message_pairs[0].model)
-        .collect::<Vec<_>>()[0];
-
-    let parsed_pairs = chat_message_pairs
+fn parse_pairs(pairs: &[ChatMessagePair]) -> Vec<ParsedMessagePair> {
+    pairs
         .iter()
         .map(|pair| {
             let human_message_html =
@@ -162,7 +168,52 @@ pub async fn chat_by_id(
                 ai_message_html,
             }
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+/// Render the messages of the chat, for htmx to swap into `#chat-messages`.
+async fn render_chat_messages(
+    state: &AppState,
+    user_id: i64,
+    chat_id: i64,
+) -> Result<Html<String>, ChatError> {
+    let chat_message_pairs = state.chat_repo.retrieve_chat(user_id, chat_id).await?;
+
+    let mut context = Context::new();
+    context.insert("chat_message_pairs", &parse_pairs(&chat_message_pairs));
+    context.insert("chat_id", &chat_id);
+    let update = state
+        .tera
+        .render("components/chat-messages.html", &context)
+        .unwrap();
+
+    Ok(Html(update))
+}
+
+#[axum::debug_handler]
+pub async fn chat_by_id(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+) -> Result<Html<String>, ChatError> {
+    let user_id = current_user.as_ref().unwrap().id;
+    let chat = state.chat_repo.get_chat(user_id, chat_id).await?;
+    let chat_message_pairs = state.chat_repo.retrieve_chat(user_id, chat_id).await?;
+
+    let user_chats = state.chat_repo.get_all_chats(user_id).await?;
+
+    let provider = chat
+        .provider
+        .parse::<ProviderKind>()
+        .map_err(|_| ChatError::Other)?;
+    let selected_model = state
+        .model_registry
+        .find(provider, &chat.model)
+        .await
+        .map_err(|_| ChatError::Other)?;
+
+    let parsed_pairs = parse_pairs(&chat_message_pairs);
 
     let mut context = Context::new();
     context.insert("name", "World");
@@ -176,6 +227,7 @@ pub async fn chat_by_id(
     let mut context = Context::new();
     context.insert("view", &home);
     context.insert("current_user", &current_user);
+    context.insert("csrf_token", &csrf_token);
     let rendered = state.tera.render("views/main.html", &context).unwrap();
 
     Ok(Html(rendered))
@@ -190,15 +242,14 @@ pub struct ChatAddMessage {
 pub async fn chat_add_message(
     Path(chat_id): Path<i64>,
     State(state): State<Arc<AppState>>,
-    Extension(_current_user): Extension<Option<User>>,
+    Extension(current_user): Extension<Option<User>>,
     Form(chat_add_message): Form<ChatAddMessage>,
 ) -> Result<Html<String>, ChatError> {
     let message = chat_add_message.message;
     state
         .chat_repo
-        .add_message_block(chat_id, &message)
-        .await
-        .map_err(|_| ChatError::Other)?;
+        .add_message_block(current_user.unwrap().id, chat_id, &message)
+        .await?;
 
     let mut context = Context::new();
     context.insert("human_message", &message);
@@ -215,105 +266,345 @@ pub async fn chat_generate(
     Extension(current_user): Extension<Option<User>>,
     Path(chat_id): Path<i64>,
     State(state): State<Arc<AppState>>,
-) -> Result<Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>>, ChatError> {
-    let chat_message_pairs = state.chat_repo.retrieve_chat(chat_id).await.unwrap();
-    let key = current_user
-        .unwrap()
-        .openai_api_key
-        .unwrap_or(String::new());
-
-    match list_engines(&key).await {
-        Ok(_res) => {}
-        Err(_) => {
-            return Err(ChatError::InvalidAPIKey);
+) -> Result<Sse<EventStream>, ChatError> {
+    let user_id = current_user.as_ref().unwrap().id;
+    let chat = state.chat_repo.get_chat(user_id, chat_id).await?;
+    let mut chat_message_pairs = state.chat_repo.retrieve_chat(user_id, chat_id).await?;
+
+    // Generate the answer of the first pair still waiting for one, with the
+    // conversation up to it as context.
+    let pending = pending_position(&chat_message_pairs).ok_or(ChatError::Other)?;
+    chat_message_pairs.truncate(pending + 1);
+    let pending_pair = chat_message_pairs[pending].clone();
+
+    // A reload while streaming follows the running generation
+    if let Some(generation) = state
+        .generations
+        .get(chat_id)
+        .filter(|generation| generation.pair_id == pending_pair.id)
+    {
+        return Ok(Sse::new(generation_events(generation)));
+    }
+
+    // Streaming but not running: the generation was lost with the server
+    if pending_pair.status == PairStatus::Streaming.as_str() {
+        state
+            .chat_repo
+            .fail_streaming_pair(pending_pair.id)
+            .await
+            .map_err(|_| ChatError::Other)?;
+        let text = pending_pair.ai_message.unwrap_or_default();
+        return Ok(Sse::new(
+            stream::once(async move { Ok(end_event(&text)) }).boxed(),
+        ));
+    }
+
+    let generation = match start_generation(
+        &state,
+        current_user.as_ref().unwrap(),
+        chat,
+        chat_message_pairs,
+    )
+    .await
+    {
+        // Shown in place of the answer, which can be regenerated next month
+        Err(ChatError::QuotaExceeded) => {
+            save_ai_message(&state, pending_pair.id, "", PairStatus::Failed).await;
+            return Ok(Sse::new(
+                stream::once(async move { Ok(end_event(QUOTA_EXCEEDED)) }).boxed(),
+            ));
         }
+        result => result?,
     };
 
-    let lat_message_id = chat_message_pairs.last().unwrap().id;
+    Ok(Sse::new(generation_events(generation)))
+}
 
-    // Create a channel for sending SSE events
-    let (sender, receiver) = mpsc::channel::<Result<GenerationEvent, axum::Error>>(10);
+/// Where the first pair still waiting for its answer is.
+pub fn pending_position(chat_message_pairs: &[ChatMessagePair]) -> Option<usize> {
+    chat_message_pairs.iter().position(|pair| {
+        pair.status == PairStatus::Pending.as_str() || pair.status == PairStatus::Streaming.as_str()
+    })
+}
 
-    // Spawn a task that generates SSE events and sends them into the channel
-    tokio::spawn(async move {
-        // Call your existing function to start generating events
-        if let Err(e) = generate_sse_stream(
-            &key,
-            &chat_message_pairs[0].model.clone(),
-            chat_message_pairs,
-            sender,
-        )
-        .await
-        {
-            eprintln!("Error generating SSE stream: {:?}", e);
+/// Start the generation of the last pair, with the pairs before it as
+/// context, or attach to the generation already running for it. With the
+/// organization keys, the monthly quotas of the user are checked first.
+pub async fn start_generation(
+    state: &Arc<AppState>,
+    user: &User,
+    chat: Chat,
+    chat_message_pairs: Vec<ChatMessagePair>,
+) -> Result<Arc<Generation>, ChatError> {
+    let pair_id = chat_message_pairs.last().ok_or(ChatError::Other)?.id;
+    let provider_kind = chat
+        .provider
+        .parse::<ProviderKind>()
+        .map_err(|_| ChatError::Other)?;
+    let provider = state
+        .model_registry
+        .provider(user, provider_kind)
+        .ok_or(ChatError::InvalidAPIKey)?;
+
+    // Refused early when a quota is used up, recording the usage below is
+    // what enforces the quotas against parallel requests
+    let key_owner = key_owner(user, provider_kind);
+    if let KeyOwner::Organization(_) = key_owner {
+        let membership = state.organization_repo.get_membership(user.id).await?;
+        if membership.is_some_and(|membership| membership.quota_exceeded()) {
+            return Err(ChatError::QuotaExceeded);
         }
-    });
-
-    // Convert the receiver into a Stream that can be used by Sse
-    // let event_stream = ReceiverStream::new(receiver);
-    let state_clone = Arc::clone(&state);
-
-    let receiver_stream = ReceiverStream::new(receiver);
-    let initial_state = (receiver_stream, String::new()); // Initial state with an empty accumulator
-    let event_stream = stream::unfold(initial_state, move |(mut rc, mut accumulated)| {
-        let state_clone = Arc::clone(&state_clone); // Clone the Arc here
-        async move {
-            match rc.next().await {
-                Some(Ok(event)) => {
-                    // Process the event
-                    match event {
-                        GenerationEvent::Text(text) => {
-                            accumulated.push_str(&text);
-                            // Return the accumulated data as part of the SSE event
-                            let html =
-                                comrak::markdown_to_html(&accumulated, &comrak::Options::default());
-                            let s = format!(r##"<div>{}<div>"##, html);
-
-                            Some((Ok(Event::default().data(s)), (rc, accumulated)))
-                        }
-                        GenerationEvent::End(text) => {
-                            println!("accumulated: {:?}", accumulated);
-
-                            state_clone
-                                .chat_repo
-                                .add_ai_message_to_pair(lat_message_id, &accumulated)
-                                .await
-                                .unwrap();
-
-                            let html =
-                                comrak::markdown_to_html(&accumulated, &comrak::Options::default());
-
-                            let s = format!(
-                                r##"<div hx-swap-oob="outerHTML:#message-container">{}</div>"##,
-                                html
-                            );
-                            // append s to text
-                            let ss = format!("{}\n{}", text, s);
-                            println!("ss: {}", ss);
-
-                            // accumulated.push_str(&ss);
-                            // Handle the end of a sequence, possibly resetting the accumulator if needed
-                            Some((Ok(Event::default().data(ss)), (rc, String::new())))
-                        } // ... handle other event types if necessary ...
+    }
+
+    if !state.model_registry.verify(user, provider_kind).await {
+        return Err(ChatError::InvalidAPIKey);
+    }
+
+    let (generation, started) = state.generations.start(chat.id, pair_id);
+    if started {
+        let usage_id = match key_owner {
+            KeyOwner::Organization(organization_id) => {
+                let usage_id = state
+                    .organization_repo
+                    .start_usage(
+                        user.id,
+                        organization_id,
+                        chat.id,
+                        provider_kind.as_str(),
+                        &chat.model,
+                    )
+                    .await;
+                match usage_id {
+                    Ok(Some(usage_id)) => Some(usage_id),
+                    result => {
+                        // The generation won't run
+                        generation.end();
+                        state.generations.finish(chat.id, &generation);
+                        return Err(match result {
+                            Ok(_) => ChatError::QuotaExceeded,
+                            Err(e) => e.into(),
+                        });
+                    }
+                }
+            }
+            KeyOwner::User => None,
+        };
+        tokio::spawn(run_generation(
+            Arc::clone(state),
+            chat.id,
+            provider,
+            chat.model,
+            chat_message_pairs,
+            Arc::clone(&generation),
+            usage_id,
+        ));
+    }
+
+    Ok(generation)
+}
+
+/// Run a generation to its end, saving the AI message while it streams. It
+/// keeps running without listeners so a reload can attach to it, only the stop
+/// button cancels it. `usage_id` is the usage event to complete with the
+/// tokens, for the organization keys.
+async fn run_generation(
+    state: Arc<AppState>,
+    chat_id: i64,
+    provider: Arc<dyn LlmProvider>,
+    model: String,
+    chat_message_pairs: Vec<ChatMessagePair>,
+    generation: Arc<Generation>,
+    usage_id: Option<i64>,
+) {
+    let pair_id = generation.pair_id;
+    save_ai_message(&state, pair_id, "", PairStatus::Streaming).await;
+    let input_tokens = chat_messages(&chat_message_pairs)
+        .iter()
+        .map(|message| estimate_tokens(&message.content))
+        .sum();
+
+    let (sender, mut receiver) = mpsc::channel::<Result<GenerationEvent, axum::Error>>(10);
+    let generate = provider.generate(&model, chat_message_pairs, sender, generation.token.clone());
+
+    let forward = async {
+        let (mut ended, mut failed) = (false, false);
+        let mut saved_at = Instant::now();
+        while let Some(event) = receiver.recv().await {
+            match event {
+                Ok(GenerationEvent::Text(text)) => {
+                    generation.push(&text);
+                    if saved_at.elapsed() >= SAVE_INTERVAL {
+                        let text = generation.text();
+                        save_ai_message(&state, pair_id, &text, PairStatus::Streaming).await;
+                        saved_at = Instant::now();
                     }
                 }
-                Some(Err(e)) => {
-                    // Handle error without altering the accumulator
-                    Some((Err(axum::Error::new(e)), (rc, accumulated)))
+                Ok(GenerationEvent::End) => ended = true,
+                Err(e) => {
+                    eprintln!("Error in the generation stream: {:?}", e);
+                    failed = true;
                 }
-                None => None, // When the receiver stream ends, finish the stream
             }
         }
-    });
+        (ended, failed)
+    };
+
+    let (result, (ended, failed)) = tokio::join!(generate, forward);
+    if let Err(e) = &result {
+        eprintln!("Error generating SSE stream: {:?}", e);
+    }
+
+    // When stopped, what was generated so far is the answer
+    let status = if result.is_ok() && !failed && (ended || generation.token.is_cancelled()) {
+        PairStatus::Complete
+    } else {
+        PairStatus::Failed
+    };
+    let text = generation.text();
+    save_ai_message(&state, pair_id, &text, status).await;
+    if let Some(usage_id) = usage_id {
+        let output_tokens = estimate_tokens(&text);
+        if let Err(e) = state
+            .organization_repo
+            .finish_usage(usage_id, input_tokens, output_tokens)
+            .await
+        {
+            eprintln!("Error recording the usage: {:?}", e);
+        }
+    }
+
+    state.generations.finish(chat_id, &generation);
+    generation.end();
+}
+
+async fn save_ai_message(state: &AppState, pair_id: i64, text: &str, status: PairStatus) {
+    if let Err(e) = state.chat_repo.save_ai_message(pair_id, text, status).await {
+        eprintln!("Error saving the AI message: {:?}", e);
+    }
+}
+
+/// Follow a generation for the SSE listener: the text generated so far, the
+/// text as it grows, then the final message closing the listener.
+fn generation_events(generation: Arc<Generation>) -> EventStream {
+    let receiver = generation.subscribe();
+    stream::unfold(Some((generation, receiver, true)), |following| async move {
+        let (generation, mut receiver, first) = following?;
+
+        let done = match receiver.as_mut() {
+            _ if first && !generation.text().is_empty() => false,
+            None => true,
+            // Lagging behind is fine, the whole text is sent each time
+            Some(receiver) => matches!(
+                receiver.recv().await,
+                Ok(GenerationEvent::End) | Err(RecvError::Closed)
+            ),
+        };
+
+        let text = generation.text();
+        if done {
+            Some((Ok(end_event(&text)), None))
+        } else {
+            Some((Ok(text_event(&text)), Some((generation, receiver, false))))
+        }
+    })
+    .boxed()
+}
+
+fn text_event(text: &str) -> Event {
+    let html = comrak::markdown_to_html(text, &comrak::Options::default());
+    Event::default().data(format!(r##"<div>{}<div>"##, html))
+}
+
+fn end_event(text: &str) -> Event {
+    let html = comrak::markdown_to_html(text, &comrak::Options::default());
+    let s = format!(
+        r##"<div hx-swap-oob="outerHTML:#message-container">{}</div>"##,
+        html
+    );
+    // close the sse listener, remove the stop button and append s
+    Event::default().data(format!(
+        "{}\n{}\n{}",
+        r#"<div id="sse-listener" hx-swap-oob="true"></div>"#,
+        r#"<div id="stop-generation" hx-swap-oob="true"></div>"#,
+        s
+    ))
+}
+
+#[axum::debug_handler]
+pub async fn chat_stop(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<StatusCode, ChatError> {
+    state
+        .chat_repo
+        .get_chat(current_user.unwrap().id, chat_id)
+        .await?;
+
+    // The generation task saves the partial answer and closes the listeners
+    state.generations.cancel(chat_id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[axum::debug_handler]
+pub async fn chat_regenerate(
+    Path((chat_id, pair_id)): Path<(i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let user_id = current_user.unwrap().id;
+    state
+        .chat_repo
+        .add_alternative_pair(user_id, chat_id, pair_id)
+        .await?;
+
+    render_chat_messages(&state, user_id, chat_id).await
+}
+
+#[axum::debug_handler]
+pub async fn chat_edit_pair(
+    Path((chat_id, pair_id)): Path<(i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(chat_edit_message): Form<ChatAddMessage>,
+) -> Result<Html<String>, ChatError> {
+    let user_id = current_user.unwrap().id;
+    state
+        .chat_repo
+        .edit_pair(user_id, chat_id, pair_id, &chat_edit_message.message)
+        .await?;
+
+    render_chat_messages(&state, user_id, chat_id).await
+}
+
+#[axum::debug_handler]
+pub async fn chat_select_pair(
+    Path((chat_id, pair_id)): Path<(i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let user_id = current_user.unwrap().id;
+    state
+        .chat_repo
+        .select_pair(user_id, chat_id, pair_id)
+        .await?;
 
-    Ok(Sse::new(event_stream))
+    render_chat_messages(&state, user_id, chat_id).await
 }
 
 pub async fn delete_chat(
     Path(chat_id): Path<i64>,
     State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
 ) -> Result<Html<String>, ChatError> {
-    state.chat_repo.delete_chat(chat_id).await.unwrap();
+    let rows_affected = state
+        .chat_repo
+        .delete_chat(current_user.unwrap().id, chat_id)
+        .await?;
+    if rows_affected == 0 {
+        return Err(ChatError::NotFound);
+    }
 
     let html = r#"<div class="hidden"></div>"#;
 
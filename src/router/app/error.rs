@@ -8,7 +8,7 @@ use tera::Context;
 
 use std::sync::Arc;
 
-use crate::{AppState, User};
+use crate::{security::csrf::CsrfToken, AppState, User};
 
 #[derive(Deserialize)]
 pub struct ErrorParams {
@@ -21,6 +21,7 @@ pub async fn error(
     Query(params): Query<ErrorParams>,
     State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
 ) -> Html<String> {
     let mut context = Context::new();
     context.insert("status_code", &params.code);
@@ -31,6 +32,7 @@ pub async fn error(
     let mut context = Context::new();
     context.insert("view", &error);
     context.insert("current_user", &current_user);
+    context.insert("csrf_token", &csrf_token);
     context.insert("with_footer", &true);
     let rendered = state.tera.render("views/main.html", &context).unwrap();
 
@@ -7,12 +7,13 @@ use tera::Context;
 
 use std::sync::Arc;
 
-use crate::{AppState, User};
+use crate::{security::csrf::CsrfToken, AppState, User};
 
 #[axum::debug_handler]
 pub async fn app(
     State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
 ) -> Html<String> {
     let mut context = Context::new();
     context.insert("name", "World");
@@ -22,6 +23,7 @@ pub async fn app(
     let mut context = Context::new();
     context.insert("view", &home);
     context.insert("current_user", &current_user);
+    context.insert("csrf_token", &csrf_token);
     context.insert("with_footer", &true);
     let rendered = state.tera.render("views/main.html", &context).unwrap();
 
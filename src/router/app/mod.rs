@@ -5,20 +5,52 @@ use axum::{
 
 use std::sync::Arc;
 
-use crate::{middleware::valid_openai_api_key, AppState};
+use crate::{
+    middleware::{admin, valid_openai_api_key},
+    AppState,
+};
 
+mod account;
+use account::{settings_delete_account, settings_export};
+mod admin;
+use admin::{
+    admin_create_invite, admin_disable_user, admin_enable_user, admin_reset_password,
+    admin_revoke_invite, admin_set_role, admin_users,
+};
 mod home;
 use home::app;
+mod api;
+use api::{api_add_message, api_auth, api_chat_by_id, api_chats, api_new_chat};
 mod chat;
-use chat::{chat, chat_add_message, chat_by_id, chat_generate, delete_chat, new_chat};
+use chat::{
+    chat, chat_add_message, chat_by_id, chat_edit_pair, chat_generate, chat_regenerate,
+    chat_select_pair, chat_stop, delete_chat, new_chat,
+};
 mod auth;
-use auth::{form_signup, login, login_form, logout, signup};
+use auth::{
+    forgot_password, forgot_password_form, form_signup, login, login_form, login_two_factor,
+    login_two_factor_form, logout, resend_verification_email, reset_password, reset_password_form,
+    signup, verify_email,
+};
 mod blog;
 use blog::{blog, blog_by_slug};
 mod settings;
-use settings::{settings, settings_openai_api_key};
+use settings::{
+    settings, settings_anthropic_api_key, settings_create_api_token, settings_openai_api_key,
+    settings_openai_endpoint, settings_revoke_api_token, settings_revoke_other_sessions,
+    settings_revoke_session,
+};
 mod error;
 use error::error;
+mod oidc;
+use oidc::{login_oidc, login_oidc_callback};
+mod organizations;
+use organizations::{
+    admin_add_member, admin_create_organization, admin_delete_organization, admin_member_quotas,
+    admin_organization, admin_organization_api_key, admin_remove_member,
+};
+mod two_factor;
+use two_factor::{settings_totp_disable, settings_totp_enable, settings_totp_setup};
 
 use crate::middleware::auth;
 
@@ -28,23 +60,98 @@ pub fn app_router(state: Arc<AppState>) -> Router {
         .route("/:id", get(chat_by_id).delete(delete_chat))
         .route("/:id/message/add", post(chat_add_message))
         .route("/:id/generate", get(chat_generate))
+        .route("/:id/stop", post(chat_stop))
+        .route("/:id/pair/:pair_id/regenerate", post(chat_regenerate))
+        .route("/:id/pair/:pair_id/select", post(chat_select_pair))
+        .route("/:id/pair/:pair_id/edit", post(chat_edit_pair))
         .with_state(state.clone())
-        .layer(axum::middleware::from_fn(valid_openai_api_key))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            valid_openai_api_key,
+        ))
         .layer(axum::middleware::from_fn(auth));
 
     let settings_router = Router::new()
         .route("/", get(settings).post(settings_openai_api_key))
+        .route("/anthropic", post(settings_anthropic_api_key))
+        .route("/openai-endpoint", post(settings_openai_endpoint))
+        .route("/sessions/:id/revoke", post(settings_revoke_session))
+        .route(
+            "/sessions/revoke-others",
+            post(settings_revoke_other_sessions),
+        )
+        .route("/2fa/setup", post(settings_totp_setup))
+        .route("/2fa/enable", post(settings_totp_enable))
+        .route("/2fa/disable", post(settings_totp_disable))
+        .route("/verify-email", post(resend_verification_email))
+        .route("/api-tokens", post(settings_create_api_token))
+        .route("/api-tokens/:id/revoke", post(settings_revoke_api_token))
+        .route("/export", get(settings_export))
+        .route("/delete-account", post(settings_delete_account))
         .layer(axum::middleware::from_fn(auth));
 
+    let admin_router = Router::new()
+        .route("/", get(admin_users))
+        .route("/users/:id/disable", post(admin_disable_user))
+        .route("/users/:id/enable", post(admin_enable_user))
+        .route("/users/:id/role", post(admin_set_role))
+        .route("/users/:id/reset-password", post(admin_reset_password))
+        .route("/invites", post(admin_create_invite))
+        .route("/invites/:id/revoke", post(admin_revoke_invite))
+        .route("/organizations", post(admin_create_organization))
+        .route("/organizations/:id", get(admin_organization))
+        .route("/organizations/:id/delete", post(admin_delete_organization))
+        .route(
+            "/organizations/:id/api-key",
+            post(admin_organization_api_key),
+        )
+        .route("/organizations/:id/members", post(admin_add_member))
+        .route(
+            "/organizations/:id/members/:user_id/quotas",
+            post(admin_member_quotas),
+        )
+        .route(
+            "/organizations/:id/members/:user_id/remove",
+            post(admin_remove_member),
+        )
+        .layer(axum::middleware::from_fn(admin));
+
     Router::new()
         .route("/", get(app))
         .route("/error", get(error))
         .route("/login", get(login).post(login_form))
+        .route(
+            "/login/2fa",
+            get(login_two_factor).post(login_two_factor_form),
+        )
+        .route("/login/oidc", get(login_oidc))
+        .route("/login/oidc/callback", get(login_oidc_callback))
         .route("/signup", get(signup).post(form_signup))
-        .route("/logout", get(logout))
+        .route("/logout", post(logout))
+        .route(
+            "/forgot-password",
+            get(forgot_password).post(forgot_password_form),
+        )
+        .route(
+            "/reset-password",
+            get(reset_password).post(reset_password_form),
+        )
+        .route("/verify-email", get(verify_email))
         .route("/blog", get(blog))
         .route("/blog/:slug", get(blog_by_slug))
         .nest("/chat", chat_router)
         .nest("/settings", settings_router)
+        .nest("/admin", admin_router)
         .with_state(state.clone())
 }
+
+/// The JSON API, authenticated with API tokens. Its errors are JSON, it is
+/// kept out of the HTML error page of `handle_error`.
+pub fn api_router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/api/chats", get(api_chats).post(api_new_chat))
+        .route("/api/chats/:id", get(api_chat_by_id))
+        .route("/api/chats/:id/messages", post(api_add_message))
+        .layer(axum::middleware::from_fn(api_auth))
+        .with_state(state)
+}
@@ -0,0 +1,236 @@
+use axum::{
+    extract::{ConnectInfo, Extension, Query, State},
+    http::HeaderMap,
+    response::{IntoResponse, Redirect, Response},
+};
+
+use serde::Deserialize;
+use tower_cookies::{cookie::SameSite, Cookie, Cookies};
+
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    security::{
+        constant_time_eq,
+        csrf::CsrfToken,
+        oidc::{IdTokenClaims, OIDC_FLOW_TTL, OIDC_STATE_COOKIE},
+        password::hash_password,
+        registration::RegistrationPolicy,
+        session::{generate_token, hash_token},
+    },
+    AppState,
+};
+
+use super::auth::{complete_login, render_login, LogInError, LogInStep};
+
+// The login page with the error of a failed single sign-on.
+fn render_error(state: &AppState, csrf_token: &CsrfToken, message: String) -> Response {
+    let error = LogInError::SingleSignOn(message);
+    render_login(state, csrf_token, None, Some(&error)).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Start {
+    // The invite code of a signup, when registration is by invite
+    invite: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn login_oidc(
+    cookies: Cookies,
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Query(start): Query<Start>,
+) -> Response {
+    let Some(ref oidc) = state.oidc else {
+        return Redirect::to("/login").into_response();
+    };
+
+    let invite = start
+        .invite
+        .map(|invite| invite.trim().to_string())
+        .filter(|invite| !invite.is_empty());
+    match oidc.start(invite).await {
+        Ok((oidc_state, url)) => {
+            // Ties the callback to the browser which started the login
+            let cookie = Cookie::build(OIDC_STATE_COOKIE, oidc_state)
+                .path("/login/oidc")
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .max_age(tower_cookies::cookie::time::Duration::seconds(
+                    OIDC_FLOW_TTL.as_secs() as i64,
+                ))
+                .finish();
+            cookies.add(cookie);
+            Redirect::to(&url).into_response()
+        }
+        Err(e) => {
+            eprintln!("Error starting single sign-on: {}", e);
+            render_error(
+                &state,
+                &csrf_token,
+                "the provider can't be reached".to_string(),
+            )
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Callback {
+    state: Option<String>,
+    code: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn login_oidc_callback(
+    cookies: Cookies,
+    State(state): State<Arc<AppState>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(callback): Query<Callback>,
+) -> Result<Response, LogInError> {
+    let Some(ref oidc) = state.oidc else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+
+    let expected_state = cookies
+        .get(OIDC_STATE_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+    cookies.remove(
+        Cookie::build(OIDC_STATE_COOKIE, "")
+            .path("/login/oidc")
+            .finish(),
+    );
+
+    if let Some(error) = callback.error {
+        let message = callback.error_description.unwrap_or(error);
+        return Ok(render_error(&state, &csrf_token, message));
+    }
+    let (Some(oidc_state), Some(code), Some(expected_state)) =
+        (callback.state, callback.code, expected_state)
+    else {
+        return Ok(render_error(
+            &state,
+            &csrf_token,
+            "the login expired".to_string(),
+        ));
+    };
+    if !constant_time_eq(oidc_state.as_bytes(), expected_state.as_bytes()) {
+        return Ok(render_error(
+            &state,
+            &csrf_token,
+            "the login expired".to_string(),
+        ));
+    }
+
+    let (claims, invite) = match oidc.finish(&oidc_state, &code).await {
+        Ok(finished) => finished,
+        Err(e) => {
+            eprintln!("Error finishing single sign-on: {}", e);
+            return Ok(render_error(&state, &csrf_token, e.to_string()));
+        }
+    };
+
+    let user_id = match find_or_create_user(&state, &claims, invite.as_deref()).await? {
+        Ok(user_id) => user_id,
+        Err(message) => return Ok(render_error(&state, &csrf_token, message.to_string())),
+    };
+    let email = sqlx::query_scalar!("SELECT email FROM users WHERE id = ?", user_id)
+        .fetch_one(&*state.pool)
+        .await
+        .map_err(|e| LogInError::DatabaseError(e.to_string()))?;
+
+    let ip = addr.ip().to_string();
+    match complete_login(&cookies, &state, user_id, &email, &ip, &headers).await {
+        Ok(LogInStep::Session) => {
+            state.login_throttle.record_success(&email).await;
+            Ok(Redirect::to("/").into_response())
+        }
+        Ok(LogInStep::SecondFactor) => Ok(Redirect::to("/login/2fa").into_response()),
+        Err(error @ (LogInError::AccountDisabled | LogInError::EmailNotVerified)) => {
+            Ok(render_login(&state, &csrf_token, Some(&email), Some(&error)).into_response())
+        }
+        Err(error) => Err(error),
+    }
+}
+
+// The user linked to the identity, else the user with its email which gets
+// linked, else a new user if the registration policy allows it, with the
+// invite code the login was started with. The inner error says why the login
+// is refused.
+async fn find_or_create_user(
+    state: &AppState,
+    claims: &IdTokenClaims,
+    invite: Option<&str>,
+) -> Result<Result<i64, &'static str>, LogInError> {
+    let database_error = |e: sqlx::Error| LogInError::DatabaseError(e.to_string());
+
+    if let Some(user_id) = state
+        .identity_repo
+        .find_user(&claims.iss, &claims.sub)
+        .await
+        .map_err(database_error)?
+    {
+        return Ok(Ok(user_id));
+    }
+
+    let Some(email) = claims.verified_email() else {
+        return Ok(Err("the provider didn't share a verified email"));
+    };
+    if let Some(user_id) = state
+        .identity_repo
+        .find_user_by_email(email)
+        .await
+        .map_err(database_error)?
+    {
+        let linked = state
+            .identity_repo
+            .link(user_id, &claims.iss, &claims.sub)
+            .await
+            .map_err(database_error)?;
+        if !linked {
+            return Ok(Err(
+                "an account with this email isn't verified, log in with its password and verify it first",
+            ));
+        }
+        return Ok(Ok(user_id));
+    }
+
+    let invite_hash = match state.registration_policy {
+        RegistrationPolicy::Closed => {
+            return Ok(Err("there is no account with this email"));
+        }
+        // The invite link of the signup page starts the login with its code
+        RegistrationPolicy::Invite => match invite {
+            Some(invite) => Some(hash_token(invite)),
+            None => {
+                return Ok(Err(
+                    "there is no account with this email, sign up with an invite link",
+                ))
+            }
+        },
+        _ if !state.registration_policy.allows_email(email) => {
+            return Ok(Err("signing up with this email domain isn't allowed"));
+        }
+        _ => None,
+    };
+
+    // Nobody knows this password, a local one can be set with a reset
+    let password =
+        hash_password(&generate_token()).map_err(|e| LogInError::DatabaseError(e.to_string()))?;
+    let user_id = state
+        .identity_repo
+        .create_user(
+            email,
+            &password,
+            &claims.iss,
+            &claims.sub,
+            invite_hash.as_deref(),
+        )
+        .await
+        .map_err(database_error)?;
+    Ok(user_id.ok_or("this invite code is invalid, used or expired"))
+}
@@ -0,0 +1,282 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
+    Form,
+};
+
+use serde::Deserialize;
+use tera::Context;
+
+use std::sync::Arc;
+
+use crate::{
+    ai::stream::ProviderKind,
+    security::{csrf::CsrfToken, secret::key_hint},
+    AppState, User,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct NewOrganization {
+    name: String,
+}
+
+#[axum::debug_handler]
+pub async fn admin_create_organization(
+    State(state): State<Arc<AppState>>,
+    Form(new_organization): Form<NewOrganization>,
+) -> Result<Redirect, StatusCode> {
+    let name = new_organization.name.trim();
+    if name.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let organization_id = state
+        .organization_repo
+        .create_organization(name)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to(&organization_url(organization_id)))
+}
+
+#[axum::debug_handler]
+pub async fn admin_organization(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Path(organization_id): Path<i64>,
+) -> Result<Html<String>, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    render_organization(&state, user, &csrf_token, organization_id, Context::new()).await
+}
+
+/// The page of an organization, `context` can hold the error of an action
+/// (`organization_error`).
+async fn render_organization(
+    state: &AppState,
+    user: &User,
+    csrf_token: &CsrfToken,
+    organization_id: i64,
+    mut context: Context,
+) -> Result<Html<String>, StatusCode> {
+    let organization = state
+        .organization_repo
+        .get_organization(organization_id)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+    let members = state
+        .organization_repo
+        .get_members(organization_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let two_factor = state
+        .two_factor_repo
+        .is_enabled(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    context.insert("organization", &organization);
+    context.insert("members", &members);
+    context.insert("two_factor_enabled", &two_factor);
+    let view = state
+        .tera
+        .render("views/organization.html", &context)
+        .unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", user);
+    context.insert("csrf_token", csrf_token);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+// The page of the organization with an error.
+async fn render_error(
+    state: &AppState,
+    user: &User,
+    csrf_token: &CsrfToken,
+    organization_id: i64,
+    message: &str,
+) -> Result<Response, StatusCode> {
+    let mut context = Context::new();
+    context.insert("organization_error", message);
+    Ok(
+        render_organization(state, user, csrf_token, organization_id, context)
+            .await?
+            .into_response(),
+    )
+}
+
+fn organization_url(organization_id: i64) -> String {
+    format!("/admin/organizations/{}", organization_id)
+}
+
+#[axum::debug_handler]
+pub async fn admin_delete_organization(
+    State(state): State<Arc<AppState>>,
+    Path(organization_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    state
+        .organization_repo
+        .delete_organization(organization_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/admin"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OrganizationApiKey {
+    provider: String,
+    api_key: String,
+}
+
+#[axum::debug_handler]
+pub async fn admin_organization_api_key(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Path(organization_id): Path<i64>,
+    Form(organization_api_key): Form<OrganizationApiKey>,
+) -> Result<Response, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    // Whoever handles the keys of an organization needs a second factor
+    let two_factor = state
+        .two_factor_repo
+        .is_enabled(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !two_factor {
+        let message = "Enable two-factor authentication in your settings to manage the keys of an organization.";
+        return render_error(&state, user, &csrf_token, organization_id, message).await;
+    }
+
+    let kind = match organization_api_key.provider.parse::<ProviderKind>() {
+        Ok(kind @ (ProviderKind::OpenAi | ProviderKind::Anthropic)) => kind,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    // An empty key removes it
+    let api_key = organization_api_key.api_key.trim();
+    let (encrypted, hint) = match api_key {
+        "" => (None, None),
+        key => (
+            Some(
+                state
+                    .key_cipher
+                    .encrypt(key, &kind.organization_key_context(organization_id)),
+            ),
+            Some(key_hint(key)),
+        ),
+    };
+    state
+        .organization_repo
+        .set_api_key(organization_id, kind, encrypted.as_deref(), hint.as_deref())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to(&organization_url(organization_id)).into_response())
+}
+
+// A quota field, empty for no quota.
+fn parse_quota(quota: &str) -> Result<Option<i64>, ()> {
+    match quota.trim() {
+        "" => Ok(None),
+        quota => match quota.parse::<i64>() {
+            Ok(quota) if quota >= 0 => Ok(Some(quota)),
+            _ => Err(()),
+        },
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MemberQuotas {
+    monthly_request_quota: String,
+    monthly_token_quota: String,
+}
+
+impl MemberQuotas {
+    fn parse(&self) -> Result<(Option<i64>, Option<i64>), ()> {
+        Ok((
+            parse_quota(&self.monthly_request_quota)?,
+            parse_quota(&self.monthly_token_quota)?,
+        ))
+    }
+}
+
+const INVALID_QUOTAS: &str = "Quotas are whole numbers, leave them empty for no quota.";
+
+#[derive(Deserialize, Debug)]
+pub struct NewMember {
+    email: String,
+    #[serde(flatten)]
+    quotas: MemberQuotas,
+}
+
+#[axum::debug_handler]
+pub async fn admin_add_member(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Path(organization_id): Path<i64>,
+    Form(new_member): Form<NewMember>,
+) -> Result<Response, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    let Ok((requests, tokens)) = new_member.quotas.parse() else {
+        return render_error(&state, user, &csrf_token, organization_id, INVALID_QUOTAS).await;
+    };
+
+    let added = state
+        .organization_repo
+        .add_member(organization_id, new_member.email.trim(), requests, tokens)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if added == 0 {
+        let message =
+            "There is no user with this email, or they are already the member of an organization.";
+        return render_error(&state, user, &csrf_token, organization_id, message).await;
+    }
+
+    Ok(Redirect::to(&organization_url(organization_id)).into_response())
+}
+
+#[axum::debug_handler]
+pub async fn admin_member_quotas(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    Path((organization_id, user_id)): Path<(i64, i64)>,
+    Form(quotas): Form<MemberQuotas>,
+) -> Result<Response, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    let Ok((requests, tokens)) = quotas.parse() else {
+        return render_error(&state, user, &csrf_token, organization_id, INVALID_QUOTAS).await;
+    };
+
+    state
+        .organization_repo
+        .set_member_quotas(organization_id, user_id, requests, tokens)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to(&organization_url(organization_id)).into_response())
+}
+
+#[axum::debug_handler]
+pub async fn admin_remove_member(
+    State(state): State<Arc<AppState>>,
+    Path((organization_id, user_id)): Path<(i64, i64)>,
+) -> Result<Redirect, StatusCode> {
+    state
+        .organization_repo
+        .remove_member(organization_id, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to(&organization_url(organization_id)))
+}
@@ -1,5 +1,5 @@
 use axum::{
-    extract::{Extension, State},
+    extract::{Extension, Path, State},
     http::StatusCode,
     response::{Html, Redirect},
     Form,
@@ -7,10 +7,32 @@ use axum::{
 
 use serde::Deserialize;
 use tera::Context;
+use tower_cookies::Cookies;
 
 use std::sync::Arc;
 
-use crate::{AppState, User};
+use crate::{
+    ai::{
+        openai::{check_base_url, extra_headers_context, header_names},
+        stream::ProviderKind,
+    },
+    security::{
+        api_token::{generate_api_token, join_scopes, ApiScope, API_TOKEN_LIFETIMES},
+        csrf::CsrfToken,
+        secret::key_hint,
+        session::{hash_token, SESSION_COOKIE},
+        totp::{provisioning_uri, qr_code_svg, secret_context},
+    },
+    AppState, User,
+};
+
+// The token hash of the session of the request.
+fn current_token_hash(cookies: &Cookies) -> String {
+    cookies
+        .get(SESSION_COOKIE)
+        .map(|cookie| hash_token(cookie.value()))
+        .unwrap_or_default()
+}
 
 #[derive(Deserialize, Debug)]
 pub struct OpenAiAPIKey {
@@ -24,31 +46,324 @@ pub async fn settings_openai_api_key(
     Form(set_openai_api_key): Form<OpenAiAPIKey>,
 ) -> Result<Redirect, StatusCode> {
     let id = current_user.unwrap().id;
+    let api_key = set_openai_api_key.api_key.trim();
+    // Keys are stored encrypted, with their last characters for display
+    let (encrypted, hint) = match api_key {
+        "" => (String::new(), None),
+        key => (
+            state
+                .key_cipher
+                .encrypt(key, &ProviderKind::OpenAi.key_context(id)),
+            Some(key_hint(key)),
+        ),
+    };
     sqlx::query!(
-        "INSERT INTO settings (user_id, openai_api_key) VALUES (?, ?) ON CONFLICT (user_id) DO UPDATE SET openai_api_key = ?",
+        "INSERT INTO settings (user_id, openai_api_key, openai_api_key_hint) VALUES (?, ?, ?) ON CONFLICT (user_id) DO UPDATE SET openai_api_key = excluded.openai_api_key, openai_api_key_hint = excluded.openai_api_key_hint",
         id,
-        set_openai_api_key.api_key,
-        set_openai_api_key.api_key
+        encrypted,
+        hint
     ).execute(&*state.pool).await.unwrap();
 
     Ok(Redirect::to("/settings"))
 }
 
+#[derive(Deserialize, Debug)]
+pub struct AnthropicAPIKey {
+    api_key: String,
+}
+
 #[axum::debug_handler]
-pub async fn settings(
+pub async fn settings_anthropic_api_key(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(set_anthropic_api_key): Form<AnthropicAPIKey>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+    // An empty key removes it
+    let api_key = set_anthropic_api_key.api_key.trim();
+    let (encrypted, hint) = match api_key {
+        "" => (None, None),
+        key => (
+            Some(
+                state
+                    .key_cipher
+                    .encrypt(key, &ProviderKind::Anthropic.key_context(id)),
+            ),
+            Some(key_hint(key)),
+        ),
+    };
+    sqlx::query!(
+        "INSERT INTO settings (user_id, openai_api_key, anthropic_api_key, anthropic_api_key_hint) VALUES (?, '', ?, ?) ON CONFLICT (user_id) DO UPDATE SET anthropic_api_key = excluded.anthropic_api_key, anthropic_api_key_hint = excluded.anthropic_api_key_hint",
+        id,
+        encrypted,
+        hint
+    ).execute(&*state.pool).await.unwrap();
+
+    Ok(Redirect::to("/settings"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OpenAiEndpoint {
+    base_url: String,
+    api_version: String,
+    // Empty keeps the saved headers
+    extra_headers: String,
+    // Checkbox, only sent when checked
+    remove_extra_headers: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn settings_openai_endpoint(
     State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
+    Form(endpoint): Form<OpenAiEndpoint>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let base_url = endpoint.base_url.trim();
+    if !base_url.is_empty() && check_base_url(base_url, state.allow_private_endpoints).is_err() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let api_version = endpoint.api_version.trim();
+    let extra_headers = endpoint.extra_headers.trim();
+    // The headers hold credentials, they are stored encrypted with their
+    // names for display
+    let headers = match (endpoint.remove_extra_headers.is_some(), extra_headers) {
+        (true, _) => Some((None, None)),
+        (false, "") => None,
+        (false, headers) => {
+            let names = header_names(headers);
+            if names.is_empty() {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            let encrypted = state
+                .key_cipher
+                .encrypt(headers, &extra_headers_context(id));
+            Some((Some(encrypted), Some(names)))
+        }
+    };
+
+    // Empty fields fall back to the OpenAI defaults
+    sqlx::query!(
+        "INSERT INTO settings (user_id, openai_api_key, openai_base_url, openai_api_version) VALUES (?, '', NULLIF(?, ''), NULLIF(?, '')) ON CONFLICT (user_id) DO UPDATE SET openai_base_url = excluded.openai_base_url, openai_api_version = excluded.openai_api_version",
+        id,
+        base_url,
+        api_version
+    ).execute(&*state.pool).await.unwrap();
+    if let Some((encrypted, names)) = headers {
+        sqlx::query!(
+            "UPDATE settings SET openai_extra_headers = ?, openai_extra_header_names = ? WHERE user_id = ?",
+            encrypted,
+            names,
+            id
+        )
+        .execute(&*state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    Ok(Redirect::to("/settings"))
+}
+
+#[axum::debug_handler]
+pub async fn settings_revoke_session(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    cookies: Cookies,
+    Path(session_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+    state
+        .session_repo
+        .revoke_user_session(id, session_id, &current_token_hash(&cookies))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/settings"))
+}
+
+#[axum::debug_handler]
+pub async fn settings_revoke_other_sessions(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    cookies: Cookies,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+    state
+        .session_repo
+        .revoke_other_sessions(id, &current_token_hash(&cookies))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/settings"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewApiToken {
+    name: String,
+    // Checkboxes, only sent when checked
+    chats_read: Option<String>,
+    chats_write: Option<String>,
+    // Days, empty for a token which doesn't expire
+    expires_in: String,
+}
+
+#[axum::debug_handler]
+pub async fn settings_create_api_token(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    cookies: Cookies,
+    Form(new_api_token): Form<NewApiToken>,
 ) -> Result<Html<String>, StatusCode> {
-    let key = current_user.as_ref().unwrap().openai_api_key.as_ref();
+    let user = current_user.as_ref().unwrap();
+    let scopes = [
+        (ApiScope::ChatsRead, &new_api_token.chats_read),
+        (ApiScope::ChatsWrite, &new_api_token.chats_write),
+    ]
+    .into_iter()
+    .filter(|(_, checked)| checked.is_some())
+    .map(|(scope, _)| scope)
+    .collect::<Vec<_>>();
+    let days = new_api_token.expires_in.parse::<i64>().ok();
+    let name = new_api_token.name.trim();
 
     let mut context = Context::new();
-    context.insert("openai_api_key", &key);
+    if name.is_empty() || scopes.is_empty() || !API_TOKEN_LIFETIMES.contains(&days) {
+        context.insert(
+            "api_token_error",
+            "Name the token and pick at least one scope.",
+        );
+    } else {
+        // Only shown now, the database keeps its hash
+        let token = generate_api_token();
+        state
+            .api_token_repo
+            .create_token(
+                user.id,
+                name,
+                &hash_token(&token),
+                &join_scopes(&scopes),
+                days,
+            )
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        context.insert("new_api_token", &token);
+    }
+
+    render_settings(&state, user, &cookies, &csrf_token, context).await
+}
+
+#[axum::debug_handler]
+pub async fn settings_revoke_api_token(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(token_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+    state
+        .api_token_repo
+        .revoke_token(id, token_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/settings"))
+}
+
+#[axum::debug_handler]
+pub async fn settings(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    cookies: Cookies,
+) -> Result<Html<String>, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    render_settings(&state, user, &cookies, &csrf_token, Context::new()).await
+}
+
+/// The settings page, `context` can hold the outcome of a two-factor
+/// authentication step (`totp_error`, `recovery_codes`), of creating an API
+/// token (`api_token_error`, `new_api_token`) or of deleting the account
+/// (`delete_account_error`).
+pub async fn render_settings(
+    state: &AppState,
+    user: &User,
+    cookies: &Cookies,
+    csrf_token: &CsrfToken,
+    mut context: Context,
+) -> Result<Html<String>, StatusCode> {
+    let sessions = state
+        .session_repo
+        .get_user_sessions(user.id, &current_token_hash(cookies))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let membership = state
+        .organization_repo
+        .get_membership(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let api_tokens = state
+        .api_token_repo
+        .get_user_tokens(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Users created by single sign-on have a password nobody knows, which
+    // deleting the account asks for
+    let single_sign_on = state
+        .identity_repo
+        .has_identity(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let totp = state
+        .two_factor_repo
+        .get_totp(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    match totp {
+        Some(totp) if totp.enabled => {
+            let recovery_codes_left = state
+                .two_factor_repo
+                .remaining_recovery_codes(user.id)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            context.insert("totp_enabled", &true);
+            context.insert("recovery_codes_left", &recovery_codes_left);
+        }
+        Some(totp) => {
+            // Enrolment waiting for its first code
+            let secret = state
+                .key_cipher
+                .decrypt(&totp.secret, &secret_context(user.id))
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let uri = provisioning_uri(&secret, &user.email);
+            context.insert("totp_secret", &secret);
+            context.insert("totp_qr_code", &qr_code_svg(&uri));
+        }
+        None => {}
+    }
+
+    context.insert("openai_api_key_hint", &user.openai_api_key_hint);
+    context.insert("anthropic_api_key_hint", &user.anthropic_api_key_hint);
+    context.insert("openai_base_url", &user.openai_base_url);
+    context.insert("openai_api_version", &user.openai_api_version);
+    context.insert("openai_extra_header_names", &user.openai_extra_header_names);
+    context.insert("sessions", &sessions);
+    context.insert("api_tokens", &api_tokens);
+    context.insert("membership", &membership);
+    context.insert("email", &user.email);
+    context.insert("email_verified", &user.email_verified_at.is_some());
+    context.insert("single_sign_on", &single_sign_on);
 
     let settings = state.tera.render("views/settings.html", &context).unwrap();
 
     let mut context = Context::new();
     context.insert("view", &settings);
-    context.insert("current_user", &current_user);
+    context.insert("current_user", user);
+    context.insert("csrf_token", csrf_token);
     context.insert("with_footer", &true);
     let rendered = state.tera.render("views/main.html", &context).unwrap();
 
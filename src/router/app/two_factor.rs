@@ -0,0 +1,161 @@
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    Form,
+};
+
+use serde::Deserialize;
+use tera::Context;
+use tower_cookies::Cookies;
+
+use std::sync::Arc;
+
+use crate::{
+    security::{
+        csrf::CsrfToken,
+        totp::{
+            generate_recovery_codes, generate_secret, hash_recovery_code, secret_context,
+            verify_code,
+        },
+    },
+    AppState, User,
+};
+
+use super::settings::render_settings;
+
+#[derive(Deserialize, Debug)]
+pub struct TotpCode {
+    code: String,
+}
+
+/// Check a TOTP code or a recovery code of a user with two-factor
+/// authentication enabled. Both are single use.
+pub async fn verify_second_factor(
+    state: &AppState,
+    user_id: i64,
+    code: &str,
+) -> sqlx::Result<bool> {
+    let Some(totp) = state
+        .two_factor_repo
+        .get_totp(user_id)
+        .await?
+        .filter(|totp| totp.enabled)
+    else {
+        return Ok(false);
+    };
+
+    match state
+        .key_cipher
+        .decrypt(&totp.secret, &secret_context(user_id))
+    {
+        Ok(secret) => {
+            if let Some(step) = verify_code(&secret, code, totp.last_used_step) {
+                return state.two_factor_repo.use_step(user_id, step).await;
+            }
+        }
+        // Recovery codes still work
+        Err(e) => eprintln!("Error decrypting the TOTP secret: {}", e),
+    }
+
+    state
+        .two_factor_repo
+        .use_recovery_code(user_id, &hash_recovery_code(code))
+        .await
+}
+
+#[axum::debug_handler]
+pub async fn settings_totp_setup(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+    let secret = state
+        .key_cipher
+        .encrypt(&generate_secret(), &secret_context(id));
+    state
+        .two_factor_repo
+        .start_enrolment(id, &secret)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/settings"))
+}
+
+#[axum::debug_handler]
+pub async fn settings_totp_enable(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    cookies: Cookies,
+    Form(totp_code): Form<TotpCode>,
+) -> Result<Response, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    let totp = state
+        .two_factor_repo
+        .get_totp(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some(totp) = totp.filter(|totp| !totp.enabled) else {
+        return Ok(Redirect::to("/settings").into_response());
+    };
+
+    let secret = state
+        .key_cipher
+        .decrypt(&totp.secret, &secret_context(user.id))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut context = Context::new();
+    match verify_code(&secret, &totp_code.code, None) {
+        Some(step) => {
+            // The recovery codes are only shown now
+            let recovery_codes = generate_recovery_codes();
+            let hashes = recovery_codes
+                .iter()
+                .map(|code| hash_recovery_code(code))
+                .collect::<Vec<_>>();
+            state
+                .two_factor_repo
+                .enable(user.id, step, &hashes)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            context.insert("recovery_codes", &recovery_codes);
+        }
+        None => context.insert(
+            "totp_error",
+            "Invalid code, check the clock of your device and try again.",
+        ),
+    }
+
+    let rendered = render_settings(&state, user, &cookies, &csrf_token, context).await?;
+    Ok(rendered.into_response())
+}
+
+#[axum::debug_handler]
+pub async fn settings_totp_disable(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(csrf_token): Extension<CsrfToken>,
+    cookies: Cookies,
+    Form(totp_code): Form<TotpCode>,
+) -> Result<Response, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    let valid = verify_second_factor(&state, user.id, &totp_code.code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if !valid {
+        let mut context = Context::new();
+        context.insert("totp_error", "Invalid code.");
+        let rendered = render_settings(&state, user, &cookies, &csrf_token, context).await?;
+        return Ok(rendered.into_response());
+    }
+
+    state
+        .two_factor_repo
+        .disable(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/settings").into_response())
+}
@@ -1,2 +1,2 @@
 pub mod app; // This defines the `app` module and makes it available to other modules.
-pub use self::app::app_router;
+pub use self::app::{api_router, app_router};
@@ -0,0 +1,130 @@
+use std::{fmt, str::FromStr};
+
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+use serde::Serialize;
+
+use super::session::generate_token;
+
+/// Prefix of the API tokens, so they are recognizable when leaked.
+pub const API_TOKEN_PREFIX: &str = "rgpt_";
+
+/// What the expiry select of the settings offers, in days. `None` never
+/// expires.
+pub const API_TOKEN_LIFETIMES: [Option<i64>; 4] = [Some(30), Some(90), Some(365), None];
+
+/// What an API token gives access to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ApiScope {
+    ChatsRead,
+    ChatsWrite,
+}
+
+impl ApiScope {
+    pub const ALL: [ApiScope; 2] = [ApiScope::ChatsRead, ApiScope::ChatsWrite];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::ChatsRead => "chats:read",
+            ApiScope::ChatsWrite => "chats:write",
+        }
+    }
+}
+
+impl fmt::Display for ApiScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ApiScope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ApiScope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// The scopes stored in `api_tokens.scopes`, unknown ones are ignored.
+pub fn parse_scopes(scopes: &str) -> Vec<ApiScope> {
+    scopes
+        .split_whitespace()
+        .filter_map(|scope| scope.parse().ok())
+        .collect()
+}
+
+pub fn join_scopes(scopes: &[ApiScope]) -> String {
+    scopes
+        .iter()
+        .map(ApiScope::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A new API token, shown once to the user then stored hashed with
+/// `session::hash_token`.
+pub fn generate_api_token() -> String {
+    format!("{}{}", API_TOKEN_PREFIX, generate_token())
+}
+
+/// The token of an `Authorization: Bearer` header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let header = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = header.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+/// The API token a request is authenticated with, instead of a session.
+#[derive(Debug, Clone)]
+pub struct ApiTokenAuth {
+    pub token_id: i64,
+    pub scopes: Vec<ApiScope>,
+}
+
+impl ApiTokenAuth {
+    pub fn allows(&self, scope: ApiScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_scopes() {
+        let scopes = parse_scopes("chats:read unknown chats:write");
+        assert_eq!(scopes, vec![ApiScope::ChatsRead, ApiScope::ChatsWrite]);
+        assert_eq!(join_scopes(&scopes), "chats:read chats:write");
+
+        let auth = ApiTokenAuth {
+            token_id: 1,
+            scopes: parse_scopes("chats:read"),
+        };
+        assert!(auth.allows(ApiScope::ChatsRead));
+        assert!(!auth.allows(ApiScope::ChatsWrite));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let token = generate_api_token();
+        assert!(token.starts_with(API_TOKEN_PREFIX));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        assert_eq!(bearer_token(&headers), Some(token.as_str()));
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("Basic dXNlcjpwdw=="),
+        );
+        assert_eq!(bearer_token(&headers), None);
+    }
+}
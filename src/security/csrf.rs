@@ -0,0 +1,102 @@
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tower_cookies::{cookie::SameSite, Cookie};
+
+use super::{constant_time_eq, session::SESSION_DAYS};
+
+/// The cookie holding the CSRF token of the browser.
+pub const CSRF_COOKIE: &str = "rust-gpt-csrf";
+
+/// The header htmx requests carry the token in, set on `<body>` by `main.html`.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// The hidden field plain form posts carry the token in.
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// The token of the current request, inserted as a request extension by the
+/// `csrf` middleware for the handlers rendering `main.html`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CsrfToken(pub String);
+
+/// Derives the tokens of logged in users from their session, so a token only
+/// works with its session and stops working when the session ends.
+#[derive(Clone)]
+pub struct CsrfSigner {
+    key: [u8; 32],
+}
+
+impl CsrfSigner {
+    /// Derive the key from the master key, distinct from the other keys.
+    pub fn new(master_key: &[u8; 32]) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(master_key).unwrap();
+        mac.update(b"rustgpt csrf");
+        Self {
+            key: mac.finalize().into_bytes().into(),
+        }
+    }
+
+    /// The token of the session with this token hash.
+    pub fn session_token(&self, session_token_hash: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(session_token_hash.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+/// The cookie for a new token of the pages seen logged out (login, signup...).
+/// It is replaced on login and logout so a token planted before logging in
+/// can't be reused.
+pub fn csrf_cookie(token: String) -> Cookie<'static> {
+    Cookie::build(CSRF_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(tower_cookies::cookie::time::Duration::days(SESSION_DAYS))
+        .finish()
+}
+
+/// Get the token from an url encoded form body.
+pub fn form_token(body: &[u8]) -> Option<String> {
+    form_urlencoded::parse(body)
+        .find(|(name, _)| name == CSRF_FIELD)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Check the submitted token against the one of the cookie.
+pub fn tokens_match(expected: &str, submitted: &str) -> bool {
+    !expected.is_empty() && constant_time_eq(expected.as_bytes(), submitted.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_form_token() {
+        assert_eq!(
+            form_token(b"email=a%40b.c&csrf_token=abc123&password=x").as_deref(),
+            Some("abc123")
+        );
+        assert_eq!(form_token(b"email=a%40b.c&password=x"), None);
+        assert_eq!(form_token(b""), None);
+    }
+
+    #[test]
+    fn test_session_token() {
+        let signer = CsrfSigner::new(&[1; 32]);
+        let token = signer.session_token("session-a");
+        assert_eq!(token.len(), 64);
+        assert_eq!(token, signer.session_token("session-a"));
+        assert_ne!(token, signer.session_token("session-b"));
+        assert_ne!(token, CsrfSigner::new(&[2; 32]).session_token("session-a"));
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("abc123", "abc123"));
+        assert!(!tokens_match("abc123", "abc124"));
+        assert!(!tokens_match("abc123", "abc"));
+        assert!(!tokens_match("", ""));
+    }
+}
@@ -0,0 +1,15 @@
+pub mod api_token;
+pub mod csrf;
+pub mod oidc;
+pub mod password;
+pub mod registration;
+pub mod secret;
+pub mod session;
+pub mod throttle;
+pub mod token;
+pub mod totp;
+
+/// Compare secrets without leaking where they differ through timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,499 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use data_encoding::BASE64URL_NOPAD;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+
+use super::session::generate_token;
+
+/// The cookie holding the `state` of a single sign-on started in the browser.
+pub const OIDC_STATE_COOKIE: &str = "rust-gpt-oidc";
+
+/// How long the user has to log in at the provider.
+pub const OIDC_FLOW_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Single sign-on with an OpenID Connect provider, configured by
+/// `OIDC_ISSUER`, `OIDC_CLIENT_ID` and `OIDC_CLIENT_SECRET`.
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    /// What the login button says, `OIDC_NAME`.
+    pub name: String,
+}
+
+impl OidcConfig {
+    /// `None` when single sign-on isn't configured.
+    pub fn from_env(app_url: &str) -> Option<Self> {
+        let issuer = dotenv::var("OIDC_ISSUER").ok()?;
+        let client_id = dotenv::var("OIDC_CLIENT_ID").ok()?;
+        Some(Self {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret: dotenv::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_url: format!("{}/login/oidc/callback", app_url),
+            name: dotenv::var("OIDC_NAME").unwrap_or_else(|_| "SSO".to_string()),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    Request(reqwest::Error),
+    Discovery(String),
+    Token(String),
+    InvalidIdToken(&'static str),
+    UnknownState,
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::Request(e) => write!(f, "request to the provider failed: {}", e),
+            OidcError::Discovery(message) => write!(f, "invalid provider metadata: {}", message),
+            OidcError::Token(message) => write!(f, "token request failed: {}", message),
+            OidcError::InvalidIdToken(reason) => write!(f, "invalid ID token: {}", reason),
+            OidcError::UnknownState => write!(f, "unknown or expired login"),
+        }
+    }
+}
+
+impl std::error::Error for OidcError {}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(e: reqwest::Error) -> Self {
+        OidcError::Request(e)
+    }
+}
+
+/// The endpoints of the provider, from its discovery document.
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == client_id,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == client_id),
+        }
+    }
+
+    fn is_many(&self) -> bool {
+        matches!(self, Audience::Many(auds) if auds.len() > 1)
+    }
+}
+
+/// The claims of an ID token used to find or create the user.
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    aud: Audience,
+    azp: Option<String>,
+    exp: u64,
+    nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+impl IdTokenClaims {
+    /// The verified email of the user, `None` if the provider didn't verify
+    /// it: it is what links an identity to an existing account.
+    pub fn verified_email(&self) -> Option<&str> {
+        self.email.as_deref().filter(|_| self.email_verified)
+    }
+}
+
+// A login started at the provider, waiting for its callback.
+struct Flow {
+    nonce: String,
+    code_verifier: String,
+    // To sign up with when registration is by invite
+    invite: Option<String>,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// The authorization code flow with PKCE. The started logins are kept in
+/// memory by `state`, like `totp::PendingLogins`.
+#[derive(Clone)]
+pub struct OidcClient {
+    pub config: OidcConfig,
+    http: reqwest::Client,
+    // Fetched on the first login, so the app starts while the provider is down
+    metadata: Arc<OnceCell<ProviderMetadata>>,
+    flows: Arc<Mutex<HashMap<String, Flow>>>,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::new(),
+            metadata: Arc::new(OnceCell::new()),
+            flows: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn metadata(&self) -> Result<&ProviderMetadata, OidcError> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!("{}/.well-known/openid-configuration", self.config.issuer);
+                let metadata: ProviderMetadata = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                if metadata.issuer.trim_end_matches('/') != self.config.issuer {
+                    return Err(OidcError::Discovery(format!(
+                        "issuer {} doesn't match",
+                        metadata.issuer
+                    )));
+                }
+                Ok(metadata)
+            })
+            .await
+    }
+
+    /// Start a login, with the invite code of a signup, returns the `state`
+    /// for the cookie and the URL to redirect to.
+    pub async fn start(&self, invite: Option<String>) -> Result<(String, String), OidcError> {
+        let metadata = self.metadata().await?;
+        let state = generate_token();
+        let flow = Flow {
+            nonce: generate_token(),
+            code_verifier: generate_token(),
+            invite,
+            expires_at: Instant::now() + OIDC_FLOW_TTL,
+        };
+
+        let url = format!(
+            "{}{}{}",
+            metadata.authorization_endpoint,
+            if metadata.authorization_endpoint.contains('?') {
+                '&'
+            } else {
+                '?'
+            },
+            form_urlencoded::Serializer::new(String::new())
+                .append_pair("response_type", "code")
+                .append_pair("client_id", &self.config.client_id)
+                .append_pair("redirect_uri", &self.config.redirect_url)
+                .append_pair("scope", "openid email profile")
+                .append_pair("state", &state)
+                .append_pair("nonce", &flow.nonce)
+                .append_pair("code_challenge", &code_challenge(&flow.code_verifier))
+                .append_pair("code_challenge_method", "S256")
+                .finish()
+        );
+
+        let mut flows = self.flows.lock().unwrap();
+        flows.retain(|_, flow| flow.expires_at > Instant::now());
+        flows.insert(state.clone(), flow);
+        Ok((state, url))
+    }
+
+    /// Finish the login of a `state` with the code of the callback, returns
+    /// the claims of the validated ID token and the invite code the login
+    /// was started with. A `state` is only used once.
+    pub async fn finish(
+        &self,
+        state: &str,
+        code: &str,
+    ) -> Result<(IdTokenClaims, Option<String>), OidcError> {
+        let flow = self
+            .flows
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|flow| flow.expires_at > Instant::now())
+            .ok_or(OidcError::UnknownState)?;
+        let metadata = self.metadata().await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("code_verifier", &flow.code_verifier),
+        ];
+        let request = self.http.post(&metadata.token_endpoint);
+        let request = match self.config.client_secret {
+            // client_secret_basic, the default authentication of clients
+            Some(ref secret) => {
+                request.basic_auth(urlencode(&self.config.client_id), Some(urlencode(secret)))
+            }
+            // A public client, only PKCE protects the code
+            None => {
+                form.push(("client_id", &self.config.client_id));
+                request
+            }
+        };
+        let response = request.form(&form).send().await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = match response.json::<TokenErrorResponse>().await {
+                Ok(e) => format!("{} {}", e.error, e.error_description.unwrap_or_default()),
+                Err(_) => status.to_string(),
+            };
+            return Err(OidcError::Token(message.trim().to_string()));
+        }
+        let token: TokenResponse = response.json().await?;
+
+        let claims = decode_id_token(&token.id_token)?;
+        validate_claims(&claims, &self.config, &flow.nonce, now())?;
+        Ok((claims, flow.invite))
+    }
+}
+
+fn code_challenge(code_verifier: &str) -> String {
+    BASE64URL_NOPAD.encode(&Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn urlencode(value: &str) -> String {
+    form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+// The ID token comes straight from the token endpoint over TLS, which
+// authenticates it in place of its signature (OpenID Connect Core 3.1.3.7).
+fn decode_id_token(id_token: &str) -> Result<IdTokenClaims, OidcError> {
+    let payload = id_token
+        .split('.')
+        .nth(1)
+        .ok_or(OidcError::InvalidIdToken("malformed"))?;
+    let payload = BASE64URL_NOPAD
+        .decode(payload.trim_end_matches('=').as_bytes())
+        .map_err(|_| OidcError::InvalidIdToken("malformed"))?;
+    serde_json::from_slice(&payload).map_err(|_| OidcError::InvalidIdToken("malformed claims"))
+}
+
+fn validate_claims(
+    claims: &IdTokenClaims,
+    config: &OidcConfig,
+    nonce: &str,
+    now: u64,
+) -> Result<(), OidcError> {
+    if claims.iss.trim_end_matches('/') != config.issuer {
+        return Err(OidcError::InvalidIdToken("wrong issuer"));
+    }
+    if !claims.aud.contains(&config.client_id) {
+        return Err(OidcError::InvalidIdToken("wrong audience"));
+    }
+    if claims.aud.is_many() && claims.azp.as_deref() != Some(&config.client_id) {
+        return Err(OidcError::InvalidIdToken("wrong authorized party"));
+    }
+    if claims.exp <= now {
+        return Err(OidcError::InvalidIdToken("expired"));
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(OidcError::InvalidIdToken("wrong nonce"));
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    // What the mock provider knows of the login in progress
+    #[derive(Default)]
+    struct MockIdp {
+        issuer: String,
+        code_challenge: String,
+        nonce: String,
+    }
+
+    type Shared = Arc<Mutex<MockIdp>>;
+
+    async fn discovery(State(idp): State<Shared>) -> Json<Value> {
+        let issuer = idp.lock().unwrap().issuer.clone();
+        Json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+        }))
+    }
+
+    async fn token(
+        State(idp): State<Shared>,
+        headers: HeaderMap,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+        let idp = idp.lock().unwrap();
+        let authorized = headers.get("authorization").map(|h| h.to_str().unwrap())
+            == Some("Basic cnVzdGdwdDpzM2NyZXQ=");
+        let verified = form.get("code_verifier").map(|v| code_challenge(v))
+            == Some(idp.code_challenge.clone());
+        if !authorized || !verified || form.get("code").map(String::as_str) != Some("the-code") {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_grant" })),
+            ));
+        }
+
+        let claims = json!({
+            "iss": idp.issuer,
+            "sub": "alice",
+            "aud": "rustgpt",
+            "exp": now() + 60,
+            "nonce": idp.nonce,
+            "email": "alice@example.com",
+            "email_verified": true,
+        });
+        let id_token = format!(
+            "eyJhbGciOiJSUzI1NiJ9.{}.signature",
+            BASE64URL_NOPAD.encode(claims.to_string().as_bytes())
+        );
+        Ok(Json(json!({ "access_token": "at", "id_token": id_token })))
+    }
+
+    async fn mock_idp() -> (OidcClient, Shared) {
+        let idp: Shared = Default::default();
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/token", post(token))
+            .with_state(idp.clone());
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let issuer = format!("http://{}", server.local_addr());
+        idp.lock().unwrap().issuer = issuer.clone();
+        tokio::spawn(server);
+
+        let client = OidcClient::new(OidcConfig {
+            issuer,
+            client_id: "rustgpt".to_string(),
+            client_secret: Some("s3cret".to_string()),
+            redirect_url: "http://localhost:3000/login/oidc/callback".to_string(),
+            name: "SSO".to_string(),
+        });
+        (client, idp)
+    }
+
+    // Start a login, and let the provider know of it as if the user logged in
+    async fn log_in_at_idp(client: &OidcClient, idp: &Shared, invite: Option<&str>) -> String {
+        let (state, url) = client.start(invite.map(str::to_string)).await.unwrap();
+        let (endpoint, query) = url.split_once('?').unwrap();
+        assert!(endpoint.ends_with("/authorize"));
+        let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        assert_eq!(params["state"], state);
+        assert_eq!(params["code_challenge_method"], "S256");
+
+        let mut idp = idp.lock().unwrap();
+        idp.code_challenge = params["code_challenge"].clone();
+        idp.nonce = params["nonce"].clone();
+        state
+    }
+
+    #[tokio::test]
+    async fn test_authorization_code_flow() {
+        let (client, idp) = mock_idp().await;
+
+        let state = log_in_at_idp(&client, &idp, None).await;
+        assert!(matches!(
+            client.finish(&state, "wrong-code").await,
+            Err(OidcError::Token(_))
+        ));
+
+        let state = log_in_at_idp(&client, &idp, Some("invite")).await;
+        let (claims, invite) = client.finish(&state, "the-code").await.unwrap();
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(invite.as_deref(), Some("invite"));
+        assert_eq!(claims.verified_email(), Some("alice@example.com"));
+
+        // A state is used once
+        assert!(matches!(
+            client.finish(&state, "the-code").await,
+            Err(OidcError::UnknownState)
+        ));
+    }
+
+    #[test]
+    fn test_validate_claims() {
+        let config = OidcConfig {
+            issuer: "https://idp.example.com".to_string(),
+            client_id: "rustgpt".to_string(),
+            client_secret: None,
+            redirect_url: "http://localhost:3000/login/oidc/callback".to_string(),
+            name: "SSO".to_string(),
+        };
+        let claims = |value: Value| serde_json::from_value::<IdTokenClaims>(value).unwrap();
+        let valid = json!({
+            "iss": "https://idp.example.com",
+            "sub": "alice",
+            "aud": "rustgpt",
+            "exp": 1000,
+            "nonce": "n",
+        });
+        assert!(validate_claims(&claims(valid.clone()), &config, "n", 999).is_ok());
+        // Unverified emails don't link accounts
+        assert_eq!(claims(valid.clone()).verified_email(), None);
+
+        let mut invalid = vec![];
+        for (key, value) in [
+            ("iss", json!("https://other.example.com")),
+            ("aud", json!("other")),
+            ("aud", json!(["rustgpt", "other"])),
+            ("exp", json!(999)),
+            ("nonce", json!("other")),
+        ] {
+            let mut value_claims = valid.clone();
+            value_claims[key] = value;
+            invalid.push(claims(value_claims));
+        }
+        for claims in invalid {
+            assert!(validate_claims(&claims, &config, "n", 999).is_err());
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+
+use super::constant_time_eq;
+
+/// The outcome of checking a password against the stored one.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Valid,
+    /// Valid, but stored in plain text by an older version, it should be
+    /// rehashed.
+    ValidLegacy,
+    Invalid,
+}
+
+// A hash of no password, with the parameters of `hash_password`, to spend as
+// long on unknown emails as on known ones
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$r+ZPmXsrbc/FmrsI9o3YvQ$xb1M+/k9sP4a+vRuVjJLfjz7W02ccwur5y1qwdHks3o";
+
+/// Hash a password with argon2id, as a PHC string for `users.password`.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Check a password against the stored PHC string. Rows which don't parse as
+/// one are plain text passwords from before hashing.
+pub fn verify_password(password: &str, stored: &str) -> PasswordCheck {
+    match PasswordHash::new(stored) {
+        Ok(hash) => match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => PasswordCheck::Valid,
+            Err(_) => PasswordCheck::Invalid,
+        },
+        Err(_) if constant_time_eq(password.as_bytes(), stored.as_bytes()) => {
+            PasswordCheck::ValidLegacy
+        }
+        Err(_) => PasswordCheck::Invalid,
+    }
+}
+
+/// Check a password of a login with an unknown email, doing the same work as
+/// `verify_password` so the response time doesn't tell the email is unknown.
+pub fn verify_dummy_password(password: &str) -> PasswordCheck {
+    verify_password(password, DUMMY_HASH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hash = hash_password("hunter2").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert_ne!(hash, hash_password("hunter2").unwrap());
+
+        assert_eq!(verify_password("hunter2", &hash), PasswordCheck::Valid);
+        assert_eq!(verify_password("hunter3", &hash), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn test_verify_legacy() {
+        assert_eq!(verify_password("test", "test"), PasswordCheck::ValidLegacy);
+        assert_eq!(verify_password("tset", "test"), PasswordCheck::Invalid);
+        assert_eq!(verify_password("", "test"), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn test_verify_dummy() {
+        // Same parameters as real hashes, so it takes as long to check
+        let dummy = PasswordHash::new(DUMMY_HASH).unwrap();
+        let hash = hash_password("hunter2").unwrap();
+        let real = PasswordHash::new(&hash).unwrap();
+        assert_eq!(dummy.algorithm, real.algorithm);
+        assert_eq!(dummy.params, real.params);
+
+        assert_eq!(verify_dummy_password("hunter2"), PasswordCheck::Invalid);
+        assert_eq!(verify_dummy_password(DUMMY_HASH), PasswordCheck::Invalid);
+    }
+}
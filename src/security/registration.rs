@@ -0,0 +1,147 @@
+use std::fmt;
+
+use super::session::generate_token;
+
+/// What the expiry select of the invite codes offers, in days. `None` never
+/// expires.
+pub const INVITE_LIFETIMES: [Option<i64>; 3] = [Some(7), Some(30), None];
+
+/// Who can sign up, set with `REGISTRATION_POLICY`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistrationPolicy {
+    /// Anyone.
+    Open,
+    /// Nobody, admins reset the passwords of the users they add by hand.
+    Closed,
+    /// With an invite code generated by an admin.
+    Invite,
+    /// With an email of one of these domains, from `ALLOWED_EMAIL_DOMAINS`.
+    Domain(Vec<String>),
+}
+
+#[derive(Debug)]
+pub struct RegistrationPolicyError(String);
+
+impl fmt::Display for RegistrationPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl RegistrationPolicy {
+    /// `open` when `REGISTRATION_POLICY` isn't set.
+    pub fn from_env() -> Result<Self, RegistrationPolicyError> {
+        let policy = dotenv::var("REGISTRATION_POLICY").unwrap_or_else(|_| "open".to_string());
+        let domains = dotenv::var("ALLOWED_EMAIL_DOMAINS").unwrap_or_default();
+        Self::parse(&policy, &domains)
+    }
+
+    fn parse(policy: &str, domains: &str) -> Result<Self, RegistrationPolicyError> {
+        match policy.trim() {
+            "open" => Ok(Self::Open),
+            "closed" => Ok(Self::Closed),
+            "invite" => Ok(Self::Invite),
+            "domain" => {
+                let domains: Vec<String> = domains
+                    .split(',')
+                    .map(|domain| domain.trim().trim_start_matches('@').to_lowercase())
+                    .filter(|domain| !domain.is_empty())
+                    .collect();
+                if domains.is_empty() {
+                    return Err(RegistrationPolicyError(
+                        "the domain policy needs ALLOWED_EMAIL_DOMAINS".to_string(),
+                    ));
+                }
+                Ok(Self::Domain(domains))
+            }
+            other => Err(RegistrationPolicyError(format!(
+                "unknown REGISTRATION_POLICY {:?}, expected open, closed, invite or domain",
+                other
+            ))),
+        }
+    }
+
+    /// `open`, `closed`, `invite` or `domain`, for the templates.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Open => "open",
+            Self::Closed => "closed",
+            Self::Invite => "invite",
+            Self::Domain(_) => "domain",
+        }
+    }
+
+    /// The domains emails must be of, empty unless the policy is `domain`.
+    pub fn allowed_domains(&self) -> &[String] {
+        match self {
+            Self::Domain(domains) => domains,
+            _ => &[],
+        }
+    }
+
+    /// Whether the email is of an allowed domain, always true unless the
+    /// policy is `domain`. Subdomains aren't allowed.
+    pub fn allows_email(&self, email: &str) -> bool {
+        match self {
+            Self::Domain(domains) => email
+                .rsplit_once('@')
+                .map(|(_, domain)| domain.to_lowercase())
+                .is_some_and(|domain| domains.contains(&domain)),
+            _ => true,
+        }
+    }
+
+    /// Whether logins wait for the email to be verified: under `domain` the
+    /// email only proves membership once its inbox is proven.
+    pub fn requires_verified_email(&self) -> bool {
+        matches!(self, Self::Domain(_))
+    }
+}
+
+/// A new invite code, shown once to the admin then stored hashed with
+/// `session::hash_token`.
+pub fn generate_invite_code() -> String {
+    generate_token()[..20].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            RegistrationPolicy::parse("open", "").unwrap(),
+            RegistrationPolicy::Open
+        );
+        assert_eq!(
+            RegistrationPolicy::parse(" invite ", "").unwrap(),
+            RegistrationPolicy::Invite
+        );
+        assert_eq!(
+            RegistrationPolicy::parse("domain", "Example.com, @acme.org,").unwrap(),
+            RegistrationPolicy::Domain(vec!["example.com".to_string(), "acme.org".to_string()])
+        );
+        assert!(RegistrationPolicy::parse("domain", " ").is_err());
+        assert!(RegistrationPolicy::parse("public", "").is_err());
+    }
+
+    #[test]
+    fn test_allows_email() {
+        let policy = RegistrationPolicy::parse("domain", "example.com").unwrap();
+        assert!(policy.allows_email("alice@example.com"));
+        assert!(policy.allows_email("Alice@EXAMPLE.com"));
+        assert!(!policy.allows_email("alice@sub.example.com"));
+        assert!(!policy.allows_email("alice@example.com.evil.org"));
+        assert!(!policy.allows_email("example.com"));
+        assert!(RegistrationPolicy::Closed.allows_email("alice@evil.org"));
+    }
+
+    #[test]
+    fn test_requires_verified_email() {
+        let policy = RegistrationPolicy::parse("domain", "example.com").unwrap();
+        assert!(policy.requires_verified_email());
+        assert!(!RegistrationPolicy::Open.requires_verified_email());
+        assert!(!RegistrationPolicy::Invite.requires_verified_email());
+    }
+}
@@ -0,0 +1,178 @@
+use std::{fmt, fs, io, path::Path};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+
+// Prefix of the stored ciphertexts, for when the format has to change.
+const VERSION: &str = "v1:";
+
+const NONCE_LEN: usize = 24;
+
+/// Encrypts the provider API keys stored in `settings` with the server master
+/// key (XChaCha20-Poly1305). Ciphertexts are bound to a context, the provider
+/// and user, so they can't be swapped between rows.
+#[derive(Clone)]
+pub struct KeyCipher {
+    cipher: XChaCha20Poly1305,
+}
+
+#[derive(Debug)]
+pub enum SecretError {
+    Malformed,
+    Decrypt,
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretError::Malformed => write!(f, "malformed encrypted secret"),
+            SecretError::Decrypt => write!(f, "can't decrypt secret, wrong master key?"),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+impl KeyCipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    pub fn encrypt(&self, plaintext: &str, context: &str) -> String {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext.as_bytes(),
+            aad: context.as_bytes(),
+        };
+        // Only fails for messages way over any API key size
+        let ciphertext = self.cipher.encrypt(&nonce, payload).unwrap();
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend(ciphertext);
+        format!("{}{}", VERSION, hex::encode(bytes))
+    }
+
+    pub fn decrypt(&self, stored: &str, context: &str) -> Result<String, SecretError> {
+        let bytes = stored
+            .strip_prefix(VERSION)
+            .and_then(|hex| hex::decode(hex).ok())
+            .filter(|bytes| bytes.len() > NONCE_LEN)
+            .ok_or(SecretError::Malformed)?;
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad: context.as_bytes(),
+        };
+        let plaintext = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| SecretError::Decrypt)?;
+        String::from_utf8(plaintext).map_err(|_| SecretError::Malformed)
+    }
+}
+
+/// Load the master key, hex encoded, from `SECRET_KEY` or else from the file
+/// at `SECRET_KEY_FILE` (`secret.key` by default), which is generated on the
+/// first start.
+pub fn load_master_key() -> io::Result<[u8; 32]> {
+    if let Ok(key) = dotenv::var("SECRET_KEY") {
+        return from_hex(&key);
+    }
+
+    let path = dotenv::var("SECRET_KEY_FILE").unwrap_or_else(|_| "secret.key".to_string());
+    match fs::read_to_string(&path) {
+        Ok(key) => from_hex(&key),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut key = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            write_key_file(Path::new(&path), &hex::encode(key))?;
+            Ok(key)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn from_hex(key: &str) -> io::Result<[u8; 32]> {
+    hex::decode(key.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the secret key must be 32 bytes, hex encoded",
+            )
+        })
+}
+
+/// Whether a stored key is encrypted, rows from before encryption are plain
+/// text.
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(VERSION)
+}
+
+/// What the settings show of a key: its last 4 characters.
+pub fn key_hint(key: &str) -> String {
+    let suffix = key
+        .chars()
+        .rev()
+        .take(4)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect::<String>();
+    format!("…{}", suffix)
+}
+
+#[cfg(unix)]
+fn write_key_file(path: &Path, key: &str) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(key.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_key_file(path: &Path, key: &str) -> io::Result<()> {
+    fs::write(path, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let cipher = KeyCipher::new(&[7; 32]);
+        let stored = cipher.encrypt("sk-secret", "openai:1");
+
+        assert!(is_encrypted(&stored));
+        assert!(!stored.contains("sk-secret"));
+        assert_ne!(stored, cipher.encrypt("sk-secret", "openai:1"));
+        assert_eq!(cipher.decrypt(&stored, "openai:1").unwrap(), "sk-secret");
+
+        // Bound to the context and the master key
+        assert!(cipher.decrypt(&stored, "openai:2").is_err());
+        assert!(KeyCipher::new(&[8; 32])
+            .decrypt(&stored, "openai:1")
+            .is_err());
+
+        assert!(cipher.decrypt("sk-secret", "openai:1").is_err());
+        assert!(cipher.decrypt("v1:00", "openai:1").is_err());
+    }
+
+    #[test]
+    fn test_key_hint() {
+        assert_eq!(key_hint("sk-abcdef123456"), "…3456");
+        assert_eq!(key_hint("ab"), "…ab");
+    }
+}
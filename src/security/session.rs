@@ -0,0 +1,36 @@
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// The cookie holding the session token.
+pub const SESSION_COOKIE: &str = "rust-gpt-session";
+
+/// How long a session lasts after logging in, in days.
+pub const SESSION_DAYS: i64 = 30;
+
+/// A new random session token, for the cookie.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// What is stored in `sessions.token_hash`, a leaked database doesn't give
+/// usable cookies.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token() {
+        let token = generate_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, generate_token());
+
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_ne!(hash_token(&token), token);
+    }
+}
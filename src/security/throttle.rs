@@ -0,0 +1,251 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{NaiveDateTime, Utc};
+
+use crate::data::repository::LoginAttemptRepository;
+
+/// How many failures are tolerated before delaying the next attempt, and
+/// after how many the key is locked out.
+struct Policy {
+    free_attempts: u32,
+    lockout_after: u32,
+}
+
+// An email is guessed by one attacker, an ip can be shared by many users.
+const EMAIL_POLICY: Policy = Policy {
+    free_attempts: 3,
+    lockout_after: 10,
+};
+const IP_POLICY: Policy = Policy {
+    free_attempts: 10,
+    lockout_after: 50,
+};
+
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+// Failures older than this are forgotten.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy)]
+struct Attempts {
+    failures: u32,
+    last_failure_at: NaiveDateTime,
+}
+
+/// How long to wait after the last failure: nothing for the first attempts,
+/// then doubling from a second, up to the lockout.
+fn retry_after(policy: &Policy, failures: u32) -> Duration {
+    if failures >= policy.lockout_after {
+        LOCKOUT
+    } else if failures < policy.free_attempts {
+        Duration::ZERO
+    } else {
+        let shift = (failures - policy.free_attempts).min(16);
+        Duration::from_secs(1 << shift).min(LOCKOUT)
+    }
+}
+
+/// Tracks failed logins by ip and by email. Kept in memory, and in the
+/// `login_attempts` table when built with `persistent` so restarts don't
+/// reset the counts.
+#[derive(Clone, Default)]
+pub struct LoginThrottle {
+    attempts: Arc<Mutex<HashMap<String, Attempts>>>,
+    repo: Option<LoginAttemptRepository>,
+}
+
+impl LoginThrottle {
+    pub async fn persistent(repo: LoginAttemptRepository) -> sqlx::Result<Self> {
+        let since = now() - chrono::Duration::from_std(FORGET_AFTER).unwrap();
+        repo.delete_older_than(since).await?;
+
+        let attempts = repo
+            .get_recent(since)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let attempts = Attempts {
+                    failures: row.failures.try_into().unwrap_or(u32::MAX),
+                    last_failure_at: row.last_failure_at,
+                };
+                (row.key, attempts)
+            })
+            .collect();
+
+        Ok(Self {
+            attempts: Arc::new(Mutex::new(attempts)),
+            repo: Some(repo),
+        })
+    }
+
+    /// `Err` with how long to wait when the ip or the email is throttled.
+    pub fn check(&self, ip: &str, email: &str) -> Result<(), Duration> {
+        self.check_at(ip, email, now())
+    }
+
+    pub async fn record_failure(&self, ip: &str, email: &str) {
+        let updated = self.record_failure_at(ip, email, now());
+        self.save(updated).await;
+    }
+
+    /// A successful login clears the failures of the email, not of the ip.
+    pub async fn record_success(&self, email: &str) {
+        let key = email_key(email);
+        self.attempts.lock().unwrap().remove(&key);
+        if let Some(ref repo) = self.repo {
+            if let Err(e) = repo.delete(&key).await {
+                eprintln!("Error deleting login attempts: {:?}", e);
+            }
+        }
+    }
+
+    fn check_at(&self, ip: &str, email: &str, now: NaiveDateTime) -> Result<(), Duration> {
+        let attempts = self.attempts.lock().unwrap();
+        let wait = [(ip_key(ip), &IP_POLICY), (email_key(email), &EMAIL_POLICY)]
+            .into_iter()
+            .filter_map(|(key, policy)| {
+                let attempts = attempts.get(&key)?;
+                let until = attempts.last_failure_at
+                    + chrono::Duration::from_std(retry_after(policy, attempts.failures)).ok()?;
+                (until - now).to_std().ok()
+            })
+            .filter(|wait| !wait.is_zero())
+            .max();
+
+        match wait {
+            Some(wait) => Err(wait),
+            None => Ok(()),
+        }
+    }
+
+    fn record_failure_at(
+        &self,
+        ip: &str,
+        email: &str,
+        now: NaiveDateTime,
+    ) -> Vec<(String, Attempts)> {
+        let mut attempts = self.attempts.lock().unwrap();
+        let forget_before = now - chrono::Duration::from_std(FORGET_AFTER).unwrap();
+        attempts.retain(|_, a| a.last_failure_at > forget_before);
+
+        [ip_key(ip), email_key(email)]
+            .into_iter()
+            .map(|key| {
+                let entry = attempts.entry(key.clone()).or_insert(Attempts {
+                    failures: 0,
+                    last_failure_at: now,
+                });
+                entry.failures = entry.failures.saturating_add(1);
+                entry.last_failure_at = now;
+                (key, *entry)
+            })
+            .collect()
+    }
+
+    async fn save(&self, updated: Vec<(String, Attempts)>) {
+        let Some(ref repo) = self.repo else {
+            return;
+        };
+        for (key, attempts) in updated {
+            if let Err(e) = repo
+                .save(&key, attempts.failures.into(), attempts.last_failure_at)
+                .await
+            {
+                eprintln!("Error saving login attempts: {:?}", e);
+            }
+        }
+    }
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+fn email_key(email: &str) -> String {
+    format!("email:{}", email.trim().to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(s: i64) -> chrono::Duration {
+        chrono::Duration::seconds(s)
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let delays = (0..11)
+            .map(|failures| retry_after(&EMAIL_POLICY, failures).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![0, 0, 0, 1, 2, 4, 8, 16, 32, 64, 900]);
+        assert_eq!(retry_after(&IP_POLICY, 49), LOCKOUT);
+    }
+
+    #[test]
+    fn test_throttle_email() {
+        let throttle = LoginThrottle::default();
+        let start = now();
+
+        for _ in 0..3 {
+            assert!(throttle.check_at("1.1.1.1", "a@b.c", start).is_ok());
+            throttle.record_failure_at("1.1.1.1", "a@b.c", start);
+        }
+        assert_eq!(
+            throttle.check_at("1.1.1.1", "A@b.c ", start),
+            Err(Duration::from_secs(1))
+        );
+        assert!(throttle
+            .check_at("1.1.1.1", "a@b.c", start + seconds(1))
+            .is_ok());
+
+        // Other emails from the same ip can still log in
+        assert!(throttle.check_at("1.1.1.1", "d@e.f", start).is_ok());
+
+        // Locked out after 10 failures
+        for _ in 3..10 {
+            throttle.record_failure_at("2.2.2.2", "a@b.c", start);
+        }
+        assert_eq!(
+            throttle.check_at("3.3.3.3", "a@b.c", start + seconds(60)),
+            Err(LOCKOUT - Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn test_throttle_ip() {
+        let throttle = LoginThrottle::default();
+        let start = now();
+
+        for i in 0..10 {
+            throttle.record_failure_at("1.1.1.1", &format!("{}@b.c", i), start);
+        }
+        assert!(throttle.check_at("1.1.1.1", "new@b.c", start).is_err());
+        assert!(throttle.check_at("2.2.2.2", "new@b.c", start).is_ok());
+
+        // Forgotten after a while
+        let later = start + chrono::Duration::from_std(FORGET_AFTER).unwrap() + seconds(1);
+        throttle.record_failure_at("2.2.2.2", "new@b.c", later);
+        assert!(throttle.check_at("1.1.1.1", "new@b.c", later).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_record_success() {
+        let throttle = LoginThrottle::default();
+        let start = now();
+
+        for _ in 0..5 {
+            throttle.record_failure_at("1.1.1.1", "a@b.c", start);
+        }
+        throttle.record_success("a@b.c").await;
+        assert!(throttle.check_at("2.2.2.2", "a@b.c", start).is_ok());
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::constant_time_eq;
+
+/// How long a password reset link is valid.
+pub const PASSWORD_RESET_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How long an email verification link is valid.
+pub const EMAIL_VERIFICATION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// What a token is for, a token of one purpose isn't valid for the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenPurpose {
+    PasswordReset,
+    EmailVerification,
+}
+
+impl TokenPurpose {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::PasswordReset => "password-reset",
+            TokenPurpose::EmailVerification => "email-verification",
+        }
+    }
+}
+
+/// Signs the tokens sent by email, `<user id>.<expiry>.<HMAC-SHA256>`. Nothing
+/// is stored: a token is bound to a value of the user row (the password hash
+/// for a reset, the email for a verification) so it stops working once used.
+#[derive(Clone)]
+pub struct TokenSigner {
+    key: [u8; 32],
+}
+
+impl TokenSigner {
+    /// Derive the signing key from the master key, so it is distinct from the
+    /// key encrypting the API keys.
+    pub fn new(master_key: &[u8; 32]) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(master_key).unwrap();
+        mac.update(b"rustgpt token signing");
+        Self {
+            key: mac.finalize().into_bytes().into(),
+        }
+    }
+
+    pub fn sign(
+        &self,
+        purpose: TokenPurpose,
+        user_id: i64,
+        binding: &str,
+        ttl: Duration,
+    ) -> String {
+        let expires_at = now() + ttl.as_secs();
+        let signature = self.signature(purpose, user_id, expires_at, binding);
+        format!("{}.{}.{}", user_id, expires_at, signature)
+    }
+
+    /// Check the signature and expiry of a token against the current value
+    /// of its binding.
+    pub fn verify(&self, purpose: TokenPurpose, token: &str, binding: &str) -> bool {
+        self.verify_at(purpose, token, binding, now())
+    }
+
+    fn verify_at(&self, purpose: TokenPurpose, token: &str, binding: &str, now: u64) -> bool {
+        let Some((user_id, expires_at, signature)) = parse(token) else {
+            return false;
+        };
+        let expected = self.signature(purpose, user_id, expires_at, binding);
+        expires_at > now && constant_time_eq(expected.as_bytes(), signature.as_bytes())
+    }
+
+    fn signature(
+        &self,
+        purpose: TokenPurpose,
+        user_id: i64,
+        expires_at: u64,
+        binding: &str,
+    ) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(format!("{}\n{}\n{}\n", purpose.as_str(), user_id, expires_at).as_bytes());
+        mac.update(binding.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+/// The user a token is for, to look up its binding before verifying it.
+pub fn token_user_id(token: &str) -> Option<i64> {
+    parse(token).map(|(user_id, _, _)| user_id)
+}
+
+fn parse(token: &str) -> Option<(i64, u64, &str)> {
+    let mut parts = token.splitn(3, '.');
+    let user_id = parts.next()?.parse().ok()?;
+    let expires_at = parts.next()?.parse().ok()?;
+    let signature = parts.next()?;
+    Some((user_id, expires_at, signature))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let signer = TokenSigner::new(&[3; 32]);
+        let token = signer.sign(
+            TokenPurpose::PasswordReset,
+            42,
+            "$argon2id$hash",
+            PASSWORD_RESET_TTL,
+        );
+
+        assert_eq!(token_user_id(&token), Some(42));
+        assert!(signer.verify(TokenPurpose::PasswordReset, &token, "$argon2id$hash"));
+
+        // Bound to the purpose, the binding and the key
+        assert!(!signer.verify(TokenPurpose::EmailVerification, &token, "$argon2id$hash"));
+        assert!(!signer.verify(TokenPurpose::PasswordReset, &token, "$argon2id$other"));
+        assert!(!TokenSigner::new(&[4; 32]).verify(
+            TokenPurpose::PasswordReset,
+            &token,
+            "$argon2id$hash"
+        ));
+
+        // The user id and expiry can't be changed
+        let forged = token.replacen("42.", "43.", 1);
+        assert!(!signer.verify(TokenPurpose::PasswordReset, &forged, "$argon2id$hash"));
+        assert!(!signer.verify(TokenPurpose::PasswordReset, "garbage", "$argon2id$hash"));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let signer = TokenSigner::new(&[3; 32]);
+        let token = signer.sign(
+            TokenPurpose::EmailVerification,
+            1,
+            "a@b.c",
+            Duration::from_secs(60),
+        );
+        let later = now() + 61;
+        assert!(!signer.verify_at(TokenPurpose::EmailVerification, &token, "a@b.c", later));
+    }
+}
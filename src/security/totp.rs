@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use qrcode::{render::svg, QrCode};
+use rand::{Rng, RngCore};
+use sha1::Sha1;
+
+use super::session::{generate_token, hash_token};
+
+/// The issuer shown in authenticator apps.
+const ISSUER: &str = "RustGPT";
+
+const PERIOD: u64 = 30;
+const DIGITS: u32 = 6;
+
+/// How many recovery codes are given when enabling two-factor authentication.
+pub const RECOVERY_CODES: usize = 10;
+
+/// The cookie holding the token of a login waiting for its second factor.
+pub const PENDING_LOGIN_COOKIE: &str = "rust-gpt-2fa";
+
+// How long the second step of a login can take, and how many codes can be
+// tried before logging in again.
+pub const PENDING_LOGIN_TTL: Duration = Duration::from_secs(5 * 60);
+const PENDING_LOGIN_ATTEMPTS: u32 = 5;
+
+/// A new random TOTP secret, base32 encoded as authenticator apps expect.
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+/// What the stored TOTP secret is encrypted with, see `secret::KeyCipher`.
+pub fn secret_context(user_id: i64) -> String {
+    format!("totp:{}", user_id)
+}
+
+/// The `otpauth://` URI authenticator apps are provisioned with.
+pub fn provisioning_uri(secret: &str, email: &str) -> String {
+    let label: String = form_urlencoded::byte_serialize(format!("{}:{}", ISSUER, email).as_bytes())
+        .collect::<String>()
+        .replace('+', "%20");
+    format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        label, secret, ISSUER, DIGITS, PERIOD
+    )
+}
+
+/// The provisioning URI as an SVG QR code, to embed in the settings.
+pub fn qr_code_svg(uri: &str) -> String {
+    QrCode::new(uri.as_bytes())
+        .map(|code| code.render::<svg::Color>().min_dimensions(200, 200).build())
+        .unwrap_or_default()
+}
+
+/// The RFC 6238 code of a time step.
+fn code_at(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+fn current_step() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() / PERIOD
+}
+
+/// Check a code against the secret, allowing a step of clock drift. Returns
+/// the matched time step, which has to be after `last_used_step` so a code
+/// can't be replayed.
+pub fn verify_code(secret: &str, code: &str, last_used_step: Option<i64>) -> Option<i64> {
+    verify_code_at(secret, code, last_used_step, current_step())
+}
+
+fn verify_code_at(secret: &str, code: &str, last_used_step: Option<i64>, step: u64) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    [step.saturating_sub(1), step, step + 1]
+        .into_iter()
+        .filter(|step| last_used_step.map_or(true, |last| *step as i64 > last))
+        .find(|step| code_at(&secret, *step) == code)
+        .map(|step| step as i64)
+}
+
+/// New recovery codes, shown once to the user then stored hashed.
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code: u64 = rng.gen_range(0..1 << 40);
+            let code = format!("{:010x}", code);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// What is stored in `recovery_codes.code_hash`, the input is normalized so
+/// codes can be typed without the dash or in upper case.
+pub fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_lowercase();
+    hash_token(&code)
+}
+
+/// A login whose password was checked, waiting for the second factor.
+#[derive(Debug, Clone)]
+pub struct PendingLogin {
+    pub user_id: i64,
+    pub email: String,
+    expires_at: Instant,
+    failures: u32,
+}
+
+/// The logins waiting for their second factor by token hash. They are short
+/// lived, losing them on restart only means logging in again.
+#[derive(Clone, Default)]
+pub struct PendingLogins {
+    logins: Arc<Mutex<HashMap<String, PendingLogin>>>,
+}
+
+impl PendingLogins {
+    /// Start the second step of a login, returns the token for the cookie.
+    pub fn start(&self, user_id: i64, email: &str) -> String {
+        let token = generate_token();
+        let mut logins = self.logins.lock().unwrap();
+        logins.retain(|_, login| login.expires_at > Instant::now());
+        logins.insert(
+            hash_token(&token),
+            PendingLogin {
+                user_id,
+                email: email.to_string(),
+                expires_at: Instant::now() + PENDING_LOGIN_TTL,
+                failures: 0,
+            },
+        );
+        token
+    }
+
+    pub fn get(&self, token: &str) -> Option<PendingLogin> {
+        self.logins
+            .lock()
+            .unwrap()
+            .get(&hash_token(token))
+            .filter(|login| login.expires_at > Instant::now())
+            .cloned()
+    }
+
+    /// Count a wrong code, the login is dropped after too many.
+    pub fn fail(&self, token: &str) {
+        let mut logins = self.logins.lock().unwrap();
+        let key = hash_token(token);
+        if let Some(login) = logins.get_mut(&key) {
+            login.failures += 1;
+            if login.failures >= PENDING_LOGIN_ATTEMPTS {
+                logins.remove(&key);
+            }
+        }
+    }
+
+    pub fn finish(&self, token: &str) {
+        self.logins.lock().unwrap().remove(&hash_token(token));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc6238() {
+        // Test vectors of RFC 6238 with SHA1, truncated to 6 digits
+        let secret = b"12345678901234567890";
+        assert_eq!(code_at(secret, 59 / PERIOD), 287082);
+        assert_eq!(code_at(secret, 1111111109 / PERIOD), 81804);
+        assert_eq!(code_at(secret, 1234567890 / PERIOD), 5924);
+        assert_eq!(code_at(secret, 20000000000 / PERIOD), 353130);
+    }
+
+    #[test]
+    fn test_verify_code() {
+        let secret = BASE32_NOPAD.encode(b"12345678901234567890");
+        let step = 1111111109 / PERIOD;
+
+        assert_eq!(
+            verify_code_at(&secret, "081804", None, step),
+            Some(step as i64)
+        );
+        assert_eq!(
+            verify_code_at(&secret, "081 804", None, step),
+            Some(step as i64)
+        );
+        // One step of drift is allowed
+        assert_eq!(
+            verify_code_at(&secret, "081804", None, step + 1),
+            Some(step as i64)
+        );
+        assert_eq!(verify_code_at(&secret, "081804", None, step + 2), None);
+        // No replay
+        assert_eq!(
+            verify_code_at(&secret, "081804", Some(step as i64), step),
+            None
+        );
+
+        assert_eq!(verify_code_at(&secret, "81804", None, step), None);
+        assert_eq!(verify_code_at(&secret, "000000", None, step), None);
+    }
+
+    #[test]
+    fn test_provisioning_uri() {
+        let uri = provisioning_uri("JBSWY3DPEHPK3PXP", "a+b@c.d");
+        assert_eq!(
+            uri,
+            "otpauth://totp/RustGPT%3Aa%2Bb%40c.d?secret=JBSWY3DPEHPK3PXP&issuer=RustGPT&algorithm=SHA1&digits=6&period=30"
+        );
+        assert!(qr_code_svg(&uri).starts_with("<?xml"));
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert_eq!(codes[0].len(), 11);
+        assert_eq!(
+            hash_recovery_code(&codes[0]),
+            hash_recovery_code(&codes[0].replace('-', "").to_uppercase())
+        );
+        assert_ne!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[1]));
+    }
+
+    #[test]
+    fn test_pending_logins() {
+        let logins = PendingLogins::default();
+        let token = logins.start(1, "a@b.c");
+        assert_eq!(logins.get(&token).map(|l| l.user_id), Some(1));
+        assert!(logins.get("other").is_none());
+
+        for _ in 0..PENDING_LOGIN_ATTEMPTS {
+            logins.fail(&token);
+        }
+        assert!(logins.get(&token).is_none());
+    }
+}